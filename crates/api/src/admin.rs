@@ -0,0 +1,109 @@
+use axum::{
+    Json,
+    extract::{Path, State},
+    http::StatusCode,
+};
+use index::{CacheStats, PartitionMap, SegmentStats};
+use serde::Serialize;
+use std::sync::Arc;
+
+use crate::error::ApiError;
+
+/// Answers as long as the process serves requests at all.
+pub async fn health_handle() -> &'static str {
+    "ok"
+}
+
+/// Answers once the data directory takes writes.
+pub async fn ready_handle(State(map): State<Arc<PartitionMap>>) -> Result<&'static str, ApiError> {
+    map.check_ready()
+        .await
+        .map_err(|source| ApiError::NotReady { source })?;
+
+    Ok("ok")
+}
+
+/// Fails unless the partition has data, so that admin requests don't create partitions.
+async fn exists(map: &PartitionMap, partition: &str) -> Result<(), ApiError> {
+    let exists = map
+        .has_partition(partition)
+        .await
+        .map_err(ApiError::in_partition(partition))?;
+
+    if exists {
+        Ok(())
+    } else {
+        Err(ApiError::NoSuchPartition {
+            partition: partition.to_string(),
+        })
+    }
+}
+
+pub async fn flush_handle(State(map): State<Arc<PartitionMap>>) -> Result<StatusCode, ApiError> {
+    map.flush().await?;
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+#[derive(Debug, Serialize)]
+pub struct CompactResponse {
+    /// Whether there were disk segments to merge.
+    data: bool,
+}
+
+pub async fn compact_handle(
+    State(map): State<Arc<PartitionMap>>,
+    Path(partition): Path<String>,
+) -> Result<Json<CompactResponse>, ApiError> {
+    exists(&map, &partition).await?;
+
+    let data = map
+        .compact(&partition)
+        .await
+        .map_err(ApiError::in_partition(&partition))?;
+
+    Ok(Json(CompactResponse { data }))
+}
+
+#[derive(Debug, Serialize)]
+pub struct PartitionsResponse {
+    data: Vec<String>,
+}
+
+pub async fn partitions_handle(
+    State(map): State<Arc<PartitionMap>>,
+) -> Result<Json<PartitionsResponse>, ApiError> {
+    let data = map.partitions().await?;
+
+    Ok(Json(PartitionsResponse { data }))
+}
+
+#[derive(Debug, Serialize)]
+pub struct PartitionStatsResponse {
+    data: SegmentStats,
+}
+
+pub async fn partition_stats_handle(
+    State(map): State<Arc<PartitionMap>>,
+    Path(partition): Path<String>,
+) -> Result<Json<PartitionStatsResponse>, ApiError> {
+    exists(&map, &partition).await?;
+
+    let data = map
+        .stats(&partition)
+        .await
+        .map_err(ApiError::in_partition(&partition))?;
+
+    Ok(Json(PartitionStatsResponse { data }))
+}
+
+#[derive(Debug, Serialize)]
+pub struct CacheStatsResponse {
+    data: CacheStats,
+}
+
+pub async fn cache_stats_handle(State(map): State<Arc<PartitionMap>>) -> Json<CacheStatsResponse> {
+    Json(CacheStatsResponse {
+        data: map.cache_stats().await,
+    })
+}
@@ -0,0 +1,306 @@
+use axum::{Json, body::Body, extract::State};
+use futures_util::StreamExt;
+use index::{PartitionMap, fxhash::FxHashMap};
+use serde::Serialize;
+use std::{collections::BTreeMap, mem, sync::Arc};
+
+use crate::error::ApiError;
+
+const BATCH_SIZE: usize = 10_000;
+
+const BUFFERED_LIMIT: usize = 100_000;
+
+// longer lines are reported and skipped without being buffered
+const MAX_LINE_LENGTH: usize = 1024 * 1024;
+
+const MAX_REPORTED_ERRORS: usize = 100;
+
+#[derive(Debug, Serialize)]
+struct LineError {
+    line: usize,
+    message: String,
+}
+
+#[derive(Debug, Default, Serialize)]
+struct BulkSummary {
+    indexed: BTreeMap<String, usize>,
+
+    failed: usize,
+
+    errors: Vec<LineError>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct BulkResponse {
+    data: BulkSummary,
+}
+
+#[derive(Default)]
+struct Batch {
+    entries: FxHashMap<String, Vec<String>>,
+    values: usize,
+}
+
+struct Bulk<'map> {
+    map: &'map PartitionMap,
+    batches: FxHashMap<String, Batch>,
+    buffered: usize,
+    summary: BulkSummary,
+}
+
+impl Bulk<'_> {
+    async fn line(&mut self, number: usize, line: &[u8]) -> Result<(), ApiError> {
+        if line.iter().all(u8::is_ascii_whitespace) {
+            return Ok(());
+        }
+
+        let [partition, key, value] = match serde_json::from_slice::<[String; 3]>(line) {
+            Ok(triple) => triple,
+            Err(err) => {
+                self.reject(number, err.to_string());
+
+                return Ok(());
+            }
+        };
+
+        let batch = match self.batches.get_mut(&partition) {
+            Some(batch) => batch,
+            None => self.batches.entry(partition.clone()).or_default(),
+        };
+
+        batch.entries.entry(key).or_default().push(value);
+        batch.values += 1;
+        self.buffered += 1;
+
+        if batch.values >= BATCH_SIZE {
+            let batch = self.batches.remove(&partition).unwrap_or_default();
+            self.index(partition, batch).await?;
+        } else if self.buffered >= BUFFERED_LIMIT {
+            self.flush().await?;
+        }
+
+        Ok(())
+    }
+
+    fn reject(&mut self, line: usize, message: String) {
+        self.summary.failed += 1;
+
+        if self.summary.errors.len() < MAX_REPORTED_ERRORS {
+            self.summary.errors.push(LineError { line, message });
+        }
+    }
+
+    async fn index(&mut self, partition: String, batch: Batch) -> Result<(), ApiError> {
+        self.map
+            .index(FxHashMap::from_iter([(partition.as_str(), batch.entries)]))
+            .await
+            .map_err(ApiError::in_partition(&partition))?;
+
+        self.buffered -= batch.values;
+        *self.summary.indexed.entry(partition).or_default() += batch.values;
+
+        Ok(())
+    }
+
+    async fn flush(&mut self) -> Result<(), ApiError> {
+        for (partition, batch) in mem::take(&mut self.batches) {
+            self.index(partition, batch).await?;
+        }
+
+        Ok(())
+    }
+}
+
+fn too_long() -> String {
+    format!("line is longer than {MAX_LINE_LENGTH} bytes")
+}
+
+/// The body is only read further once the batches read so far are indexed, so a client
+/// sending faster than the partitions take it is held back. Batches indexed before a storage
+/// error stay indexed.
+pub async fn bulk_handle(
+    State(map): State<Arc<PartitionMap>>,
+    body: Body,
+) -> Result<Json<BulkResponse>, ApiError> {
+    let mut bulk = Bulk {
+        map: &map,
+        batches: FxHashMap::default(),
+        buffered: 0,
+        summary: BulkSummary::default(),
+    };
+
+    let mut stream = body.into_data_stream();
+
+    // start of the line the body was cut in the middle of
+    let mut line = Vec::new();
+    let mut number = 0;
+
+    // whether the rest of the current line is dropped, as it's too long
+    let mut skipping = false;
+
+    while let Some(chunk) = stream.next().await {
+        let mut chunk = &chunk?[..];
+
+        while let Some(end) = chunk.iter().position(|&byte| byte == b'\n') {
+            number += 1;
+
+            if skipping {
+                skipping = false;
+            } else if line.len() + end > MAX_LINE_LENGTH {
+                bulk.reject(number, too_long());
+            } else {
+                line.extend_from_slice(&chunk[..end]);
+                bulk.line(number, &line).await?;
+            }
+
+            line.clear();
+            chunk = &chunk[end + 1..];
+        }
+
+        if skipping {
+            continue;
+        }
+
+        if line.len() + chunk.len() > MAX_LINE_LENGTH {
+            bulk.reject(number + 1, too_long());
+
+            line.clear();
+            skipping = true;
+        } else {
+            line.extend_from_slice(chunk);
+        }
+    }
+
+    // the last line may go without a newline
+    if !skipping && !line.is_empty() {
+        bulk.line(number + 1, &line).await?;
+    }
+
+    bulk.flush().await?;
+
+    tracing::debug!("bulk request done: {:?}", bulk.summary);
+
+    Ok(Json(BulkResponse { data: bulk.summary }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::body::Bytes;
+    use futures_util::stream;
+    use std::convert::Infallible;
+    use tempfile::tempdir;
+
+    async fn bulk(map: &Arc<PartitionMap>, chunks: &[&[u8]]) -> BulkSummary {
+        let chunks = chunks
+            .iter()
+            .map(|chunk| Ok::<_, Infallible>(Bytes::copy_from_slice(chunk)))
+            .collect::<Vec<_>>();
+
+        let Json(response) =
+            bulk_handle(State(map.clone()), Body::from_stream(stream::iter(chunks)))
+                .await
+                .unwrap();
+
+        response.data
+    }
+
+    fn lines(summary: &BulkSummary) -> Vec<usize> {
+        summary.errors.iter().map(|error| error.line).collect()
+    }
+
+    #[tokio::test]
+    async fn lines_split_across_chunks_are_put_back_together() {
+        let tmp = tempdir().unwrap();
+        let map = Arc::new(PartitionMap::new(tmp.path().to_path_buf()).await.unwrap());
+
+        let summary = bulk(
+            &map,
+            &[
+                br#"["p", "k"#,
+                br#"1", "v1"]"#,
+                b"\n",
+                br#"["p", "k1", "v2"]"#,
+                b"\n[\"p\", \"k2\"",
+                br#", "v3"]"#,
+            ],
+        )
+        .await;
+
+        assert_eq!(summary.indexed, BTreeMap::from([("p".to_string(), 3)]));
+        assert_eq!(summary.failed, 0);
+
+        let query = FxHashMap::from_iter([("p", vec!["k1", "k2"])]);
+        assert_eq!(
+            map.search(query, None, false).await.unwrap(),
+            ["v1", "v2", "v3"]
+        );
+    }
+
+    #[tokio::test]
+    async fn blank_and_failed_lines_are_skipped_and_still_counted() {
+        let tmp = tempdir().unwrap();
+        let map = Arc::new(PartitionMap::new(tmp.path().to_path_buf()).await.unwrap());
+
+        let summary = bulk(
+            &map,
+            &[
+                b"\n  \n",
+                br#"["p", "k1", "v1"]"#,
+                b"\nnot json\n\t\n[\"p\", \"k1\"]\n",
+                br#"["p", "k1", "v2"]"#,
+                b"\n",
+            ],
+        )
+        .await;
+
+        assert_eq!(summary.indexed, BTreeMap::from([("p".to_string(), 2)]));
+        assert_eq!(summary.failed, 2);
+        assert_eq!(lines(&summary), [4, 6]);
+    }
+
+    #[tokio::test]
+    async fn lines_past_the_length_limit_are_rejected() {
+        let tmp = tempdir().unwrap();
+        let map = Arc::new(PartitionMap::new(tmp.path().to_path_buf()).await.unwrap());
+
+        let long = vec![b' '; MAX_LINE_LENGTH + 1];
+
+        // whole within a chunk, cut over several of them, and at the very end
+        let mut whole = long.clone();
+        whole.extend_from_slice(b"\n[\"p\", \"k1\", \"v1\"]\n");
+
+        let summary = bulk(
+            &map,
+            &[
+                &whole,
+                &long[..10],
+                &long[10..],
+                b" still the same line\n",
+                br#"["p", "k1", "v2"]"#,
+                b"\n",
+                &long,
+            ],
+        )
+        .await;
+
+        assert_eq!(summary.indexed, BTreeMap::from([("p".to_string(), 2)]));
+        assert_eq!(summary.failed, 3);
+        assert_eq!(lines(&summary), [1, 3, 5]);
+        assert!(
+            summary
+                .errors
+                .iter()
+                .all(|error| error.message == too_long())
+        );
+
+        // a line right at the limit still goes through
+        let mut longest = vec![b' '; MAX_LINE_LENGTH - br#"["p", "k2", "v3"]"#.len()];
+        longest.extend_from_slice(br#"["p", "k2", "v3"]"#);
+
+        let summary = bulk(&map, &[&longest, b"\n"]).await;
+
+        assert_eq!(summary.indexed, BTreeMap::from([("p".to_string(), 1)]));
+        assert_eq!(summary.failed, 0);
+    }
+}
@@ -0,0 +1,365 @@
+use clap::{Args, Parser};
+use index::{CompactionPolicy, FlushPolicy, PartitionMapOptions};
+use serde::Deserialize;
+use snafu::{ResultExt, Whatever, whatever};
+use std::{
+    net::{IpAddr, Ipv4Addr, SocketAddr},
+    num::{NonZeroU64, NonZeroUsize},
+    path::PathBuf,
+    time::Duration,
+};
+
+const DEFAULT_BIND: IpAddr = IpAddr::V4(Ipv4Addr::UNSPECIFIED);
+const DEFAULT_PORT: u16 = 8497;
+const DEFAULT_BODY_LIMIT: usize = 2 * 1024 * 1024;
+
+#[derive(Debug, Clone, Parser)]
+pub struct Opts {
+    #[clap(
+        short = 'c',
+        long = "config",
+        env = "CHEHOV_CONFIG",
+        help = "TOML file to read the settings not given by flags or the environment from."
+    )]
+    config: Option<PathBuf>,
+
+    #[clap(flatten)]
+    settings: Settings,
+}
+
+// flags are named after the keys of the config file
+#[derive(Debug, Clone, Default, Args, Deserialize)]
+#[serde(default, rename_all = "kebab-case", deny_unknown_fields)]
+struct Settings {
+    #[clap(
+        short = 'd',
+        long = "directory",
+        env = "CHEHOV_DIRECTORY",
+        help = "Where partitions will be stored."
+    )]
+    directory: Option<PathBuf>,
+
+    #[clap(
+        long = "bind",
+        env = "CHEHOV_BIND",
+        help = "Address to listen on, 0.0.0.0 by default."
+    )]
+    bind: Option<IpAddr>,
+
+    #[clap(
+        short = 'p',
+        long = "port",
+        env = "CHEHOV_PORT",
+        help = "Port to listen on, 8497 by default."
+    )]
+    port: Option<u16>,
+
+    #[clap(
+        short = 'w',
+        long = "workers",
+        env = "CHEHOV_WORKERS",
+        help = "Number of runtime worker threads, one per core by default."
+    )]
+    workers: Option<NonZeroUsize>,
+
+    #[clap(
+        long = "body-limit",
+        env = "CHEHOV_BODY_LIMIT",
+        help = "Largest request body accepted, in bytes, 2 MiB by default."
+    )]
+    body_limit: Option<usize>,
+
+    #[clap(
+        long = "cache-capacity",
+        env = "CHEHOV_CACHE_CAPACITY",
+        help = "Number of partitions kept open at once."
+    )]
+    cache_capacity: Option<NonZeroUsize>,
+
+    #[clap(flatten)]
+    flush: FlushSettings,
+
+    #[clap(flatten)]
+    compaction: CompactionSettings,
+}
+
+#[derive(Debug, Clone, Default, Args, Deserialize)]
+#[serde(default, rename_all = "kebab-case", deny_unknown_fields)]
+struct FlushSettings {
+    #[clap(
+        id = "flush_max_segments",
+        long = "flush-max-segments",
+        env = "CHEHOV_FLUSH_MAX_SEGMENTS",
+        help = "Number of memory segments a partition queues up before flushing them."
+    )]
+    max_segments: Option<NonZeroUsize>,
+
+    #[clap(
+        id = "flush_max_bytes",
+        long = "flush-max-bytes",
+        env = "CHEHOV_FLUSH_MAX_BYTES",
+        help = "Size of the memory segments a partition queues up before flushing them."
+    )]
+    max_bytes: Option<usize>,
+
+    #[clap(
+        id = "flush_max_age_ms",
+        long = "flush-max-age-ms",
+        env = "CHEHOV_FLUSH_MAX_AGE_MS",
+        help = "Age of the oldest memory segment of a partition that triggers a flush."
+    )]
+    max_age_ms: Option<u64>,
+
+    #[clap(
+        id = "flush_interval_ms",
+        long = "flush-interval-ms",
+        env = "CHEHOV_FLUSH_INTERVAL_MS",
+        help = "How often partitions check whether to flush and compact."
+    )]
+    interval_ms: Option<NonZeroU64>,
+}
+
+#[derive(Debug, Clone, Default, Args, Deserialize)]
+#[serde(default, rename_all = "kebab-case", deny_unknown_fields)]
+struct CompactionSettings {
+    #[clap(
+        id = "compaction_min_segments",
+        long = "compaction-min-segments",
+        env = "CHEHOV_COMPACTION_MIN_SEGMENTS",
+        help = "Number of similarly sized disk segments that triggers a compaction."
+    )]
+    min_segments: Option<usize>,
+
+    #[clap(
+        id = "compaction_max_segments",
+        long = "compaction-max-segments",
+        env = "CHEHOV_COMPACTION_MAX_SEGMENTS",
+        help = "Most disk segments merged at once."
+    )]
+    max_segments: Option<usize>,
+
+    #[clap(
+        id = "compaction_tier_ratio",
+        long = "compaction-tier-ratio",
+        env = "CHEHOV_COMPACTION_TIER_RATIO",
+        help = "How many times bigger than the smallest one segments of a tier may get."
+    )]
+    tier_ratio: Option<NonZeroU64>,
+}
+
+impl Settings {
+    fn or(self, other: Self) -> Self {
+        Self {
+            directory: self.directory.or(other.directory),
+            bind: self.bind.or(other.bind),
+            port: self.port.or(other.port),
+            workers: self.workers.or(other.workers),
+            body_limit: self.body_limit.or(other.body_limit),
+            cache_capacity: self.cache_capacity.or(other.cache_capacity),
+            flush: FlushSettings {
+                max_segments: self.flush.max_segments.or(other.flush.max_segments),
+                max_bytes: self.flush.max_bytes.or(other.flush.max_bytes),
+                max_age_ms: self.flush.max_age_ms.or(other.flush.max_age_ms),
+                interval_ms: self.flush.interval_ms.or(other.flush.interval_ms),
+            },
+            compaction: CompactionSettings {
+                min_segments: self
+                    .compaction
+                    .min_segments
+                    .or(other.compaction.min_segments),
+                max_segments: self
+                    .compaction
+                    .max_segments
+                    .or(other.compaction.max_segments),
+                tier_ratio: self.compaction.tier_ratio.or(other.compaction.tier_ratio),
+            },
+        }
+    }
+}
+
+/// Taken from flags, then the environment, then the config file, then the defaults.
+#[derive(Debug, Clone)]
+pub struct Config {
+    pub directory: PathBuf,
+    pub address: SocketAddr,
+    pub workers: Option<NonZeroUsize>,
+    pub body_limit: usize,
+    pub partitions: PartitionMapOptions,
+}
+
+impl Config {
+    pub fn resolve(Opts { config, settings }: Opts) -> Result<Self, Whatever> {
+        let file = match config {
+            Some(path) => {
+                let contents = std::fs::read_to_string(&path)
+                    .with_whatever_context(|_| format!("could not read config file {path:?}"))?;
+
+                toml::from_str(&contents)
+                    .with_whatever_context(|_| format!("invalid config file {path:?}"))?
+            }
+            None => Settings::default(),
+        };
+
+        let Settings {
+            directory,
+            bind,
+            port,
+            workers,
+            body_limit,
+            cache_capacity,
+            flush,
+            compaction,
+        } = settings.or(file);
+
+        let Some(directory) = directory else {
+            whatever!("no directory given by --directory, CHEHOV_DIRECTORY or the config file");
+        };
+
+        let defaults = PartitionMapOptions::default();
+
+        let flush = FlushPolicy {
+            max_segments: flush
+                .max_segments
+                .map_or(defaults.flush.max_segments, NonZeroUsize::get),
+            max_bytes: flush.max_bytes.unwrap_or(defaults.flush.max_bytes),
+            max_age: flush
+                .max_age_ms
+                .map_or(defaults.flush.max_age, Duration::from_millis),
+            interval: flush
+                .interval_ms
+                .map_or(defaults.flush.interval, |interval| {
+                    Duration::from_millis(interval.get())
+                }),
+        };
+
+        let compaction = CompactionPolicy {
+            min_segments: compaction
+                .min_segments
+                .unwrap_or(defaults.compaction.min_segments),
+            max_segments: compaction
+                .max_segments
+                .unwrap_or(defaults.compaction.max_segments),
+            tier_ratio: compaction
+                .tier_ratio
+                .map_or(defaults.compaction.tier_ratio, NonZeroU64::get),
+        };
+
+        // a compaction merges at least two segments, and at least min-segments of them
+        if compaction.max_segments < compaction.min_segments.max(2) {
+            whatever!(
+                "compaction max-segments is {}, but has to be at least 2 and at least \
+                 min-segments ({}), as given by --compaction-max-segments, \
+                 CHEHOV_COMPACTION_MAX_SEGMENTS or the config file",
+                compaction.max_segments,
+                compaction.min_segments
+            );
+        }
+
+        Ok(Self {
+            directory,
+            address: SocketAddr::new(bind.unwrap_or(DEFAULT_BIND), port.unwrap_or(DEFAULT_PORT)),
+            workers,
+            body_limit: body_limit.unwrap_or(DEFAULT_BODY_LIMIT),
+            partitions: PartitionMapOptions {
+                flush,
+                compaction,
+                cache_capacity: cache_capacity.unwrap_or(defaults.cache_capacity),
+            },
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::CommandFactory;
+
+    #[test]
+    fn flags_are_consistent() {
+        Opts::command().debug_assert();
+    }
+
+    #[test]
+    fn zero_settings_are_rejected_by_name() {
+        for flag in [
+            "--cache-capacity",
+            "--flush-max-segments",
+            "--compaction-tier-ratio",
+        ] {
+            let error = Opts::try_parse_from(["api", "-d", "data", flag, "0"]).unwrap_err();
+
+            assert!(error.to_string().contains(flag), "{error}");
+        }
+
+        let tmp = tempfile::tempdir().unwrap();
+        let path = tmp.path().join("config.toml");
+
+        for (table, key) in [
+            ("", "cache-capacity"),
+            ("[flush]", "max-segments"),
+            ("[compaction]", "tier-ratio"),
+        ] {
+            std::fs::write(&path, format!("directory = \"data\"\n{table}\n{key} = 0\n")).unwrap();
+
+            let opts = Opts::try_parse_from(["api", "-c", path.to_str().unwrap()]).unwrap();
+            let error = snafu::Report::from_error(Config::resolve(opts).unwrap_err());
+
+            assert!(error.to_string().contains(key), "{error}");
+        }
+    }
+
+    #[test]
+    fn compactions_that_cant_happen_are_rejected_by_name() {
+        for (min, max, valid) in [
+            ("4", "1", false),
+            ("1", "1", false),
+            ("8", "4", false),
+            ("1", "2", true),
+            ("8", "8", true),
+        ] {
+            let opts = Opts::try_parse_from([
+                "api",
+                "-d",
+                "data",
+                "--compaction-min-segments",
+                min,
+                "--compaction-max-segments",
+                max,
+            ])
+            .unwrap();
+
+            match Config::resolve(opts) {
+                Ok(_) => assert!(valid, "{min}..{max}"),
+                Err(error) => {
+                    let error = snafu::Report::from_error(error).to_string();
+
+                    assert!(!valid && error.contains("max-segments"), "{error}");
+                }
+            }
+        }
+
+        // the default max-segments is below the given min-segments too
+        let opts =
+            Opts::try_parse_from(["api", "-d", "data", "--compaction-min-segments", "64"]).unwrap();
+
+        assert!(Config::resolve(opts).is_err());
+    }
+
+    #[test]
+    fn settings_fall_back_to_defaults() {
+        let opts = Opts::try_parse_from(["api", "-d", "data", "--cache-capacity", "8"]).unwrap();
+        let config = Config::resolve(opts).unwrap();
+
+        let defaults = PartitionMapOptions::default();
+
+        assert_eq!(config.partitions.cache_capacity.get(), 8);
+        assert_eq!(
+            config.partitions.flush.max_segments,
+            defaults.flush.max_segments
+        );
+        assert_eq!(
+            config.partitions.compaction.tier_ratio,
+            defaults.compaction.tier_ratio
+        );
+    }
+}
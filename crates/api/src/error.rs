@@ -0,0 +1,280 @@
+use axum::{
+    Json,
+    extract::rejection::{JsonRejection, QueryRejection},
+    http::StatusCode,
+    response::{IntoResponse, Response},
+};
+use index::{DiskResolutionError, PartitionError, SegmentMapError};
+use serde::Serialize;
+use snafu::Snafu;
+
+/// Error of a request, answered with its status code and a JSON body of the form
+/// `{"error": {"code": ..., "message": ..., "partition": ...}}`.
+#[derive(Debug, Snafu)]
+pub enum ApiError {
+    #[snafu(display("{source}"))]
+    Partition {
+        source: PartitionError,
+
+        /// Partition the error happened in, when the request was down to a single one.
+        partition: Option<String>,
+    },
+
+    #[snafu(display("not ready: {source}"))]
+    NotReady { source: PartitionError },
+
+    #[snafu(display("no such partition"))]
+    NoSuchPartition { partition: String },
+
+    #[snafu(context(false), display("{}", source.body_text()))]
+    InvalidBody { source: JsonRejection },
+
+    #[snafu(context(false), display("{}", source.body_text()))]
+    InvalidQuery { source: QueryRejection },
+
+    #[snafu(context(false), display("could not read the request body: {source}"))]
+    ReadBody { source: axum::Error },
+}
+
+impl From<PartitionError> for ApiError {
+    fn from(source: PartitionError) -> Self {
+        Self::Partition {
+            source,
+            partition: None,
+        }
+    }
+}
+
+impl ApiError {
+    pub fn in_partition(partition: &str) -> impl FnOnce(PartitionError) -> Self {
+        move |source| Self::Partition {
+            source,
+            partition: Some(partition.to_string()),
+        }
+    }
+
+    fn status(&self) -> StatusCode {
+        match self {
+            Self::Partition {
+                source:
+                    PartitionError::UnboundedQuery
+                    | PartitionError::InvalidCursor
+                    | PartitionError::ZeroLimit
+                    | PartitionError::EmptyPartition,
+                ..
+            } => StatusCode::BAD_REQUEST,
+            Self::Partition { .. } => StatusCode::INTERNAL_SERVER_ERROR,
+            Self::NotReady { .. } => StatusCode::SERVICE_UNAVAILABLE,
+            Self::NoSuchPartition { .. } => StatusCode::NOT_FOUND,
+            Self::InvalidBody { source } => source.status(),
+            Self::InvalidQuery { source } => source.status(),
+            Self::ReadBody { .. } => StatusCode::BAD_REQUEST,
+        }
+    }
+
+    /// Stable, machine-readable name of the error.
+    fn code(&self) -> &'static str {
+        match self {
+            Self::Partition { source, .. } => match source {
+                PartitionError::IoError { .. } => "io_error",
+                PartitionError::ResolutionError { source } => resolution_code(source),
+                PartitionError::SegmentCreationError { source } => match source {
+                    SegmentMapError::IoError { .. } => "io_error",
+                    SegmentMapError::InvalidIndex => "corrupted_partition",
+                    SegmentMapError::UnsupportedVersion { .. } => "unsupported_version",
+                    SegmentMapError::ResolutionError { source } => resolution_code(source),
+                },
+                PartitionError::UnboundedQuery => "unbounded_query",
+                PartitionError::InvalidCursor => "invalid_cursor",
+                PartitionError::ZeroLimit => "invalid_limit",
+                PartitionError::EmptyPartition => "invalid_partition",
+            },
+            Self::NotReady { .. } => "not_ready",
+            Self::NoSuchPartition { .. } => "no_such_partition",
+            Self::InvalidBody { .. } => "invalid_body",
+            Self::InvalidQuery { .. } => "invalid_query",
+            Self::ReadBody { .. } => "unreadable_body",
+        }
+    }
+
+    fn partition(&self) -> Option<&str> {
+        match self {
+            Self::Partition { partition, .. } => partition.as_deref(),
+            Self::NoSuchPartition { partition } => Some(partition),
+            Self::NotReady { .. }
+            | Self::InvalidBody { .. }
+            | Self::InvalidQuery { .. }
+            | Self::ReadBody { .. } => None,
+        }
+    }
+}
+
+fn resolution_code(error: &DiskResolutionError) -> &'static str {
+    match error {
+        DiskResolutionError::IoError { .. } => "io_error",
+        DiskResolutionError::UnsupportedVersion { .. } => "unsupported_version",
+        DiskResolutionError::LookupInvalidSize
+        | DiskResolutionError::DataInvalidSize
+        | DiskResolutionError::BloomLoadError
+        | DiskResolutionError::Corrupted { .. }
+        | DiskResolutionError::Utf8Error { .. } => "corrupted_segment",
+    }
+}
+
+#[derive(Debug, Serialize)]
+struct ErrorBody<'error> {
+    code: &'static str,
+    message: String,
+    partition: Option<&'error str>,
+}
+
+#[derive(Debug, Serialize)]
+struct ErrorResponse<'error> {
+    error: ErrorBody<'error>,
+}
+
+impl IntoResponse for ApiError {
+    fn into_response(self) -> Response {
+        let status = self.status();
+
+        if status.is_server_error() {
+            tracing::warn!("request failed: {self:?}");
+        } else {
+            tracing::debug!("request rejected: {self:?}");
+        }
+
+        let response = ErrorResponse {
+            error: ErrorBody {
+                code: self.code(),
+                message: self.to_string(),
+                partition: self.partition(),
+            },
+        };
+
+        (status, Json(response)).into_response()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::{
+        body::{Body, to_bytes},
+        extract::{FromRequest, Request},
+        http::header,
+    };
+    use index::Cursor;
+    use serde_json::{Value, json};
+    use std::io;
+
+    async fn respond(error: ApiError) -> (StatusCode, Value) {
+        let response = error.into_response();
+        let status = response.status();
+        let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+
+        (status, serde_json::from_slice(&body).unwrap())
+    }
+
+    #[tokio::test]
+    async fn malformed_bodies_are_bad_requests() {
+        let request = Request::builder()
+            .header(header::CONTENT_TYPE, "application/json")
+            .body(Body::from("{\"keys\": "))
+            .unwrap();
+
+        let rejection = Json::<Value>::from_request(request, &()).await.unwrap_err();
+        let (status, body) = respond(rejection.into()).await;
+
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert_eq!(body["error"]["code"], "invalid_body");
+        assert_eq!(body["error"]["partition"], Value::Null);
+        assert!(body["error"]["message"].as_str().unwrap().contains("EOF"));
+    }
+
+    #[tokio::test]
+    async fn invalid_cursors_and_limits_are_bad_requests() {
+        let error = "not a cursor".parse::<Cursor>().unwrap_err();
+
+        assert_eq!(
+            respond(error.into()).await,
+            (
+                StatusCode::BAD_REQUEST,
+                json!({"error": {
+                    "code": "invalid_cursor",
+                    "message": "cursor is malformed or belongs to another search",
+                    "partition": null,
+                }})
+            )
+        );
+
+        let error = ApiError::in_partition("tenant")(PartitionError::ZeroLimit);
+
+        assert_eq!(
+            respond(error).await,
+            (
+                StatusCode::BAD_REQUEST,
+                json!({"error": {
+                    "code": "invalid_limit",
+                    "message": "limit must be at least one, or a page would never move forward",
+                    "partition": "tenant",
+                }})
+            )
+        );
+    }
+
+    #[tokio::test]
+    async fn missing_partitions_are_not_found() {
+        let error = ApiError::NoSuchPartition {
+            partition: "tenant".to_string(),
+        };
+
+        assert_eq!(
+            respond(error).await,
+            (
+                StatusCode::NOT_FOUND,
+                json!({"error": {
+                    "code": "no_such_partition",
+                    "message": "no such partition",
+                    "partition": "tenant",
+                }})
+            )
+        );
+    }
+
+    #[tokio::test]
+    async fn storage_failures_are_internal_errors() {
+        let error = ApiError::in_partition("tenant")(PartitionError::IoError {
+            source: io::Error::other("disk on fire"),
+        });
+
+        assert_eq!(
+            respond(error).await,
+            (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                json!({"error": {
+                    "code": "io_error",
+                    "message": "disk on fire",
+                    "partition": "tenant",
+                }})
+            )
+        );
+
+        let error = ApiError::from(PartitionError::ResolutionError {
+            source: DiskResolutionError::Corrupted {
+                file: "entries.bin".to_string(),
+            },
+        });
+
+        assert_eq!(
+            respond(error).await,
+            (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                json!({"error": {
+                    "code": "corrupted_segment",
+                    "message": "segment file \"entries.bin\" is corrupted",
+                    "partition": null,
+                }})
+            )
+        );
+    }
+}
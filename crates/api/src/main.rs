@@ -1,116 +1,437 @@
 use axum::{
     Json,
-    extract::State,
-    routing::{get, post},
+    extract::{
+        DefaultBodyLimit, Path, Query, State,
+        rejection::{JsonRejection, QueryRejection},
+    },
+    http::StatusCode,
+    routing::{delete, get, post},
 };
-use index::{PartitionMap, fxhash::FxHashMap};
+use index::{Cursor, Direction, PartitionError, PartitionMap, SearchResult, fxhash::FxHashMap};
 use serde::{Deserialize, Serialize};
 use snafu::ResultExt;
-use std::{path::PathBuf, sync::Arc};
+use std::{collections::BTreeMap, sync::Arc};
 use tracing_subscriber::EnvFilter;
 
 use clap::Parser;
 use tokio::net::TcpListener;
 
-#[derive(Debug, Clone, Parser)]
-struct Opts {
-    #[clap(
-        short = 'd',
-        long = "directory",
-        help = "Where partitions will be stored."
-    )]
-    directory: PathBuf,
-}
+use admin::{
+    cache_stats_handle, compact_handle, flush_handle, health_handle, partition_stats_handle,
+    partitions_handle, ready_handle,
+};
+use bulk::bulk_handle;
+use config::{Config, Opts};
+use error::ApiError;
+
+mod admin;
+mod bulk;
+mod config;
+mod error;
 
 type IndexRequest = Vec<[String; 3]>;
 
+#[derive(Debug, Serialize)]
+struct IndexResponse {
+    /// Values indexed, by partition.
+    data: BTreeMap<String, usize>,
+}
+
 async fn index_handle(
     State(map): State<Arc<PartitionMap>>,
-    Json(request): Json<IndexRequest>,
-) -> String {
-    let mut req = FxHashMap::default();
+    request: Result<Json<IndexRequest>, JsonRejection>,
+) -> Result<Json<IndexResponse>, ApiError> {
+    let Json(request) = request?;
+
+    let mut req = FxHashMap::<_, FxHashMap<_, Vec<_>>>::default();
 
     for [partition, key, value] in request {
-        let entries = if let Some(entries) = req.get_mut(&partition) {
-            entries
-        } else {
-            req.insert(partition.clone(), FxHashMap::default());
+        req.entry(partition)
+            .or_default()
+            .entry(key)
+            .or_default()
+            .push(value);
+    }
 
-            req.get_mut(&partition).unwrap()
-        };
+    tracing::debug!("preprocessed request: {req:?}");
 
-        let values = if let Some(values) = entries.get_mut(&key) {
-            values
-        } else {
-            entries.insert(key.clone(), Vec::new());
+    let mut data = BTreeMap::new();
+
+    // one partition at a time, so that a failure is attributed to its partition
+    for (partition, entries) in req {
+        let indexed = entries.values().map(Vec::len).sum();
+
+        map.index(FxHashMap::from_iter([(partition.as_str(), entries)]))
+            .await
+            .map_err(ApiError::in_partition(&partition))?;
+
+        data.insert(partition, indexed);
+    }
+
+    Ok(Json(IndexResponse { data }))
+}
 
-            entries.get_mut(&key).unwrap()
+/// Either `[partition, key]`, deleting the whole key, or `[partition, key, value]`.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(untagged)]
+enum DeleteItem {
+    Key([String; 2]),
+    Pair([String; 3]),
+}
+
+type DeleteRequest = Vec<DeleteItem>;
+
+#[derive(Debug, Serialize)]
+struct DeleteResponse {
+    /// Keys and values deleted, by partition.
+    data: BTreeMap<String, usize>,
+}
+
+async fn delete_handle(
+    State(map): State<Arc<PartitionMap>>,
+    request: Result<Json<DeleteRequest>, JsonRejection>,
+) -> Result<Json<DeleteResponse>, ApiError> {
+    let Json(request) = request?;
+
+    let mut req = FxHashMap::<_, FxHashMap<_, Option<Vec<_>>>>::default();
+
+    for item in request {
+        let (partition, key, value) = match item {
+            DeleteItem::Key([partition, key]) => (partition, key, None),
+            DeleteItem::Pair([partition, key, value]) => (partition, key, Some(value)),
         };
 
-        values.push(value);
+        let entries = req.entry(partition).or_default();
+
+        match (
+            entries.entry(key).or_insert_with(|| Some(Vec::new())),
+            value,
+        ) {
+            (Some(values), Some(value)) => values.push(value),
+            (values, _) => *values = None,
+        }
     }
 
     tracing::debug!("preprocessed request: {req:?}");
 
-    match map.index(req).await {
-        Ok(_) => "ok".to_string(),
-        Err(err) => {
-            tracing::warn!("index error: {err:?}");
+    let mut data = BTreeMap::new();
 
-            err.to_string()
-        }
+    for (partition, deletion) in req {
+        // whole keys count once
+        let deleted = deletion
+            .values()
+            .map(|values| values.as_ref().map_or(1, Vec::len))
+            .sum();
+
+        map.delete(FxHashMap::from_iter([(partition.as_str(), deletion)]))
+            .await
+            .map_err(ApiError::in_partition(&partition))?;
+
+        data.insert(partition, deleted);
+    }
+
+    Ok(Json(DeleteResponse { data }))
+}
+
+async fn drop_partition_handle(
+    State(map): State<Arc<PartitionMap>>,
+    Path(partition): Path<String>,
+) -> Result<StatusCode, ApiError> {
+    let dropped = map
+        .drop_partition(&partition)
+        .await
+        .map_err(ApiError::in_partition(&partition))?;
+
+    if dropped {
+        Ok(StatusCode::NO_CONTENT)
+    } else {
+        Err(ApiError::NoSuchPartition { partition })
     }
 }
 
 #[derive(Debug, Clone, Deserialize)]
 struct SearchRequest {
+    /// Exact keys to look up, by partition.
+    #[serde(default)]
     query: FxHashMap<String, Vec<String>>,
+
+    /// Key prefixes to look up, by partition; matched after the exact keys.
+    #[serde(default)]
+    prefixes: FxHashMap<String, Vec<String>>,
+
+    /// Boolean queries, by partition; matched after the prefixes.
+    #[serde(default)]
+    queries: FxHashMap<String, index::Query>,
+
+    /// Cursor of the previous page, continuing its exact keys. Prefixes and queries aren't
+    /// paginated, and are matched once the exact keys run out.
+    cursor: Option<String>,
+
+    /// Returns a value found under several of the exact keys only once.
+    #[serde(default)]
+    distinct: bool,
+
+    /// Returns the values in a single list rather than by partition and key.
+    #[serde(default)]
+    flat: bool,
+
     limit: Option<usize>,
 }
 
 #[derive(Debug, Serialize)]
 #[serde(untagged)]
 enum SearchResponse {
-    Error { error: String },
-    Value { data: Vec<String> },
+    Value {
+        data: SearchResult,
+
+        /// Set when the page is full and more values may follow.
+        #[serde(skip_serializing_if = "Option::is_none")]
+        cursor: Option<String>,
+    },
+    Flat {
+        data: Vec<String>,
+        truncated: bool,
+
+        #[serde(skip_serializing_if = "Option::is_none")]
+        cursor: Option<String>,
+    },
 }
 
-async fn search_handle(
-    State(map): State<Arc<PartitionMap>>,
-    Json(SearchRequest { query, limit }): Json<SearchRequest>,
-) -> Json<SearchResponse> {
-    match map.search(query, limit).await {
-        Ok(data) => Json::from(SearchResponse::Value { data }),
-        Err(err) => {
-            tracing::warn!("search error: {err:?}");
-
-            Json::from(SearchResponse::Error {
-                error: err.to_string(),
-            })
+async fn search(
+    map: &PartitionMap,
+    SearchRequest {
+        query,
+        prefixes,
+        queries,
+        cursor,
+        distinct,
+        limit,
+        ..
+    }: SearchRequest,
+) -> Result<(SearchResult, Option<Cursor>), PartitionError> {
+    let cursor = cursor.map(|cursor| cursor.parse()).transpose()?;
+
+    let (mut data, cursor) = map.search_page(query, cursor, limit, distinct).await?;
+
+    if cursor.is_some() {
+        return Ok((data, cursor));
+    }
+
+    let left = limit.map(|limit| limit.saturating_sub(data.len()));
+    data.merge(map.find_prefix(prefixes, left).await?);
+
+    let left = limit.map(|limit| limit.saturating_sub(data.len()));
+    data.merge(map.query(queries, left).await?);
+
+    Ok((data, None))
+}
+
+impl SearchResponse {
+    fn new(data: SearchResult, cursor: Option<Cursor>, flat: bool) -> Self {
+        let cursor = cursor.as_ref().map(Cursor::to_string);
+
+        if flat {
+            Self::Flat {
+                truncated: data.truncated,
+                data: data.into_flat(),
+                cursor,
+            }
+        } else {
+            Self::Value { data, cursor }
         }
     }
 }
 
-#[tokio::main(flavor = "current_thread")]
-async fn main() -> Result<(), snafu::Whatever> {
+async fn search_handle(
+    State(map): State<Arc<PartitionMap>>,
+    request: Result<Json<SearchRequest>, JsonRejection>,
+) -> Result<Json<SearchResponse>, ApiError> {
+    let Json(request) = request?;
+    let flat = request.flat;
+
+    let (data, cursor) = search(&map, request).await?;
+
+    Ok(Json(SearchResponse::new(data, cursor, flat)))
+}
+
+#[derive(Debug, Clone, Deserialize)]
+struct KeySearchRequest {
+    /// Cursor of the previous page.
+    cursor: Option<String>,
+    limit: Option<usize>,
+}
+
+/// Looks up the values of a single key, for clients that can't send a body along with a GET.
+async fn key_search_handle(
+    State(map): State<Arc<PartitionMap>>,
+    Path((partition, key)): Path<(String, String)>,
+    request: Result<Query<KeySearchRequest>, QueryRejection>,
+) -> Result<Json<SearchResponse>, ApiError> {
+    let Query(KeySearchRequest { cursor, limit }) = request?;
+
+    let request = SearchRequest {
+        query: FxHashMap::from_iter([(partition.clone(), vec![key])]),
+        prefixes: FxHashMap::default(),
+        queries: FxHashMap::default(),
+        cursor,
+        distinct: false,
+        flat: true,
+        limit,
+    };
+
+    let (data, cursor) = search(&map, request)
+        .await
+        .map_err(ApiError::in_partition(&partition))?;
+
+    Ok(Json(SearchResponse::new(data, cursor, true)))
+}
+
+#[derive(Debug, Clone, Deserialize)]
+struct KeyRequest {
+    partition: String,
+    key: String,
+}
+
+#[derive(Debug, Serialize)]
+struct CountResponse {
+    data: usize,
+}
+
+async fn count_handle(
+    State(map): State<Arc<PartitionMap>>,
+    request: Result<Query<KeyRequest>, QueryRejection>,
+) -> Result<Json<CountResponse>, ApiError> {
+    let Query(request) = request?;
+
+    let data = map
+        .count(&request.partition, &request.key)
+        .await
+        .map_err(ApiError::in_partition(&request.partition))?;
+
+    Ok(Json(CountResponse { data }))
+}
+
+#[derive(Debug, Serialize)]
+struct ExistsResponse {
+    data: bool,
+}
+
+async fn exists_handle(
+    State(map): State<Arc<PartitionMap>>,
+    request: Result<Query<KeyRequest>, QueryRejection>,
+) -> Result<Json<ExistsResponse>, ApiError> {
+    let Query(request) = request?;
+
+    let data = map
+        .contains(&request.partition, &request.key)
+        .await
+        .map_err(ApiError::in_partition(&request.partition))?;
+
+    Ok(Json(ExistsResponse { data }))
+}
+
+#[derive(Debug, Clone, Deserialize)]
+struct ScanRequest {
+    #[serde(default)]
+    start: String,
+    end: Option<String>,
+    #[serde(default)]
+    reverse: bool,
+    limit: Option<usize>,
+}
+
+#[derive(Debug, Serialize)]
+struct ScanResponse {
+    data: Vec<(String, Vec<String>)>,
+}
+
+async fn scan_handle(
+    State(map): State<Arc<PartitionMap>>,
+    Path(partition): Path<String>,
+    request: Result<Query<ScanRequest>, QueryRejection>,
+) -> Result<Json<ScanResponse>, ApiError> {
+    let Query(request) = request?;
+
+    let direction = if request.reverse {
+        Direction::Reverse
+    } else {
+        Direction::Forward
+    };
+
+    let data = map
+        .scan(
+            &partition,
+            &request.start,
+            request.end.as_deref(),
+            direction,
+            request.limit,
+        )
+        .await
+        .map_err(ApiError::in_partition(&partition))?;
+
+    Ok(Json(ScanResponse { data }))
+}
+
+fn main() -> Result<(), snafu::Whatever> {
     tracing_subscriber::fmt()
         .with_env_filter(EnvFilter::from_default_env())
         .init();
 
-    let opts = Opts::parse();
+    let config = Config::resolve(Opts::parse())?;
+
+    tracing::info!("resolved configuration: {config:?}");
+
+    let mut runtime = tokio::runtime::Builder::new_multi_thread();
+
+    if let Some(workers) = config.workers {
+        runtime.worker_threads(workers.get());
+    }
+
+    runtime
+        .enable_all()
+        .build()
+        .whatever_context("failed to start the runtime")?
+        .block_on(serve(config))
+}
+
+fn router(map: Arc<PartitionMap>, body_limit: usize) -> axum::Router {
+    axum::Router::new()
+        .route("/index", post(index_handle))
+        .route("/bulk", post(bulk_handle))
+        .route("/search", get(search_handle).post(search_handle))
+        .route("/count", get(count_handle))
+        .route("/exists", get(exists_handle))
+        .route("/delete", post(delete_handle))
+        .route("/partitions/{partition}", delete(drop_partition_handle))
+        .route("/partitions/{partition}/scan", get(scan_handle))
+        .route("/partitions/{partition}/keys/{key}", get(key_search_handle))
+        .route("/healthz", get(health_handle))
+        .route("/readyz", get(ready_handle))
+        .route("/admin/flush", post(flush_handle))
+        .route("/admin/stats", get(cache_stats_handle))
+        .route("/admin/partitions", get(partitions_handle))
+        .route(
+            "/admin/partitions/{partition}/stats",
+            get(partition_stats_handle),
+        )
+        .route(
+            "/admin/partitions/{partition}/compact",
+            post(compact_handle),
+        )
+        .layer(DefaultBodyLimit::max(body_limit))
+        .with_state(map)
+}
 
+async fn serve(config: Config) -> Result<(), snafu::Whatever> {
     let map = Arc::new(
-        index::PartitionMap::new(opts.directory)
+        index::PartitionMap::with_options(config.directory, config.partitions)
             .await
             .whatever_context("failed to create the partition map")?,
     );
 
-    let router = axum::Router::new()
-        .route("/index", post(index_handle))
-        .route("/search", get(search_handle))
-        .with_state(map);
+    let router = router(map, config.body_limit);
 
-    let listener = TcpListener::bind("0.0.0.0:8497")
+    let listener = TcpListener::bind(config.address)
         .await
         .whatever_context("could not bind address")?;
 
@@ -127,3 +448,104 @@ async fn main() -> Result<(), snafu::Whatever> {
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::{
+        body::{Body, to_bytes},
+        http::{Method, Request, header},
+    };
+    use serde_json::{Value, json};
+    use tempfile::tempdir;
+    use tower::ServiceExt;
+
+    async fn send(router: &axum::Router, request: Request<Body>) -> (StatusCode, Value) {
+        let response = router.clone().oneshot(request).await.unwrap();
+        let status = response.status();
+        let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+
+        (status, serde_json::from_slice(&body).unwrap())
+    }
+
+    async fn indexed(values: &[&str]) -> (tempfile::TempDir, axum::Router) {
+        let tmp = tempdir().unwrap();
+        let map = PartitionMap::new(tmp.path().to_path_buf()).await.unwrap();
+
+        map.index(FxHashMap::from_iter([(
+            "tenant",
+            FxHashMap::from_iter([("k1", values.to_vec())]),
+        )]))
+        .await
+        .unwrap();
+
+        (tmp, router(Arc::new(map), 1024 * 1024))
+    }
+
+    #[tokio::test]
+    async fn searches_take_a_body_over_get_and_post() {
+        let (_tmp, router) = indexed(&["v1", "v2"]).await;
+
+        for method in [Method::GET, Method::POST] {
+            let request = Request::builder()
+                .method(method)
+                .uri("/search")
+                .header(header::CONTENT_TYPE, "application/json")
+                .body(Body::from(
+                    json!({"query": {"tenant": ["k1"]}, "flat": true}).to_string(),
+                ))
+                .unwrap();
+
+            assert_eq!(
+                send(&router, request).await,
+                (
+                    StatusCode::OK,
+                    json!({"data": ["v1", "v2"], "truncated": false})
+                )
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn key_lookups_page_through_the_query_string() {
+        let (_tmp, router) = indexed(&["v1", "v2", "v3"]).await;
+
+        let get = |uri: String| Request::get(uri).body(Body::empty()).unwrap();
+
+        let (status, page) = send(&router, get("/partitions/tenant/keys/k1?limit=2".into())).await;
+
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(page["data"], json!(["v1", "v2"]));
+        assert_eq!(page["truncated"], true);
+
+        let cursor = page["cursor"].as_str().unwrap();
+        let (status, page) = send(
+            &router,
+            get(format!(
+                "/partitions/tenant/keys/k1?limit=2&cursor={cursor}"
+            )),
+        )
+        .await;
+
+        assert_eq!(
+            (status, page),
+            (StatusCode::OK, json!({"data": ["v3"], "truncated": false}))
+        );
+
+        // a cursor of another key doesn't continue this one
+        let (status, error) = send(
+            &router,
+            get(format!("/partitions/tenant/keys/k2?cursor={cursor}")),
+        )
+        .await;
+
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert_eq!(error["error"]["code"], "invalid_cursor");
+        assert_eq!(error["error"]["partition"], "tenant");
+
+        let (status, error) = send(&router, get("/partitions/tenant/keys/k1?limit=0".into())).await;
+
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert_eq!(error["error"]["code"], "invalid_limit");
+    }
+}
@@ -0,0 +1,42 @@
+use serde::{Deserialize, Serialize};
+use std::{fmt, str::FromStr};
+
+use crate::{partition::PartitionError, segment::Position};
+
+/// Opaque position a search stopped at, returned along with a page of results and handed back
+/// to fetch the next one.
+///
+/// Written as z-base-32 of its JSON, so that it can be put in a URL as is.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Cursor {
+    pub(crate) partition: String,
+
+    /// Incarnation of the partition the page ended in, so that a cursor doesn't outlive a drop
+    /// of the partition.
+    pub(crate) incarnation: u64,
+
+    /// Hash of the keys looked up and of whether values were distinct, so that a cursor only
+    /// continues the query it was returned for.
+    pub(crate) query: u64,
+
+    pub(crate) key: String,
+    pub(crate) position: Position,
+}
+
+impl fmt::Display for Cursor {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let json = serde_json::to_vec(self).map_err(|_| fmt::Error)?;
+
+        f.write_str(&base32::encode(base32::Alphabet::Z, &json))
+    }
+}
+
+impl FromStr for Cursor {
+    type Err = PartitionError;
+
+    fn from_str(cursor: &str) -> Result<Self, Self::Err> {
+        base32::decode(base32::Alphabet::Z, cursor)
+            .and_then(|json| serde_json::from_slice(&json).ok())
+            .ok_or(PartitionError::InvalidCursor)
+    }
+}
@@ -1,7 +1,13 @@
 mod segment;
 mod partition;
+mod query;
+mod cursor;
+mod result;
 
 pub use fxhash;
 
-pub use partition::{PartitionMap, PartitionError};
-pub use segment::{SegmentMapError, DiskResolutionError};
+pub use partition::{PartitionMap, PartitionMapOptions, PartitionError, CacheStats};
+pub use query::Query;
+pub use cursor::Cursor;
+pub use result::{SearchResult, KeyValues};
+pub use segment::{SegmentMapError, DiskResolutionError, DamagedSegment, FlushPolicy, CompactionPolicy, Direction, SegmentStats, DiskSegmentStats};
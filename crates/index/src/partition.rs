@@ -1,10 +1,30 @@
-use fxhash::FxHashMap;
+use futures_util::{StreamExt, future, stream};
+use fxhash::{FxBuildHasher, FxHashMap, FxHashSet};
+use lru::LruCache;
+use serde::Serialize;
 use snafu::Snafu;
-use std::{path::PathBuf, sync::Arc};
-use tokio::{fs, io, sync::Mutex};
+use std::{
+    num::NonZeroUsize,
+    path::PathBuf,
+    sync::{Arc, Weak},
+};
+use tokio::{
+    fs::{self, File},
+    io,
+    sync::{Mutex, OwnedRwLockReadGuard, OwnedRwLockWriteGuard, RwLock},
+    time::{self, MissedTickBehavior},
+};
 use tracing::Instrument;
 
-use crate::segment::{self, TieredSegmentMap};
+use crate::{
+    cursor::Cursor,
+    query::Query,
+    result::SearchResult,
+    segment::{
+        self, CompactionPolicy, DamagedSegment, Direction, FlushPolicy, SegmentStats,
+        TieredSegmentMap,
+    },
+};
 
 #[derive(Debug, Snafu)]
 pub enum PartitionError {
@@ -18,34 +38,252 @@ pub enum PartitionError {
 
     #[snafu(transparent)]
     SegmentCreationError { source: segment::SegmentMapError },
+
+    #[snafu(display("query only excludes values, so it matches every other value"))]
+    UnboundedQuery,
+
+    #[snafu(display("cursor is malformed or belongs to another search"))]
+    InvalidCursor,
+
+    #[snafu(display("limit must be at least one, or a page would never move forward"))]
+    ZeroLimit,
+
+    #[snafu(display("partition name is empty"))]
+    EmptyPartition,
+}
+
+// not part of the z-base-32 alphabet, so neither can clash with a partition
+const DROPPED_PREFIX: &str = ".dropped-";
+
+const READY_PROBE: &str = ".ready-probe";
+
+const CONCURRENT_LOOKUPS: usize = 16;
+
+#[derive(Debug, Clone)]
+pub struct PartitionMapOptions {
+    pub flush: FlushPolicy,
+    pub compaction: CompactionPolicy,
+
+    pub cache_capacity: NonZeroUsize,
+}
+
+impl Default for PartitionMapOptions {
+    fn default() -> Self {
+        Self {
+            flush: FlushPolicy::default(),
+            compaction: CompactionPolicy::default(),
+            cache_capacity: NonZeroUsize::new(1024).unwrap(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize)]
+pub struct CacheStats {
+    pub hits: u64,
+    pub misses: u64,
+    pub evictions: u64,
+    pub open: usize,
+}
+
+struct Cache {
+    open: LruCache<String, Arc<RwLock<TieredSegmentMap>>, FxBuildHasher>,
+
+    // evicted partitions still held by operations in flight, picked back up when loaded again
+    // so that no two segment maps ever share a directory
+    closing: FxHashMap<String, Weak<RwLock<TieredSegmentMap>>>,
+
+    // partitions being loaded or dropped, locked until that's through
+    busy: FxHashMap<String, Arc<Mutex<()>>>,
+
+    stats: CacheStats,
+}
+
+impl Cache {
+    fn remove(&mut self, partition: &str) -> Option<Arc<RwLock<TieredSegmentMap>>> {
+        self.open.pop(partition).or_else(|| {
+            self.closing
+                .remove(partition)
+                .and_then(|segments| segments.upgrade())
+        })
+    }
+}
+
+struct Page {
+    values: Vec<String>,
+    position: Option<segment::Position>,
+    incarnation: u64,
 }
 
 pub struct PartitionMap {
     directory: PathBuf,
+    options: PartitionMapOptions,
+
+    cache: Mutex<Cache>,
+}
 
-    cache: Mutex<FxHashMap<String, Arc<Mutex<TieredSegmentMap>>>>,
+async fn flush(
+    partition: &str,
+    segments: &RwLock<TieredSegmentMap>,
+    policy: &FlushPolicy,
+) -> Result<bool, PartitionError> {
+    // most ticks have nothing to flush, and shouldn't hold up readers
+    if !segments.read().await.should_flush(policy) {
+        return Ok(false);
+    }
+
+    let job = {
+        let mut guard = segments.write().await;
+
+        if !guard.should_flush(policy) {
+            return Ok(false);
+        }
+
+        let Some(job) = guard.prepare_flush().await? else {
+            return Ok(false);
+        };
+
+        job
+    };
+
+    let result = job
+        .run()
+        .instrument(tracing::trace_span!("tiered::flush", partition))
+        .await;
+
+    segments.write().await.complete_flush(job, result).await?;
+
+    Ok(true)
+}
+
+async fn compact(
+    partition: &str,
+    segments: &RwLock<TieredSegmentMap>,
+    policy: Option<&CompactionPolicy>,
+) -> Result<bool, PartitionError> {
+    let job = {
+        let mut guard = segments.write().await;
+
+        let job = match policy {
+            Some(policy) => guard.prepare_compaction(policy).await?,
+            None => guard.prepare_full_compaction(),
+        };
+
+        let Some(job) = job else {
+            return Ok(false);
+        };
+
+        job
+    };
+
+    let result = job
+        .run()
+        .instrument(tracing::trace_span!("tiered::compact", partition))
+        .await;
+
+    segments
+        .write()
+        .await
+        .complete_compaction(job, result)
+        .await?;
+
+    Ok(true)
+}
+
+async fn maintain_in_background(
+    partition: String,
+    segments: Weak<RwLock<TieredSegmentMap>>,
+    options: PartitionMapOptions,
+) {
+    let mut interval = time::interval(options.flush.interval);
+    interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+
+    loop {
+        interval.tick().await;
+
+        let Some(segments) = segments.upgrade() else {
+            tracing::trace!(
+                partition,
+                "partition is gone, stopping background maintenance"
+            );
+
+            break;
+        };
+
+        if let Err(err) = flush(&partition, &segments, &options.flush).await {
+            tracing::warn!(partition, "background flush failed: {err:?}");
+        }
+
+        if let Err(err) = compact(&partition, &segments, Some(&options.compaction)).await {
+            tracing::warn!(partition, "background compaction failed: {err:?}");
+        }
+    }
 }
 
 impl PartitionMap {
     pub async fn new(directory: PathBuf) -> Result<Self, PartitionError> {
+        Self::with_options(directory, PartitionMapOptions::default()).await
+    }
+
+    pub async fn with_options(
+        directory: PathBuf,
+        options: PartitionMapOptions,
+    ) -> Result<Self, PartitionError> {
         fs::create_dir_all(&directory).await?;
 
         tracing::debug!("partition map directory: {directory:?}");
 
+        // finish removals interrupted by a crash
+        let mut iter = fs::read_dir(&directory).await?;
+
+        while let Some(entry) = iter.next_entry().await? {
+            if entry
+                .file_name()
+                .to_str()
+                .is_some_and(|name| name.starts_with(DROPPED_PREFIX))
+            {
+                tracing::debug!("removing dropped partition {:?}", entry.path());
+
+                fs::remove_dir_all(entry.path()).await?;
+            }
+        }
+
         Ok(Self {
             directory,
-            cache: Mutex::new(FxHashMap::default()),
+            cache: Mutex::new(Cache {
+                open: LruCache::with_hasher(options.cache_capacity, FxBuildHasher::default()),
+                closing: FxHashMap::default(),
+                busy: FxHashMap::default(),
+                stats: CacheStats::default(),
+            }),
+            options,
         })
     }
 
-    // TODO: implement cache
+    fn partition_directory_name(partition: &str) -> Result<String, PartitionError> {
+        if partition.is_empty() {
+            return Err(PartitionError::EmptyPartition);
+        }
+
+        Ok(base32::encode(base32::Alphabet::Z, partition.as_bytes()))
+    }
+
+    fn check_partitions<P: AsRef<str>>(
+        partitions: impl Iterator<Item = P>,
+    ) -> Result<(), PartitionError> {
+        for partition in partitions {
+            Self::partition_directory_name(partition.as_ref())?;
+        }
+
+        Ok(())
+    }
+
     async fn load_segment_map_from_disk(
         &self,
         partition: &str,
     ) -> Result<TieredSegmentMap, PartitionError> {
-        let key = base32::encode(base32::Alphabet::Z, partition.as_bytes());
-
-        let directory = self.directory.join(key);
+        let directory = self
+            .directory
+            .join(Self::partition_directory_name(partition)?);
 
         tracing::debug!("partition directory: {directory:?}");
 
@@ -55,19 +293,138 @@ impl PartitionMap {
     async fn load_segment_map(
         &self,
         partition: &str,
-    ) -> Result<Arc<Mutex<TieredSegmentMap>>, PartitionError> {
+    ) -> Result<Arc<RwLock<TieredSegmentMap>>, PartitionError> {
+        let (segments, evicted) = loop {
+            let mut guard = self.cache.lock().await;
+
+            if let Some(busy) = guard.busy.get(partition).cloned() {
+                drop(guard);
+                self.wait_for(partition, busy).await;
+
+                continue;
+            }
+
+            if let Some(entry) = guard.open.get(partition) {
+                let entry = entry.clone();
+                guard.stats.hits += 1;
+
+                return Ok(entry);
+            }
+
+            guard.stats.misses += 1;
+
+            let closing = guard
+                .closing
+                .remove(partition)
+                .and_then(|segments| segments.upgrade());
+
+            let segments = if let Some(segments) = closing {
+                tracing::debug!(partition, "reopening partition that is still being closed");
+
+                // the background task is still running, as the map never went away
+                segments
+            } else {
+                let marker = Arc::new(Mutex::new(()));
+                let loading = marker.clone().lock_owned().await;
+
+                guard.busy.insert(partition.to_string(), marker);
+                drop(guard);
+
+                // a cold partition shouldn't hold up lookups in the open ones
+                let loaded = self.load_segment_map_from_disk(partition).await;
+
+                guard = self.cache.lock().await;
+                guard.busy.remove(partition);
+                drop(loading);
+
+                let segments = Arc::new(RwLock::new(loaded?));
+
+                tokio::spawn(maintain_in_background(
+                    partition.to_string(),
+                    Arc::downgrade(&segments),
+                    self.options.clone(),
+                ));
+
+                segments
+            };
+
+            let evicted = guard.open.push(partition.to_string(), segments.clone());
+
+            if let Some((evicted, evicted_segments)) = &evicted {
+                guard.stats.evictions += 1;
+
+                guard
+                    .closing
+                    .retain(|_, segments| segments.strong_count() > 0);
+                guard
+                    .closing
+                    .insert(evicted.clone(), Arc::downgrade(evicted_segments));
+            }
+
+            break (segments, evicted);
+        };
+
+        if let Some((evicted, evicted_segments)) = evicted {
+            tracing::debug!(partition = evicted, "evicting partition");
+
+            if let Err(err) = evicted_segments
+                .write()
+                .await
+                .flush()
+                .instrument(tracing::trace_span!(
+                    "tiered::flush",
+                    partition = evicted.as_str()
+                ))
+                .await
+            {
+                tracing::warn!(
+                    partition = evicted,
+                    "flush of evicted partition failed: {err:?}"
+                );
+            }
+        }
+
+        Ok(segments)
+    }
+
+    async fn wait_for(&self, partition: &str, busy: Arc<Mutex<()>>) {
+        drop(busy.lock().await);
+
         let mut guard = self.cache.lock().await;
-        if let Some(entry) = guard.get(partition) {
-            Ok(entry.clone())
-        } else {
-            guard.insert(
-                partition.to_string(),
-                Arc::new(Mutex::new(
-                    self.load_segment_map_from_disk(partition).await?,
-                )),
-            );
 
-            Ok(guard.get(partition).unwrap().clone())
+        // a load or drop cancelled midway leaves its marker behind
+        if guard
+            .busy
+            .get(partition)
+            .is_some_and(|marker| Arc::ptr_eq(marker, &busy))
+        {
+            guard.busy.remove(partition);
+        }
+    }
+
+    async fn read_segment_map(
+        &self,
+        partition: &str,
+    ) -> Result<OwnedRwLockReadGuard<TieredSegmentMap>, PartitionError> {
+        loop {
+            let guard = self.load_segment_map(partition).await?.read_owned().await;
+
+            if !guard.is_dropped() {
+                return Ok(guard);
+            }
+        }
+    }
+
+    async fn write_segment_map(
+        &self,
+        partition: &str,
+    ) -> Result<OwnedRwLockWriteGuard<TieredSegmentMap>, PartitionError> {
+        loop {
+            let guard = self.load_segment_map(partition).await?.write_owned().await;
+
+            if !guard.is_dropped() {
+                return Ok(guard);
+            }
         }
     }
 
@@ -75,12 +432,11 @@ impl PartitionMap {
         &self,
         map: FxHashMap<P, FxHashMap<K, Vec<B>>>,
     ) -> Result<(), PartitionError> {
-        for (partition, entries) in map {
-            let segment = self.load_segment_map(partition.as_ref()).await?;
+        Self::check_partitions(map.keys())?;
 
-            segment
-                .lock()
-                .await
+        for (partition, entries) in map {
+            self.write_segment_map(partition.as_ref())
+                .await?
                 .insert(entries)
                 .instrument(tracing::trace_span!(
                     "tiered::index",
@@ -92,42 +448,1222 @@ impl PartitionMap {
         Ok(())
     }
 
-    pub async fn search<K: AsRef<str> + Ord, B: AsRef<str>>(
+    pub async fn delete<P: AsRef<str>, K: AsRef<str>, B: AsRef<str>>(
+        &self,
+        map: FxHashMap<P, FxHashMap<K, Option<Vec<B>>>>,
+    ) -> Result<(), PartitionError> {
+        Self::check_partitions(map.keys())?;
+
+        for (partition, deletion) in map {
+            self.write_segment_map(partition.as_ref())
+                .await?
+                .delete(deletion)
+                .instrument(tracing::trace_span!(
+                    "tiered::delete",
+                    partition = partition.as_ref(),
+                ))
+                .await?;
+        }
+
+        Ok(())
+    }
+
+    /// With `distinct`, a value found under several keys is only returned for the first one.
+    pub async fn search<K: AsRef<str>, B: AsRef<str>>(
         &self,
         query: FxHashMap<K, Vec<B>>,
-        mut limit: Option<usize>,
+        limit: Option<usize>,
+        distinct: bool,
     ) -> Result<Vec<String>, PartitionError> {
-        let mut result = Vec::new();
-
-        for (partition, keys) in query {
-            let segments = self.load_segment_map(partition.as_ref()).await?;
-
-            for key in keys {
-                result.extend(
-                    segments
-                        .lock()
-                        .await
-                        .find(key.as_ref(), limit)
-                        .instrument(tracing::trace_span!(
-                            "tiered::find",
-                            partition = partition.as_ref(),
-                            key = key.as_ref(),
-                        ))
-                        .await?,
-                );
+        Ok(self
+            .search_page(query, None, limit, distinct)
+            .await?
+            .0
+            .into_flat())
+    }
+
+    /// Like [`Self::search`], continuing after the page `cursor` was returned with. Keys are
+    /// looked up in order, so that every page of a search sees them in the same order.
+    pub async fn search_page<K: AsRef<str>, B: AsRef<str>>(
+        &self,
+        query: FxHashMap<K, Vec<B>>,
+        cursor: Option<Cursor>,
+        limit: Option<usize>,
+        distinct: bool,
+    ) -> Result<(SearchResult, Option<Cursor>), PartitionError> {
+        if limit == Some(0) {
+            return Err(PartitionError::ZeroLimit);
+        }
+
+        let mut lookups = query
+            .iter()
+            .flat_map(|(partition, keys)| {
+                keys.iter()
+                    .map(move |key| (partition.as_ref(), key.as_ref()))
+            })
+            .collect::<Vec<_>>();
+
+        lookups.sort_unstable();
+        lookups.dedup();
+
+        // a cursor only continues the query it was returned for
+        let query = fxhash::hash64(&(&lookups, distinct));
 
-                if let Some(value) = limit {
-                    let left = value.saturating_sub(result.len());
+        let resumed = cursor.is_some();
+        let (start, resumed_at, incarnation) = match cursor {
+            Some(cursor) if cursor.query == query => {
+                let start = lookups
+                    .binary_search(&(cursor.partition.as_str(), cursor.key.as_str()))
+                    .map_err(|_| PartitionError::InvalidCursor)?;
 
-                    if left > 0 {
-                        limit = Some(left);
-                    } else {
+                (start, Some(cursor.position), Some(cursor.incarnation))
+            }
+            Some(_) => return Err(PartitionError::InvalidCursor),
+            None => (0, None, None),
+        };
+
+        // keys are looked up a few at a time ahead of the one being cut down to the limit, and
+        // none of them for more than a page
+        let mut prefetched = stream::iter(start..lookups.len())
+            .map(|index| {
+                let (partition, key) = lookups[index];
+                let after = if index == start {
+                    resumed_at.clone()
+                } else {
+                    None
+                };
+
+                self.find_page(partition, key, after, limit)
+            })
+            .buffered(CONCURRENT_LOOKUPS);
+
+        let mut result = SearchResult::default();
+        let mut returned = 0;
+        let mut taken = FxHashSet::default();
+
+        for (index, &(partition, key)) in lookups.iter().enumerate().skip(start) {
+            // keys looked up by previous pages, which returned their values already
+            let previous = if resumed { index.min(start + 1) } else { 0 };
+
+            let mut page = prefetched.next().await.transpose()?;
+            let mut after = if index == start {
+                resumed_at.clone()
+            } else {
+                None
+            };
+
+            loop {
+                let Page {
+                    values,
+                    position,
+                    incarnation: found_in,
+                } = match page.take() {
+                    Some(page) => page,
+                    None => {
+                        self.find_page(
+                            partition,
+                            key,
+                            after.take(),
+                            limit.map(|limit| limit - returned),
+                        )
+                        .await?
+                    }
+                };
+
+                // positions within a dropped partition mean nothing to the one replacing it
+                if index == start && incarnation.is_some_and(|incarnation| incarnation != found_in)
+                {
+                    return Err(PartitionError::InvalidCursor);
+                }
+
+                // values of keys returned by previous pages, checked once for the whole batch
+                let held = if distinct && previous > 0 {
+                    self.held(&lookups[..previous], &values).await?
+                } else {
+                    FxHashSet::default()
+                };
+
+                let room = limit.map_or(usize::MAX, |limit| limit - returned);
+                let mut kept = Vec::with_capacity(values.len().min(room));
+
+                for value in values {
+                    if distinct && (!taken.insert(value.clone()) || held.contains(&value)) {
+                        continue;
+                    }
+
+                    kept.push(value);
+
+                    if kept.len() == room {
                         break;
                     }
                 }
+
+                // keys are looked up for a whole page, so the values past the room left, and
+                // the keys after, are left to the next one
+                let position = match kept.last() {
+                    Some(last) if kept.len() == room => Some(segment::Position {
+                        value: last.clone(),
+                    }),
+                    _ => position,
+                };
+
+                returned += kept.len();
+                let found = result.push(partition, key, kept);
+
+                let Some(position) = position else {
+                    break;
+                };
+
+                if limit.is_some_and(|limit| returned >= limit) {
+                    found.truncated = true;
+                    result.truncated = true;
+
+                    let cursor = Cursor {
+                        partition: partition.to_string(),
+                        incarnation: found_in,
+                        query,
+                        key: key.to_string(),
+                        position,
+                    };
+
+                    return Ok((result, Some(cursor)));
+                }
+
+                // values taken from other keys were skipped, leaving room for more
+                after = Some(position);
+            }
+        }
+
+        Ok((result, None))
+    }
+
+    async fn held(
+        &self,
+        lookups: &[(&str, &str)],
+        values: &[String],
+    ) -> Result<FxHashSet<String>, PartitionError> {
+        let mut held = FxHashSet::default();
+
+        // lookups are sorted, so the keys of a partition are next to each other
+        for keys in lookups.chunk_by(|a, b| a.0 == b.0) {
+            let segments = self.read_segment_map(keys[0].0).await?;
+
+            for &(_, key) in keys {
+                for value in values {
+                    if !held.contains(value) && segments.holds(key, value).await? {
+                        held.insert(value.clone());
+                    }
+                }
+            }
+        }
+
+        Ok(held)
+    }
+
+    async fn find_page(
+        &self,
+        partition: &str,
+        key: &str,
+        after: Option<segment::Position>,
+        limit: Option<usize>,
+    ) -> Result<Page, PartitionError> {
+        let segments = self.read_segment_map(partition).await?;
+
+        let (values, position) = segments
+            .find_page(key, after, limit)
+            .instrument(tracing::trace_span!(
+                "tiered::find_page",
+                partition = partition,
+                key = key,
+            ))
+            .await?;
+
+        Ok(Page {
+            values,
+            position,
+            incarnation: segments.incarnation(),
+        })
+    }
+
+    /// Keys are returned in order within each prefix.
+    pub async fn find_prefix<P: AsRef<str>, B: AsRef<str>>(
+        &self,
+        query: FxHashMap<P, Vec<B>>,
+        limit: Option<usize>,
+    ) -> Result<SearchResult, PartitionError> {
+        let mut lookups = query
+            .iter()
+            .flat_map(|(partition, prefixes)| {
+                prefixes
+                    .iter()
+                    .map(move |prefix| (partition.as_ref(), prefix.as_ref()))
+            })
+            .collect::<Vec<_>>();
+
+        lookups.sort_unstable();
+        lookups.dedup();
+
+        // every prefix is looked up at once, then cut down to the limit in order
+        let found = future::try_join_all(lookups.iter().map(|&(partition, prefix)| async move {
+            let found = self
+                .read_segment_map(partition)
+                .await?
+                .find_prefix(prefix, limit)
+                .instrument(tracing::trace_span!(
+                    "tiered::find_prefix",
+                    partition = partition,
+                    prefix = prefix,
+                ))
+                .await?;
+
+            Ok::<_, PartitionError>(found)
+        }))
+        .await?;
+
+        let mut result = SearchResult::default();
+        let mut returned = 0;
+
+        for ((partition, _), found) in lookups.into_iter().zip(found) {
+            if limit == Some(returned) {
+                result.truncated = true;
+                break;
+            }
+
+            let mut last = None;
+
+            for (key, mut values) in found {
+                let left = limit.map_or(usize::MAX, |limit| limit - returned);
+
+                if left == 0 {
+                    break;
+                }
+
+                values.truncate(left);
+                returned += values.len();
+                result.push(partition, &key, values);
+
+                last = Some(key);
+            }
+
+            // the limit may have cut the last key short
+            if let Some(key) = last.filter(|_| limit == Some(returned)) {
+                result.push(partition, &key, Vec::new()).truncated = true;
+                result.truncated = true;
             }
         }
 
         Ok(result)
     }
+
+    pub async fn query<P: AsRef<str>>(
+        &self,
+        queries: FxHashMap<P, Query>,
+        limit: Option<usize>,
+    ) -> Result<SearchResult, PartitionError> {
+        if !queries.values().all(Query::is_bounded) {
+            return Err(PartitionError::UnboundedQuery);
+        }
+
+        let mut queries = queries.into_iter().collect::<Vec<_>>();
+        queries.sort_unstable_by(|(a, _), (b, _)| a.as_ref().cmp(b.as_ref()));
+
+        // every partition is evaluated at once, then cut down to the limit in order
+        let matched = future::try_join_all(queries.iter().map(|(partition, query)| async move {
+            let matched = query
+                .evaluate(&*self.read_segment_map(partition.as_ref()).await?)
+                .instrument(tracing::trace_span!(
+                    "tiered::query",
+                    partition = partition.as_ref(),
+                ))
+                .await?;
+
+            Ok::<_, PartitionError>(matched)
+        }))
+        .await?;
+
+        let mut result = SearchResult::default();
+        let mut returned = 0;
+
+        for ((partition, _), mut matched) in queries.iter().zip(matched) {
+            let left = limit.map(|limit| limit - returned);
+
+            if left == Some(0) {
+                result.truncated = true;
+                break;
+            }
+
+            if let Some(left) = left.filter(|&left| matched.len() > left) {
+                matched.truncate(left);
+                result.truncated = true;
+            }
+
+            returned += matched.len();
+            result
+                .queries
+                .insert(partition.as_ref().to_string(), matched);
+        }
+
+        Ok(result)
+    }
+
+    /// Scans the keys within `[start, end)`, or past `start` without an end.
+    pub async fn scan(
+        &self,
+        partition: &str,
+        start: &str,
+        end: Option<&str>,
+        direction: Direction,
+        limit: Option<usize>,
+    ) -> Result<Vec<(String, Vec<String>)>, PartitionError> {
+        Ok(self
+            .read_segment_map(partition)
+            .await?
+            .scan(start, end, direction, limit)
+            .instrument(tracing::trace_span!(
+                "tiered::scan",
+                partition = partition,
+                start = start,
+                end = end,
+            ))
+            .await?)
+    }
+
+    pub async fn count(&self, partition: &str, key: &str) -> Result<usize, PartitionError> {
+        Ok(self
+            .read_segment_map(partition)
+            .await?
+            .count(key)
+            .instrument(tracing::trace_span!(
+                "tiered::count",
+                partition = partition,
+                key = key,
+            ))
+            .await?)
+    }
+
+    pub async fn contains(&self, partition: &str, key: &str) -> Result<bool, PartitionError> {
+        Ok(self
+            .read_segment_map(partition)
+            .await?
+            .contains(key)
+            .instrument(tracing::trace_span!(
+                "tiered::contains",
+                partition = partition,
+                key = key,
+            ))
+            .await?)
+    }
+
+    pub async fn find_keys_for_value(
+        &self,
+        partition: &str,
+        value: &str,
+        limit: Option<usize>,
+    ) -> Result<Vec<String>, PartitionError> {
+        Ok(self
+            .read_segment_map(partition)
+            .await?
+            .find_keys_for_value(value, limit)
+            .instrument(tracing::trace_span!(
+                "tiered::find_keys_for_value",
+                partition = partition,
+                value = value,
+            ))
+            .await?)
+    }
+
+    pub async fn flush(&self) -> Result<(), PartitionError> {
+        let partitions = {
+            let guard = self.cache.lock().await;
+
+            guard
+                .open
+                .iter()
+                .map(|(partition, segments)| (partition.clone(), segments.clone()))
+                .chain(guard.closing.iter().filter_map(|(partition, segments)| {
+                    Some((partition.clone(), segments.upgrade()?))
+                }))
+                .collect::<Vec<_>>()
+        };
+
+        for (partition, segments) in partitions {
+            segments
+                .write()
+                .await
+                .flush()
+                .instrument(tracing::trace_span!(
+                    "tiered::flush",
+                    partition = partition.as_str(),
+                ))
+                .await?;
+        }
+
+        Ok(())
+    }
+
+    pub async fn compact(&self, partition: &str) -> Result<bool, PartitionError> {
+        let segments = self.load_segment_map(partition).await?;
+
+        compact(partition, &segments, None).await
+    }
+
+    pub async fn verify(&self, partition: &str) -> Result<Vec<DamagedSegment>, PartitionError> {
+        let segments = self.load_segment_map(partition).await?;
+
+        let verify = segments.read().await.verify();
+
+        Ok(verify
+            .instrument(tracing::trace_span!("tiered::verify", partition))
+            .await)
+    }
+
+    pub async fn stats(&self, partition: &str) -> Result<SegmentStats, PartitionError> {
+        Ok(self
+            .read_segment_map(partition)
+            .await?
+            .stats()
+            .instrument(tracing::trace_span!("tiered::stats", partition))
+            .await?)
+    }
+
+    /// Whether the partition has data on disk, without loading it.
+    pub async fn has_partition(&self, partition: &str) -> Result<bool, PartitionError> {
+        let directory = self
+            .directory
+            .join(Self::partition_directory_name(partition)?);
+
+        Ok(fs::try_exists(directory).await?)
+    }
+
+    pub async fn partitions(&self) -> Result<Vec<String>, PartitionError> {
+        let mut partitions = Vec::new();
+        let mut iter = fs::read_dir(&self.directory).await?;
+
+        while let Some(entry) = iter.next_entry().await? {
+            if !entry.file_type().await?.is_dir() {
+                continue;
+            }
+
+            let partition = entry
+                .file_name()
+                .to_str()
+                .and_then(|name| base32::decode(base32::Alphabet::Z, name))
+                .and_then(|partition| String::from_utf8(partition).ok());
+
+            match partition {
+                Some(partition) => partitions.push(partition),
+                None => tracing::debug!("ignoring entry {:?}", entry.path()),
+            }
+        }
+
+        partitions.sort_unstable();
+
+        Ok(partitions)
+    }
+
+    /// Checks that the directory takes writes, leaving partitions to be loaded as needed.
+    pub async fn check_ready(&self) -> Result<(), PartitionError> {
+        fs::read_dir(&self.directory).await?.next_entry().await?;
+
+        let probe = self.directory.join(READY_PROBE);
+
+        File::create(&probe).await?.sync_all().await?;
+        fs::remove_file(&probe).await?;
+
+        Ok(())
+    }
+
+    pub async fn cache_stats(&self) -> CacheStats {
+        let guard = self.cache.lock().await;
+
+        CacheStats {
+            open: guard.open.len(),
+            ..guard.stats
+        }
+    }
+
+    /// Waits for the operations and background jobs holding the partition to finish; the ones
+    /// still queued up for it, and anything issued afterwards, see an empty partition.
+    pub async fn drop_partition(&self, partition: &str) -> Result<bool, PartitionError> {
+        Self::partition_directory_name(partition)?;
+
+        let (segments, dropping) = loop {
+            let mut cache = self.cache.lock().await;
+
+            if let Some(busy) = cache.busy.get(partition).cloned() {
+                drop(cache);
+                self.wait_for(partition, busy).await;
+
+                continue;
+            }
+
+            let marker = Arc::new(Mutex::new(()));
+            let dropping = marker.clone().lock_owned().await;
+
+            cache.busy.insert(partition.to_string(), marker);
+
+            break (cache.remove(partition), dropping);
+        };
+
+        let result = self.remove_partition(partition, segments).await;
+
+        self.cache.lock().await.busy.remove(partition);
+        drop(dropping);
+
+        result
+    }
+
+    async fn remove_partition(
+        &self,
+        partition: &str,
+        segments: Option<Arc<RwLock<TieredSegmentMap>>>,
+    ) -> Result<bool, PartitionError> {
+        if let Some(segments) = segments {
+            let jobs = segments.write().await.mark_dropped();
+
+            // jobs still running would otherwise write into the directory once it's gone,
+            // or into the one of a partition created again under the same name
+            jobs.await;
+        }
+
+        let name = Self::partition_directory_name(partition)?;
+        let directory = self.directory.join(&name);
+
+        if !fs::try_exists(&directory).await? {
+            return Ok(false);
+        }
+
+        // renamed first, so a crash halfway through can't leave a partial partition behind
+        let removed = self.directory.join(format!("{DROPPED_PREFIX}{name}"));
+
+        fs::rename(&directory, &removed).await?;
+        File::open(&self.directory).await?.sync_all().await?;
+
+        fs::remove_dir_all(&removed).await?;
+
+        tracing::debug!(partition, "dropped partition");
+
+        Ok(true)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+    use tempfile::tempdir;
+
+    fn entries(key: &str, values: &[&str]) -> FxHashMap<String, FxHashMap<String, Vec<String>>> {
+        let mut entries = FxHashMap::default();
+        entries.insert(
+            key.to_string(),
+            values.iter().map(|value| value.to_string()).collect(),
+        );
+
+        let mut map = FxHashMap::default();
+        map.insert("tenant".to_string(), entries);
+
+        map
+    }
+
+    fn query(key: &str) -> FxHashMap<&str, Vec<&str>> {
+        let mut query = FxHashMap::default();
+        query.insert("tenant", vec![key]);
+
+        query
+    }
+
+    #[tokio::test]
+    async fn empty_partition_names_are_rejected() {
+        let tmp = tempdir().unwrap();
+        let map = PartitionMap::new(tmp.path().to_path_buf()).await.unwrap();
+
+        map.index(entries("k1", &["v1"])).await.unwrap();
+
+        // nothing is written when any of the partitions is rejected
+        let mut both = entries("k2", &["v2"]);
+        both.insert(String::new(), both["tenant"].clone());
+
+        assert!(matches!(
+            map.index(both).await,
+            Err(PartitionError::EmptyPartition)
+        ));
+        assert!(matches!(
+            map.search(FxHashMap::from_iter([("", vec!["k1"])]), None, false)
+                .await,
+            Err(PartitionError::EmptyPartition)
+        ));
+        assert!(matches!(
+            map.stats("").await,
+            Err(PartitionError::EmptyPartition)
+        ));
+        assert!(matches!(
+            map.drop_partition("").await,
+            Err(PartitionError::EmptyPartition)
+        ));
+
+        // the data directory it would stand for is left alone
+        assert_eq!(map.partitions().await.unwrap(), ["tenant"]);
+        assert_eq!(map.search(query("k1"), None, false).await.unwrap(), ["v1"]);
+        assert!(
+            map.search(query("k2"), None, false)
+                .await
+                .unwrap()
+                .is_empty()
+        );
+    }
+
+    #[tokio::test]
+    async fn drop_partition_removes_its_data() {
+        let tmp = tempdir().unwrap();
+        let map = PartitionMap::new(tmp.path().to_path_buf()).await.unwrap();
+
+        map.index(entries("k1", &["v1"])).await.unwrap();
+        map.flush().await.unwrap();
+        map.index(entries("k2", &["v2"])).await.unwrap();
+
+        assert!(map.drop_partition("tenant").await.unwrap());
+        assert!(!map.drop_partition("tenant").await.unwrap());
+
+        let mut iter = fs::read_dir(tmp.path()).await.unwrap();
+        assert!(iter.next_entry().await.unwrap().is_none());
+
+        assert!(
+            map.search(query("k1"), None, false)
+                .await
+                .unwrap()
+                .is_empty()
+        );
+        assert!(
+            map.search(query("k2"), None, false)
+                .await
+                .unwrap()
+                .is_empty()
+        );
+
+        // the partition starts over from scratch
+        map.index(entries("k1", &["v3"])).await.unwrap();
+        assert_eq!(map.search(query("k1"), None, false).await.unwrap(), ["v3"]);
+
+        drop(map);
+
+        let map = PartitionMap::new(tmp.path().to_path_buf()).await.unwrap();
+        assert_eq!(map.search(query("k1"), None, false).await.unwrap(), ["v3"]);
+    }
+
+    #[tokio::test]
+    async fn concurrent_loads_share_one_segment_map() {
+        let tmp = tempdir().unwrap();
+        let map = PartitionMap::new(tmp.path().to_path_buf()).await.unwrap();
+
+        map.index(entries("k1", &["v1"])).await.unwrap();
+        map.flush().await.unwrap();
+        drop(map);
+
+        let map = PartitionMap::new(tmp.path().to_path_buf()).await.unwrap();
+
+        let loaded = future::try_join_all((0..8).map(|_| map.load_segment_map("tenant")))
+            .await
+            .unwrap();
+
+        assert!(
+            loaded
+                .iter()
+                .all(|segments| Arc::ptr_eq(segments, &loaded[0]))
+        );
+        assert_eq!(map.cache_stats().await.misses, 1);
+    }
+
+    #[tokio::test]
+    async fn damaged_segments_fail_lookups() {
+        for name in ["keys.data.bin", "entries.bin"] {
+            let tmp = tempdir().unwrap();
+            let map = PartitionMap::new(tmp.path().to_path_buf()).await.unwrap();
+
+            map.index(entries("k1", &["v1", "v2", "v3"])).await.unwrap();
+            map.flush().await.unwrap();
+            drop(map);
+
+            let directory = tmp
+                .path()
+                .join(PartitionMap::partition_directory_name("tenant").unwrap());
+            let mut iter = fs::read_dir(&directory).await.unwrap();
+
+            // a byte in the middle of the file, so that its length stays the same
+            while let Some(entry) = iter.next_entry().await.unwrap() {
+                if entry.file_type().await.unwrap().is_dir() {
+                    let path = entry.path().join(name);
+                    let mut buffer = fs::read(&path).await.unwrap();
+                    let middle = buffer.len() / 2;
+                    buffer[middle] ^= 0b1;
+                    fs::write(&path, buffer).await.unwrap();
+                }
+            }
+
+            let map = PartitionMap::new(tmp.path().to_path_buf()).await.unwrap();
+
+            assert!(matches!(
+                map.search(query("k1"), None, false).await,
+                Err(PartitionError::SegmentCreationError {
+                    source: segment::SegmentMapError::ResolutionError {
+                        source: segment::DiskResolutionError::Corrupted { file },
+                    },
+                }) if file == name
+            ));
+        }
+    }
+
+    #[tokio::test]
+    async fn search_pages_follow_their_cursor() {
+        let tmp = tempdir().unwrap();
+        let map = PartitionMap::new(tmp.path().to_path_buf()).await.unwrap();
+
+        map.index(entries("k1", &["v1", "v2", "v3"])).await.unwrap();
+        map.flush().await.unwrap();
+        map.index(entries("k2", &["v4", "v5"])).await.unwrap();
+
+        let mut query = FxHashMap::default();
+        query.insert("tenant", vec!["k1", "k2"]);
+
+        let mut pages = Vec::new();
+        let mut cursor = None;
+
+        loop {
+            let (page, next) = map
+                .search_page(query.clone(), cursor, Some(2), false)
+                .await
+                .unwrap();
+            pages.push(page.into_flat());
+
+            // cursors go through their text form, as they would over the API
+            let Some(next) = next else {
+                break;
+            };
+            cursor = Some(next.to_string().parse().unwrap());
+        }
+
+        assert_eq!(pages, [vec!["v1", "v2"], vec!["v3", "v4"], vec!["v5"]]);
+
+        let (_, cursor) = map
+            .search_page(query.clone(), None, Some(1), false)
+            .await
+            .unwrap();
+
+        assert!(matches!(
+            map.search_page(self::query("k3"), cursor.clone(), None, false)
+                .await,
+            Err(PartitionError::InvalidCursor)
+        ));
+
+        // the key the cursor stopped at is looked up by these too, but they are other queries
+        let mut wider = query.clone();
+        wider.insert("tenant", vec!["k0", "k1", "k2"]);
+
+        assert!(matches!(
+            map.search_page(wider, cursor.clone(), None, false).await,
+            Err(PartitionError::InvalidCursor)
+        ));
+        assert!(matches!(
+            map.search_page(query.clone(), cursor.clone(), None, true)
+                .await,
+            Err(PartitionError::InvalidCursor)
+        ));
+        assert!(matches!(
+            "not a cursor".parse::<Cursor>(),
+            Err(PartitionError::InvalidCursor)
+        ));
+
+        // an empty page would hand back the very cursor it was given
+        assert!(matches!(
+            map.search_page(query, cursor, Some(0), false).await,
+            Err(PartitionError::ZeroLimit)
+        ));
+    }
+
+    #[tokio::test]
+    async fn limited_pages_cut_keys_looked_up_at_once() {
+        let tmp = tempdir().unwrap();
+        let map = PartitionMap::new(tmp.path().to_path_buf()).await.unwrap();
+
+        let mut values = FxHashMap::default();
+        values.insert("k1".to_string(), vec!["v1".to_string()]);
+        values.insert("k2".to_string(), vec!["v2".to_string()]);
+        map.index([("a", values)].into_iter().collect())
+            .await
+            .unwrap();
+
+        let mut values = FxHashMap::default();
+        values.insert(
+            "k3".to_string(),
+            vec!["v3".to_string(), "v4".to_string(), "v5".to_string()],
+        );
+        map.index([("b", values)].into_iter().collect())
+            .await
+            .unwrap();
+
+        let mut query = FxHashMap::default();
+        query.insert("a", vec!["k2", "k1"]);
+        query.insert("b", vec!["k3"]);
+
+        let mut pages = Vec::new();
+        let mut cursor = None;
+
+        loop {
+            let (page, next) = map
+                .search_page(query.clone(), cursor, Some(2), false)
+                .await
+                .unwrap();
+            pages.push(page.into_flat());
+
+            let Some(next) = next else {
+                break;
+            };
+            cursor = Some(next);
+        }
+
+        // the second key fills the first page exactly, and the third one is left to the next
+        assert_eq!(pages, [vec!["v1", "v2"], vec!["v3", "v4"], vec!["v5"]]);
+    }
+
+    #[tokio::test]
+    async fn limited_pages_stop_looking_keys_up_once_full() {
+        let tmp = tempdir().unwrap();
+        let map = PartitionMap::new(tmp.path().to_path_buf()).await.unwrap();
+
+        let partitions = (0..CONCURRENT_LOOKUPS * 3)
+            .map(|i| format!("p{i:02}"))
+            .collect::<Vec<_>>();
+
+        for partition in &partitions {
+            let values = FxHashMap::from_iter([("k", vec!["v"])]);
+            map.index(FxHashMap::from_iter([(partition.as_str(), values)]))
+                .await
+                .unwrap();
+        }
+
+        drop(map);
+        let map = PartitionMap::new(tmp.path().to_path_buf()).await.unwrap();
+
+        let query = partitions
+            .iter()
+            .map(|partition| (partition.as_str(), vec!["k"]))
+            .collect::<FxHashMap<_, _>>();
+
+        let (page, cursor) = map.search_page(query, None, Some(1), false).await.unwrap();
+
+        assert_eq!(page.into_flat(), ["v"]);
+        assert!(cursor.is_some());
+        assert!(map.cache_stats().await.misses <= CONCURRENT_LOOKUPS as u64);
+    }
+
+    #[tokio::test]
+    async fn cursors_do_not_outlive_their_partition() {
+        let tmp = tempdir().unwrap();
+        let map = PartitionMap::new(tmp.path().to_path_buf()).await.unwrap();
+
+        map.index(entries("k1", &["v1", "v2", "v3"])).await.unwrap();
+
+        let (_, cursor) = map
+            .search_page(query("k1"), None, Some(1), false)
+            .await
+            .unwrap();
+
+        // reopening the partition keeps it the same one
+        drop(map);
+        let map = PartitionMap::new(tmp.path().to_path_buf()).await.unwrap();
+
+        let (page, _) = map
+            .search_page(query("k1"), cursor.clone(), Some(1), false)
+            .await
+            .unwrap();
+        assert_eq!(page.into_flat(), ["v2"]);
+
+        // a partition created again starts over, even with the same values
+        assert!(map.drop_partition("tenant").await.unwrap());
+        map.index(entries("k1", &["v1", "v2", "v3"])).await.unwrap();
+
+        assert!(matches!(
+            map.search_page(query("k1"), cursor, Some(1), false).await,
+            Err(PartitionError::InvalidCursor)
+        ));
+    }
+
+    #[tokio::test]
+    async fn distinct_searches_return_values_once_across_keys() {
+        let tmp = tempdir().unwrap();
+        let map = PartitionMap::new(tmp.path().to_path_buf()).await.unwrap();
+
+        map.index(entries("k1", &["v1", "v2"])).await.unwrap();
+        map.flush().await.unwrap();
+        map.index(entries("k1", &["v2", "v3"])).await.unwrap();
+        map.index(entries("k2", &["v3", "v4", "v1", "v5"]))
+            .await
+            .unwrap();
+
+        let mut query = FxHashMap::default();
+        query.insert("tenant", vec!["k1", "k2"]);
+
+        assert_eq!(
+            map.search(query.clone(), None, false).await.unwrap(),
+            ["v1", "v2", "v3", "v1", "v3", "v4", "v5"]
+        );
+        assert_eq!(
+            map.search(query.clone(), None, true).await.unwrap(),
+            ["v1", "v2", "v3", "v4", "v5"]
+        );
+
+        // pages keep leaving out values of keys looked up by the previous ones
+        let mut pages = Vec::new();
+        let mut cursor = None;
+
+        loop {
+            let (page, next) = map
+                .search_page(query.clone(), cursor, Some(2), true)
+                .await
+                .unwrap();
+            pages.push(page.into_flat());
+
+            let Some(next) = next else {
+                break;
+            };
+            cursor = Some(next);
+        }
+
+        assert_eq!(pages, [vec!["v1", "v2"], vec!["v3", "v4"], vec!["v5"]]);
+    }
+
+    #[tokio::test]
+    async fn search_results_are_attributed_to_their_keys() {
+        let tmp = tempdir().unwrap();
+        let map = PartitionMap::new(tmp.path().to_path_buf()).await.unwrap();
+
+        map.index(entries("k1", &["v1", "v2", "v3"])).await.unwrap();
+        map.index(entries("k2", &["v4"])).await.unwrap();
+
+        let mut query = FxHashMap::default();
+        query.insert("tenant", vec!["k2", "k1", "missing"]);
+
+        let (result, cursor) = map
+            .search_page(query.clone(), None, Some(2), false)
+            .await
+            .unwrap();
+
+        let keys = &result.partitions["tenant"];
+        assert_eq!(keys.len(), 1);
+        assert_eq!(keys["k1"].values, ["v1", "v2"]);
+        assert!(keys["k1"].truncated && result.truncated);
+
+        let (result, _) = map
+            .search_page(query.clone(), cursor, None, false)
+            .await
+            .unwrap();
+
+        // keys without values are still reported
+        let keys = &result.partitions["tenant"];
+        assert_eq!(keys["k1"].values, ["v3"]);
+        assert_eq!(keys["k2"].values, ["v4"]);
+        assert!(keys["missing"].values.is_empty());
+        assert!(!result.truncated);
+
+        let mut prefixes = FxHashMap::default();
+        prefixes.insert("tenant", vec!["k"]);
+
+        let result = map.find_prefix(prefixes, Some(3)).await.unwrap();
+        assert_eq!(result.partitions["tenant"]["k1"].values, ["v1", "v2", "v3"]);
+        assert!(result.partitions["tenant"]["k1"].truncated && result.truncated);
+
+        let mut queries = FxHashMap::default();
+        queries.insert("tenant", Query::Term("k1".to_string()));
+
+        let result = map.query(queries, Some(2)).await.unwrap();
+        assert_eq!(result.queries["tenant"], ["v1", "v2"]);
+        assert!(result.truncated);
+        assert_eq!(result.into_flat(), ["v1", "v2"]);
+    }
+
+    #[tokio::test]
+    async fn drops_dont_hold_up_other_partitions() {
+        let tmp = tempdir().unwrap();
+        let map = PartitionMap::new(tmp.path().to_path_buf()).await.unwrap();
+
+        map.index(entries("k1", &["v1"])).await.unwrap();
+
+        // the drop has to wait for this reader
+        let reader = map.read_segment_map("tenant").await.unwrap();
+        let mut dropped = std::pin::pin!(map.drop_partition("tenant"));
+
+        assert!(
+            time::timeout(Duration::from_millis(50), &mut dropped)
+                .await
+                .is_err()
+        );
+
+        let mut other = entries("k2", &["v2"]);
+        let values = other.remove("tenant").unwrap();
+        other.insert("other".to_string(), values);
+
+        time::timeout(Duration::from_secs(5), map.index(other))
+            .await
+            .expect("other partitions shouldn't wait on the drop")
+            .unwrap();
+
+        drop(reader);
+
+        assert!(dropped.await.unwrap());
+        assert!(
+            map.search(query("k1"), None, false)
+                .await
+                .unwrap()
+                .is_empty()
+        );
+    }
+
+    #[tokio::test]
+    async fn readers_share_a_partition() {
+        let tmp = tempdir().unwrap();
+        let map = PartitionMap::new(tmp.path().to_path_buf()).await.unwrap();
+
+        map.index(entries("k1", &["v1"])).await.unwrap();
+
+        let mut other = entries("k2", &["v2"]);
+        other.insert("other".to_string(), other["tenant"].clone());
+        map.index(other).await.unwrap();
+
+        // held by another reader throughout
+        let reader = map.read_segment_map("tenant").await.unwrap();
+
+        let mut query = FxHashMap::default();
+        query.insert("tenant", vec!["k1", "k2"]);
+        query.insert("other", vec!["k2"]);
+
+        let found = time::timeout(Duration::from_secs(5), map.search(query, None, false))
+            .await
+            .expect("readers shouldn't wait on each other")
+            .unwrap();
+
+        assert_eq!(found, ["v2", "v1", "v2"]);
+
+        drop(reader);
+    }
+
+    #[tokio::test]
+    async fn partitions_are_listed_and_checked() {
+        let tmp = tempdir().unwrap();
+        let map = PartitionMap::new(tmp.path().to_path_buf()).await.unwrap();
+
+        map.index(entries("k1", &["v1", "v2"])).await.unwrap();
+        map.flush().await.unwrap();
+        map.index(entries("k2", &["v3"])).await.unwrap();
+
+        let mut other = FxHashMap::default();
+        other.insert("another", entries("k1", &["v1"]).remove("tenant").unwrap());
+        map.index(other).await.unwrap();
+
+        assert_eq!(map.partitions().await.unwrap(), ["another", "tenant"]);
+
+        let stats = map.stats("tenant").await.unwrap();
+        assert_eq!(stats.memory_segments, 1);
+        assert_eq!(stats.disk_segments.len(), 1);
+        assert!(stats.disk_segments[0].size > 0);
+
+        assert!(map.has_partition("tenant").await.unwrap());
+        assert!(!map.has_partition("missing").await.unwrap());
+
+        map.check_ready().await.unwrap();
+
+        // a partition that can't be loaded anymore is only noticed once it's needed
+        drop(map);
+        let map = PartitionMap::new(tmp.path().to_path_buf()).await.unwrap();
+
+        let directory = tmp
+            .path()
+            .join(PartitionMap::partition_directory_name("another").unwrap());
+        fs::write(
+            directory.join("manifest.json"),
+            r#"{"version": 99, "generation": 1, "incarnation": 1, "counter": 0, "checkpoint": 0, "segments": []}"#,
+        )
+        .await
+        .unwrap();
+
+        map.check_ready().await.unwrap();
+
+        assert!(matches!(
+            map.stats("another").await,
+            Err(PartitionError::SegmentCreationError {
+                source: segment::SegmentMapError::UnsupportedVersion { version: 99 }
+            })
+        ));
+        assert!(!map.has_partition("missing").await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn least_recently_used_partitions_are_flushed_and_closed() {
+        let tmp = tempdir().unwrap();
+        let map = PartitionMap::with_options(
+            tmp.path().to_path_buf(),
+            PartitionMapOptions {
+                cache_capacity: NonZeroUsize::new(2).unwrap(),
+                ..PartitionMapOptions::default()
+            },
+        )
+        .await
+        .unwrap();
+
+        let index = |partition: &str| {
+            let mut entries = FxHashMap::default();
+            entries.insert("key", vec![partition.to_string()]);
+
+            let mut map = FxHashMap::default();
+            map.insert(partition.to_string(), entries);
+
+            map
+        };
+
+        let search = |partition: &str| {
+            let mut query = FxHashMap::default();
+            query.insert(partition.to_string(), vec!["key"]);
+
+            query
+        };
+
+        map.index(index("a")).await.unwrap();
+        map.index(index("b")).await.unwrap();
+        assert_eq!(map.search(search("a"), None, false).await.unwrap(), ["a"]);
+
+        // "b" is the least recently used one by now
+        map.index(index("c")).await.unwrap();
+
+        assert_eq!(
+            map.cache_stats().await,
+            CacheStats {
+                hits: 1,
+                misses: 3,
+                evictions: 1,
+                open: 2,
+            }
+        );
+
+        let evicted = tmp
+            .path()
+            .join(PartitionMap::partition_directory_name("b").unwrap());
+        let manifest = fs::read_to_string(evicted.join("manifest.json"))
+            .await
+            .unwrap();
+        assert!(manifest.contains("seg-"));
+
+        assert_eq!(map.search(search("b"), None, false).await.unwrap(), ["b"]);
+        assert_eq!(map.cache_stats().await.evictions, 2);
+    }
+
+    #[tokio::test]
+    async fn interrupted_drops_are_finished_on_startup() {
+        let tmp = tempdir().unwrap();
+
+        let leftover = tmp.path().join(format!("{DROPPED_PREFIX}abc"));
+        fs::create_dir_all(leftover.join("seg-1")).await.unwrap();
+
+        PartitionMap::new(tmp.path().to_path_buf()).await.unwrap();
+
+        assert!(!fs::try_exists(&leftover).await.unwrap());
+    }
 }
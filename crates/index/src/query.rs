@@ -0,0 +1,222 @@
+use fxhash::FxHashSet;
+use serde::Deserialize;
+
+use crate::segment::{DiskResolutionError, TieredSegmentMap};
+
+/// Boolean query over the keys of a partition, matching the values indexed under them.
+///
+/// In JSON, `{"and": [{"term": "a"}, {"term": "b"}, {"not": {"term": "c"}}]}` matches the
+/// values indexed under both `a` and `b`, but not under `c`.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Query {
+    Term(String),
+    And(Vec<Query>),
+    Or(Vec<Query>),
+    Not(Box<Query>),
+}
+
+impl Query {
+    /// Whether the query matches a finite set of values. Anything else matches every value
+    /// but a finite set, so it can only narrow down a conjunction.
+    pub fn is_bounded(&self) -> bool {
+        match self {
+            Self::Term(_) => true,
+            Self::And(queries) => queries.iter().any(Self::is_bounded),
+            Self::Or(queries) => queries.iter().all(Self::is_bounded),
+            Self::Not(query) => !query.is_bounded(),
+        }
+    }
+
+    /// Evaluates a bounded query, keeping the values in the order they were first matched.
+    pub(crate) async fn evaluate(
+        &self,
+        map: &TieredSegmentMap,
+    ) -> Result<Vec<String>, DiskResolutionError> {
+        match self {
+            Self::Term(key) => {
+                let mut values = map.find(key, None).await?;
+                dedup(&mut values);
+
+                Ok(values)
+            }
+
+            Self::And(queries) => {
+                let (bounded, unbounded) = queries
+                    .iter()
+                    .partition::<Vec<_>, _>(|query| query.is_bounded());
+
+                let Some((first, bounded)) = bounded.split_first() else {
+                    unreachable!("bounded conjunctions have a bounded query");
+                };
+
+                let mut values = Box::pin(first.evaluate(map)).await?;
+
+                for query in bounded {
+                    if values.is_empty() {
+                        break;
+                    }
+
+                    let matched = Box::pin(query.evaluate(map)).await?;
+                    retain(&mut values, matched, true);
+                }
+
+                for query in unbounded {
+                    if values.is_empty() {
+                        break;
+                    }
+
+                    let excluded = Box::pin(query.excluded(map)).await?;
+                    retain(&mut values, excluded, false);
+                }
+
+                Ok(values)
+            }
+
+            Self::Or(queries) => {
+                let mut values = Vec::new();
+
+                for query in queries {
+                    values.extend(Box::pin(query.evaluate(map)).await?);
+                }
+
+                dedup(&mut values);
+
+                Ok(values)
+            }
+
+            // the negation of an unbounded query is what that query excludes
+            Self::Not(query) => Box::pin(query.excluded(map)).await,
+        }
+    }
+
+    async fn excluded(&self, map: &TieredSegmentMap) -> Result<Vec<String>, DiskResolutionError> {
+        match self {
+            Self::Not(query) => Box::pin(query.evaluate(map)).await,
+
+            // every query of an unbounded conjunction is unbounded
+            Self::And(queries) => {
+                let mut excluded = Vec::new();
+
+                for query in queries {
+                    excluded.extend(Box::pin(query.excluded(map)).await?);
+                }
+
+                dedup(&mut excluded);
+
+                Ok(excluded)
+            }
+
+            Self::Or(queries) => {
+                let (bounded, unbounded) = queries
+                    .iter()
+                    .partition::<Vec<_>, _>(|query| query.is_bounded());
+
+                let mut excluded = None;
+
+                for query in unbounded {
+                    let matched = Box::pin(query.excluded(map)).await?;
+
+                    match &mut excluded {
+                        None => excluded = Some(matched),
+                        Some(excluded) => retain(excluded, matched, true),
+                    }
+                }
+
+                let mut excluded = excluded.unwrap_or_default();
+
+                for query in bounded {
+                    if excluded.is_empty() {
+                        break;
+                    }
+
+                    let matched = Box::pin(query.evaluate(map)).await?;
+                    retain(&mut excluded, matched, false);
+                }
+
+                Ok(excluded)
+            }
+
+            Self::Term(_) => unreachable!("terms are bounded"),
+        }
+    }
+}
+
+fn dedup(values: &mut Vec<String>) {
+    let mut seen = FxHashSet::default();
+    values.retain(|value| seen.insert(value.clone()));
+}
+
+fn retain(values: &mut Vec<String>, other: Vec<String>, among: bool) {
+    let other = other.into_iter().collect::<FxHashSet<_>>();
+    values.retain(|value| other.contains(value) == among);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use fxhash::FxHashMap;
+    use tempfile::tempdir;
+
+    fn query(json: &str) -> Query {
+        serde_json::from_str(json).unwrap()
+    }
+
+    #[tokio::test]
+    async fn boolean_queries_combine_posting_lists() {
+        let tmp = tempdir().unwrap();
+        let mut map = TieredSegmentMap::new(tmp.path().to_path_buf())
+            .await
+            .unwrap();
+
+        let mut entries = FxHashMap::default();
+        entries.insert("a", vec!["1", "2", "3", "4"]);
+        entries.insert("b", vec!["2", "3", "4", "5"]);
+        entries.insert("c", vec!["3"]);
+        entries.insert("d", vec!["4", "6"]);
+        map.insert(entries).await.unwrap();
+
+        let cases = [
+            (r#"{"term": "a"}"#, vec!["1", "2", "3", "4"]),
+            (
+                r#"{"and": [{"term": "a"}, {"term": "b"}]}"#,
+                vec!["2", "3", "4"],
+            ),
+            (
+                r#"{"and": [{"term": "a"}, {"term": "b"}, {"not": {"term": "c"}}]}"#,
+                vec!["2", "4"],
+            ),
+            (
+                r#"{"or": [{"term": "c"}, {"term": "d"}]}"#,
+                vec!["3", "4", "6"],
+            ),
+            (
+                r#"{"and": [{"term": "b"}, {"not": {"or": [{"term": "c"}, {"term": "d"}]}}]}"#,
+                vec!["2", "5"],
+            ),
+            // not c and not d
+            (
+                r#"{"and": [{"term": "b"}, {"and": [{"not": {"term": "c"}}, {"not": {"term": "d"}}]}]}"#,
+                vec!["2", "5"],
+            ),
+            // d and (a or not d), so d and a
+            (
+                r#"{"and": [{"term": "d"}, {"or": [{"term": "a"}, {"not": {"term": "d"}}]}]}"#,
+                vec!["4"],
+            ),
+            (r#"{"not": {"not": {"term": "c"}}}"#, vec!["3"]),
+            (r#"{"and": [{"term": "a"}, {"term": "missing"}]}"#, vec![]),
+        ];
+
+        for (json, expected) in cases {
+            let query = query(json);
+
+            assert!(query.is_bounded(), "{json}");
+            assert_eq!(query.evaluate(&map).await.unwrap(), expected, "{json}");
+        }
+
+        assert!(!query(r#"{"not": {"term": "a"}}"#).is_bounded());
+        assert!(!query(r#"{"or": [{"term": "a"}, {"not": {"term": "b"}}]}"#).is_bounded());
+        assert!(!query(r#"{"and": []}"#).is_bounded());
+    }
+}
@@ -0,0 +1,79 @@
+use serde::Serialize;
+use std::collections::BTreeMap;
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct KeyValues {
+    pub values: Vec<String>,
+
+    /// Whether more values of the key may follow.
+    pub truncated: bool,
+}
+
+/// Values of a search, attributed to the partition and key they were found under.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct SearchResult {
+    pub partitions: BTreeMap<String, BTreeMap<String, KeyValues>>,
+
+    /// Values matched by boolean queries, which don't come from a single key.
+    pub queries: BTreeMap<String, Vec<String>>,
+
+    pub truncated: bool,
+}
+
+impl SearchResult {
+    pub fn len(&self) -> usize {
+        let keys = self
+            .partitions
+            .values()
+            .flat_map(BTreeMap::values)
+            .map(|key| key.values.len());
+
+        keys.chain(self.queries.values().map(Vec::len)).sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub(crate) fn push(
+        &mut self,
+        partition: &str,
+        key: &str,
+        values: Vec<String>,
+    ) -> &mut KeyValues {
+        let found = self
+            .partitions
+            .entry(partition.to_string())
+            .or_default()
+            .entry(key.to_string())
+            .or_default();
+
+        found.values.extend(values);
+
+        found
+    }
+
+    pub fn merge(&mut self, other: Self) {
+        for (partition, keys) in other.partitions {
+            for (key, found) in keys {
+                self.push(&partition, &key, found.values).truncated |= found.truncated;
+            }
+        }
+
+        for (partition, values) in other.queries {
+            self.queries.entry(partition).or_default().extend(values);
+        }
+
+        self.truncated |= other.truncated;
+    }
+
+    pub fn into_flat(self) -> Vec<String> {
+        let keys = self
+            .partitions
+            .into_values()
+            .flat_map(BTreeMap::into_values)
+            .flat_map(|key| key.values);
+
+        keys.chain(self.queries.into_values().flatten()).collect()
+    }
+}
@@ -0,0 +1,514 @@
+use std::{cmp::Reverse, collections::BinaryHeap, path::PathBuf, sync::Arc};
+use tokio::sync::OwnedRwLockReadGuard;
+
+use super::{
+    disk::{DiskResolutionError, DiskSegment, PairCursor, TableCursor, TableWriter},
+    memory::{self, Entry, Found, KEY_TOMBSTONE, Record},
+};
+
+/// Size-tiered compaction: a run of adjacent disk segments of similar size gets merged into
+/// one once it's long enough.
+#[derive(Debug, Clone)]
+pub struct CompactionPolicy {
+    /// Number of similarly sized segments that triggers a compaction.
+    pub min_segments: usize,
+
+    /// Upper bound on the number of segments merged at once.
+    pub max_segments: usize,
+
+    /// Segments belong to the same tier while the largest of them is at most this many
+    /// times bigger than the smallest one.
+    pub tier_ratio: u64,
+}
+
+impl Default for CompactionPolicy {
+    fn default() -> Self {
+        Self {
+            min_segments: 4,
+            max_segments: 32,
+            tier_ratio: 4,
+        }
+    }
+}
+
+impl CompactionPolicy {
+    /// Picks the longest run of adjacent segments within one tier, given their sizes in order.
+    pub fn select(&self, sizes: &[u64]) -> Option<std::ops::Range<usize>> {
+        let mut best: Option<std::ops::Range<usize>> = None;
+
+        for start in 0..sizes.len() {
+            let (mut smallest, mut largest) = (sizes[start].max(1), sizes[start].max(1));
+            let mut end = start + 1;
+
+            while end < sizes.len() && end - start < self.max_segments {
+                let size = sizes[end].max(1);
+
+                if size.max(largest) > size.min(smallest).saturating_mul(self.tier_ratio) {
+                    break;
+                }
+
+                smallest = smallest.min(size);
+                largest = largest.max(size);
+                end += 1;
+            }
+
+            if end - start >= self.min_segments.max(2)
+                && best.as_ref().is_none_or(|best| best.len() < end - start)
+            {
+                best = Some(start..end);
+            }
+        }
+
+        best
+    }
+}
+
+/// Disk segments being merged into a single new one.
+///
+/// Like [`super::FlushJob`], the job runs without access to the segment map; the inputs are
+/// immutable, so lookups keep using them until the compaction is completed.
+///
+/// The inputs are read front to back rather than loaded, so that only the item of every input
+/// at the head of the merge, the index mappings and the merged entries are held in memory.
+pub struct CompactionJob {
+    pub(super) id: usize,
+    pub(super) directory: PathBuf,
+    pub(super) inputs: Vec<Arc<DiskSegment>>,
+
+    /// Whether the inputs start with the oldest segment, leaving nothing for the tombstones to
+    /// shadow.
+    pub(super) purge: bool,
+
+    /// Keeps a drop of the partition waiting until the job is completed.
+    pub(super) _running: OwnedRwLockReadGuard<()>,
+}
+
+/// Sorted run of items read from disk one at a time.
+trait Run {
+    type Item;
+
+    async fn next(&mut self) -> Result<Option<Self::Item>, DiskResolutionError>;
+}
+
+impl Run for TableCursor {
+    type Item = Entry;
+
+    async fn next(&mut self) -> Result<Option<Entry>, DiskResolutionError> {
+        TableCursor::next(self).await
+    }
+}
+
+/// Entries of an input, with their keys and values mapped into the merged tables.
+struct Remapped<'mapping> {
+    entries: PairCursor,
+    keys: &'mapping [u32],
+    values: &'mapping [u32],
+}
+
+impl Run for Remapped<'_> {
+    type Item = (u32, Record);
+
+    async fn next(&mut self) -> Result<Option<(u32, Record)>, DiskResolutionError> {
+        let Some((key, value)) = self.entries.next().await? else {
+            return Ok(None);
+        };
+
+        let record = Record::from_raw(value);
+
+        if let Record::Value(value) | Record::Tombstone(value) = record
+            && value as usize >= self.values.len()
+        {
+            return Err(corrupted_entries());
+        }
+
+        let key = *self.keys.get(key as usize).ok_or_else(corrupted_entries)?;
+
+        Ok(Some((key, record.map(|value| self.values[value as usize]))))
+    }
+}
+
+/// Merges sorted runs, yielding every item with its run and sort key; equal keys come out in
+/// run order.
+struct KWayMerge<R: Run, K, F> {
+    runs: Vec<R>,
+    heads: Vec<Option<R::Item>>,
+    heap: BinaryHeap<Reverse<(K, usize)>>,
+    key: F,
+}
+
+impl<R: Run, K: Ord, F: Fn(&R::Item) -> Result<K, DiskResolutionError>> KWayMerge<R, K, F> {
+    async fn new(runs: Vec<R>, key: F) -> Result<Self, DiskResolutionError> {
+        let mut merge = Self {
+            heads: runs.iter().map(|_| None).collect(),
+            runs,
+            heap: BinaryHeap::new(),
+            key,
+        };
+
+        for run in 0..merge.runs.len() {
+            merge.advance(run).await?;
+        }
+
+        Ok(merge)
+    }
+
+    async fn advance(&mut self, run: usize) -> Result<(), DiskResolutionError> {
+        if let Some(item) = self.runs[run].next().await? {
+            self.heap.push(Reverse(((self.key)(&item)?, run)));
+            self.heads[run] = Some(item);
+        }
+
+        Ok(())
+    }
+
+    async fn next(&mut self) -> Result<Option<(usize, K, R::Item)>, DiskResolutionError> {
+        let Some(Reverse((key, run))) = self.heap.pop() else {
+            return Ok(None);
+        };
+
+        let item = self.heads[run]
+            .take()
+            .expect("every run in the heap has a head");
+
+        self.advance(run).await?;
+
+        Ok(Some((run, key, item)))
+    }
+}
+
+const KEYS: &str = "keys.data.bin";
+const VALUES: &str = "values.data.bin";
+
+fn corrupted_entries() -> DiskResolutionError {
+    DiskResolutionError::Corrupted {
+        file: "entries.bin".to_string(),
+    }
+}
+
+fn uncompressed(entry: &Entry, file: &str) -> Result<String, DiskResolutionError> {
+    entry
+        .clone()
+        .try_into_uncompressed()
+        .ok_or_else(|| DiskResolutionError::Corrupted {
+            file: file.to_string(),
+        })
+}
+
+/// Merges sorted tables, returning the new index of every item of every input table within
+/// the deduplicated merge, along with the number of distinct items.
+async fn map_tables<R: Run<Item = Entry>>(
+    tables: Vec<R>,
+    file: &str,
+) -> Result<(Vec<Vec<u32>>, usize), DiskResolutionError> {
+    let mut mapping = tables.iter().map(|_| Vec::new()).collect::<Vec<_>>();
+
+    let mut merge = KWayMerge::new(tables, |entry| uncompressed(entry, file)).await?;
+    let mut distinct = 0;
+    let mut last = None;
+
+    while let Some((run, key, _)) = merge.next().await? {
+        if last.as_ref() != Some(&key) {
+            distinct += 1;
+            last = Some(key);
+        }
+
+        mapping[run].push(distinct as u32 - 1);
+    }
+
+    Ok((mapping, distinct))
+}
+
+/// Merges sorted tables again like [`map_tables`], writing the distinct items still
+/// referenced.
+async fn write_table<R: Run<Item = Entry>>(
+    tables: Vec<R>,
+    file: &str,
+    referenced: &[bool],
+    writer: &mut TableWriter,
+    mut written: impl FnMut(&str),
+) -> Result<(), DiskResolutionError> {
+    let mut merge = KWayMerge::new(tables, |entry| uncompressed(entry, file)).await?;
+    let mut distinct = 0;
+    let mut last = None;
+
+    while let Some((_, key, entry)) = merge.next().await? {
+        if last.as_ref() == Some(&key) {
+            continue;
+        }
+
+        if referenced[distinct] {
+            writer.push(&entry).await?;
+            written(&key);
+        }
+
+        distinct += 1;
+        last = Some(key);
+    }
+
+    Ok(())
+}
+
+/// Resolves the records of a key, oldest input first, into its merged entries.
+fn resolve(key: u32, records: &[(usize, Record)], purge: bool, entries: &mut Vec<(u32, u32)>) {
+    let mut found = Found::<u32>::default();
+
+    for records in records.chunk_by(|(a, _), (b, _)| a == b) {
+        let mut newer = Found::default();
+
+        for &(_, record) in records {
+            newer.push(record, |value| value);
+        }
+
+        found.apply(newer);
+    }
+
+    if purge {
+        found.purge();
+    }
+
+    let deleted = found.deleted.then_some(KEY_TOMBSTONE);
+    let tombstones = found
+        .tombstones
+        .into_iter()
+        .map(|value| Record::Tombstone(value).into_raw());
+
+    let mut run = deleted
+        .into_iter()
+        .chain(tombstones)
+        .chain(found.values)
+        .map(|value| (key, value))
+        .collect::<Vec<_>>();
+
+    // mapped into the merged tables in order, so sorting the indices sorts the values
+    run.sort_unstable_by_key(|&(key, record)| memory::run_order(key, record));
+    run.dedup();
+
+    entries.extend(run);
+}
+
+/// Finds the keys and values no entry refers to anymore, remapping the entries to the tables
+/// without them.
+fn retain_referenced(
+    keys: usize,
+    values: usize,
+    entries: &mut [(u32, u32)],
+) -> Result<(Vec<bool>, Vec<bool>), DiskResolutionError> {
+    let (mut referenced_keys, mut referenced_values) = (vec![false; keys], vec![false; values]);
+
+    for &(key, value) in entries.iter() {
+        *referenced_keys
+            .get_mut(key as usize)
+            .ok_or_else(corrupted_entries)? = true;
+
+        if let Record::Value(value) | Record::Tombstone(value) = Record::from_raw(value) {
+            *referenced_values
+                .get_mut(value as usize)
+                .ok_or_else(corrupted_entries)? = true;
+        }
+    }
+
+    fn mapping(referenced: &[bool]) -> Vec<u32> {
+        let mut retained = 0;
+
+        referenced
+            .iter()
+            .map(|&referenced| {
+                let index = retained;
+                retained += referenced as u32;
+
+                index
+            })
+            .collect()
+    }
+
+    let (keys_mapping, values_mapping) = (mapping(&referenced_keys), mapping(&referenced_values));
+
+    for (key, value) in entries.iter_mut() {
+        *key = keys_mapping[*key as usize];
+        *value = Record::from_raw(*value)
+            .map(|value| values_mapping[value as usize])
+            .into_raw();
+    }
+
+    Ok((referenced_keys, referenced_values))
+}
+
+impl CompactionJob {
+    async fn cursors(&self, prefix: &str) -> Result<Vec<TableCursor>, DiskResolutionError> {
+        let mut cursors = Vec::with_capacity(self.inputs.len());
+
+        for input in &self.inputs {
+            cursors.push(input.table_cursor(prefix).await?);
+        }
+
+        Ok(cursors)
+    }
+
+    pub async fn run(&self) -> Result<DiskSegment, DiskResolutionError> {
+        let (keys_mapping, keys) = map_tables(self.cursors("keys").await?, KEYS).await?;
+        let (values_mapping, values) = map_tables(self.cursors("values").await?, VALUES).await?;
+
+        let mut runs = Vec::with_capacity(self.inputs.len());
+
+        for ((input, keys), values) in self.inputs.iter().zip(&keys_mapping).zip(&values_mapping) {
+            runs.push(Remapped {
+                entries: input.entries_cursor().await?,
+                keys,
+                values,
+            });
+        }
+
+        let mut merge = KWayMerge::new(runs, |&(key, _)| Ok(key)).await?;
+
+        let mut entries = Vec::new();
+        let mut records = Vec::new();
+        let mut current = None;
+
+        // inputs are ordered oldest first, and so are the runs of every key
+        while let Some((run, key, (_, record))) = merge.next().await? {
+            if let Some(current) = current.filter(|&current| current != key) {
+                resolve(current, &records, self.purge, &mut entries);
+                records.clear();
+            }
+
+            current = Some(key);
+            records.push((run, record));
+        }
+
+        if let Some(current) = current {
+            resolve(current, &records, self.purge, &mut entries);
+        }
+
+        let (referenced_keys, referenced_values) = retain_referenced(keys, values, &mut entries)?;
+
+        tracing::debug!(
+            "merged {:?} disk segments into {:?} keys, {:?} values and {:?} entries",
+            self.inputs.len(),
+            referenced_keys
+                .iter()
+                .filter(|&&referenced| referenced)
+                .count(),
+            referenced_values
+                .iter()
+                .filter(|&&referenced| referenced)
+                .count(),
+            entries.len(),
+        );
+
+        let mut bloom = memory::bloom(
+            referenced_keys
+                .iter()
+                .filter(|&&referenced| referenced)
+                .count(),
+        );
+
+        super::write_segment(&self.directory, self.id, async |segment| {
+            let mut writer = segment.table_writer("keys").await?;
+            write_table(
+                self.cursors("keys").await?,
+                KEYS,
+                &referenced_keys,
+                &mut writer,
+                |key| bloom.set(key),
+            )
+            .await?;
+            writer.finish().await?;
+
+            let mut writer = segment.table_writer("values").await?;
+            write_table(
+                self.cursors("values").await?,
+                VALUES,
+                &referenced_values,
+                &mut writer,
+                |_| {},
+            )
+            .await?;
+            writer.finish().await?;
+
+            segment
+                .finish_write(&bloom, &entries, &memory::reverse(&entries))
+                .await?;
+
+            Ok(())
+        })
+        .await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn select_prefers_longest_run_within_tier() {
+        let policy = CompactionPolicy {
+            min_segments: 3,
+            max_segments: 8,
+            tier_ratio: 2,
+        };
+
+        assert_eq!(policy.select(&[]), None);
+        assert_eq!(policy.select(&[10, 10]), None);
+        assert_eq!(policy.select(&[1000, 10, 12, 15]), Some(1..4));
+        assert_eq!(policy.select(&[10, 12, 1000, 10, 11, 12, 13]), Some(3..7));
+        assert_eq!(policy.select(&[10, 100, 10, 100, 10]), None);
+    }
+
+    #[test]
+    fn select_caps_run_length() {
+        let policy = CompactionPolicy {
+            min_segments: 2,
+            max_segments: 3,
+            tier_ratio: 2,
+        };
+
+        assert_eq!(policy.select(&[5; 10]), Some(0..3));
+    }
+
+    impl<T> Run for std::vec::IntoIter<T> {
+        type Item = T;
+
+        async fn next(&mut self) -> Result<Option<T>, DiskResolutionError> {
+            Ok(Iterator::next(self))
+        }
+    }
+
+    #[tokio::test]
+    async fn map_tables_deduplicates_and_maps() {
+        let tables = vec![
+            vec![Entry::new("a"), Entry::new("c")].into_iter(),
+            vec![Entry::new("b"), Entry::new("c"), Entry::new("d")].into_iter(),
+        ];
+
+        let (mapping, distinct) = map_tables(tables, KEYS).await.unwrap();
+
+        assert_eq!(distinct, 4);
+        assert_eq!(mapping, [vec![0, 2], vec![1, 2, 3]]);
+
+        // damaged items fail the compaction rather than the task running it
+        let tables = vec![vec![Entry::Compressed(vec![0xff; 8])].into_iter()];
+
+        assert!(matches!(
+            map_tables(tables, KEYS).await,
+            Err(DiskResolutionError::Corrupted { file }) if file == KEYS
+        ));
+    }
+
+    #[test]
+    fn retain_referenced_drops_purged_items() {
+        let mut entries = vec![(0, Record::Tombstone(1).into_raw()), (2, 2)];
+
+        let (keys, values) = retain_referenced(3, 3, &mut entries).unwrap();
+
+        assert_eq!(keys, [true, false, true]);
+        assert_eq!(values, [false, true, true]);
+        assert_eq!(entries, [(0, Record::Tombstone(0).into_raw()), (1, 1)]);
+
+        let mut entries = vec![(0, 3)];
+
+        assert!(matches!(
+            retain_referenced(3, 3, &mut entries),
+            Err(DiskResolutionError::Corrupted { .. })
+        ));
+    }
+}
@@ -2,94 +2,330 @@ use std::{
     backtrace::Backtrace,
     cmp::Ordering,
     io::{ErrorKind, SeekFrom},
-    path::PathBuf,
+    ops::Range,
+    path::{Path, PathBuf},
 };
 use bitflags::bitflags;
 use bloomfilter::Bloom;
+use fxhash::FxHashMap;
 use snafu::Snafu;
 use tokio::{
     fs::{self, File},
-    io::{self, AsyncReadExt, AsyncSeekExt, AsyncWriteExt, BufWriter},
+    io::{self, AsyncBufReadExt, AsyncReadExt, AsyncSeekExt, AsyncWriteExt, BufReader, BufWriter},
+    sync::Mutex,
 };
 use tracing::Instrument;
+use zerocopy::IntoBytes;
 
-use super::memory::{CachedSegment, Entry};
+use super::{
+    header::{self, FileKind, Header},
+    memory::{self, ALL_VALUES, CachedSegment, Entry, Found, KEY_TOMBSTONE, Record, TOMBSTONE},
+};
+
+#[cfg(feature = "mmap")]
+use super::mapped::MappedSegment;
+
+const CHECKSUMS_FILE_NAME: &str = "checksums.bin";
+
+/// Files every segment consists of.
+const SEGMENT_FILES: [&str; 6] = [
+    "keys.data.bin",
+    "keys.lookup.bin",
+    "values.data.bin",
+    "values.lookup.bin",
+    "bloom.bin",
+    "entries.bin",
+];
+
+/// Reverse entries table, which segments written before it was introduced go without.
+const REVERSE_FILE_NAME: &str = "reverse.bin";
+
+/// Segment files listed in the checksums.
+fn recorded_files(checksums: &FxHashMap<String, Checksum>) -> impl Iterator<Item = &'static str> {
+    SEGMENT_FILES.into_iter().chain(
+        checksums
+            .contains_key(REVERSE_FILE_NAME)
+            .then_some(REVERSE_FILE_NAME),
+    )
+}
+
+/// Length and adler32 of a segment file, as recorded in `checksums.bin` once the segment is
+/// written.
+///
+/// Past its header, the checksums file is laid out as
+/// `([name length: u16][name][length: u64][adler32: u32])*` followed by the adler32 of the
+/// records. Segments written before checksums were
+/// introduced have no such file and are read unverified.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Checksum {
+    length: u64,
+    adler32: u32,
+}
+
+impl Checksum {
+    fn of(buffer: &[u8]) -> Self {
+        Self {
+            length: buffer.len() as u64,
+            adler32: adler2::adler32_slice(buffer),
+        }
+    }
+
+    async fn of_file(path: &Path) -> Result<Self, io::Error> {
+        let mut file = File::open(path).await?;
+        let mut buffer = vec![0u8; 64 * 1024];
+
+        let mut adler32 = adler2::Adler32::new();
+        let mut length = 0;
+
+        loop {
+            let read = file.read(&mut buffer).await?;
+
+            if read == 0 {
+                break;
+            }
+
+            adler32.write_slice(&buffer[..read]);
+            length += read as u64;
+        }
+
+        Ok(Self {
+            length,
+            adler32: adler32.checksum(),
+        })
+    }
+}
 
 pub struct DiskSegment {
     pub directory: PathBuf,
+
+    /// Loaded once the segment files are written, or when opening a written segment.
+    resident: Option<Resident>,
 }
 
-impl DiskSegment {
-    async fn write_lookup_table(
-        &self,
-        prefix: &str,
-        offsets: impl IntoIterator<Item = u64>,
-    ) -> Result<(), io::Error> {
-        let file = fs::OpenOptions::new()
-            .create_new(true)
-            .write(true)
-            .open(self.directory.join(format!("{prefix}.lookup.bin")))
+/// Parts of a written segment kept in memory for as long as the segment is open.
+struct Resident {
+    bloom: Bloom<str>,
+    reader: Reader,
+
+    /// Reverse table built from the entries of segments written without one.
+    reverse: Option<Vec<(u32, u32)>>,
+
+    /// Whether the values of every key are ordered, which segments written before
+    /// [`header::ORDERED_VERSION`] don't guarantee.
+    ordered: bool,
+}
+
+impl Resident {
+    async fn key(&self, index: u32) -> Result<String, DiskResolutionError> {
+        match &self.reader {
+            #[cfg(feature = "mmap")]
+            Reader::Mapped(mapped) => mapped.key(index),
+            Reader::Files(files) => files.lock().await.keys.get_value_under(index).await,
+        }
+    }
+
+    async fn key_index(&self, key: &str) -> Result<Option<u32>, DiskResolutionError> {
+        match &self.reader {
+            #[cfg(feature = "mmap")]
+            Reader::Mapped(mapped) => mapped.key_index(key),
+            Reader::Files(files) => files.lock().await.keys.map_to_index(key).await,
+        }
+    }
+
+    async fn value_index(&self, value: &str) -> Result<Option<u32>, DiskResolutionError> {
+        match &self.reader {
+            #[cfg(feature = "mmap")]
+            Reader::Mapped(mapped) => mapped.value_index(value),
+            Reader::Files(files) => files.lock().await.entries.values.map_to_index(value).await,
+        }
+    }
+
+    async fn count(&self, key_index: u32) -> Result<usize, DiskResolutionError> {
+        match &self.reader {
+            #[cfg(feature = "mmap")]
+            Reader::Mapped(mapped) => Ok(mapped.count(key_index)),
+            Reader::Files(files) => Ok(files.lock().await.entries.count(key_index).await? as usize),
+        }
+    }
+
+    /// Key indices of the reverse table under `record`.
+    async fn reverse_run(&self, record: u32) -> Result<Vec<u32>, DiskResolutionError> {
+        if let Some(reverse) = &self.reverse {
+            return Ok(memory::reverse_run(reverse, record)
+                .iter()
+                .map(|&(_, key)| key)
+                .collect());
+        }
+
+        match &self.reader {
+            #[cfg(feature = "mmap")]
+            Reader::Mapped(mapped) => mapped.reverse_run(record),
+            Reader::Files(files) => files.lock().await.reverse()?.run(record).await,
+        }
+    }
+
+    async fn has_reverse(&self, pair: (u32, u32)) -> Result<bool, DiskResolutionError> {
+        if let Some(reverse) = &self.reverse {
+            return Ok(reverse.binary_search(&pair).is_ok());
+        }
+
+        match &self.reader {
+            #[cfg(feature = "mmap")]
+            Reader::Mapped(mapped) => mapped.has_reverse(pair),
+            Reader::Files(files) => files.lock().await.reverse()?.contains(pair).await,
+        }
+    }
+}
+
+enum Reader {
+    #[cfg(feature = "mmap")]
+    Mapped(MappedSegment),
+
+    /// Used whenever the files can't be mapped; lookups on the segment take turns on the
+    /// file handles.
+    Files(Box<Mutex<FileReader>>),
+}
+
+/// Writes a table item by item, along with the lookup table of its offsets.
+pub struct TableWriter {
+    data: BufWriter<File>,
+    lookup: BufWriter<File>,
+
+    /// Offset the next item goes at.
+    position: u64,
+}
+
+impl TableWriter {
+    async fn create(directory: &Path, prefix: &str) -> Result<Self, io::Error> {
+        async fn create(path: PathBuf) -> Result<File, io::Error> {
+            fs::OpenOptions::new()
+                .create_new(true)
+                .write(true)
+                .open(path)
+                .await
+        }
+
+        let mut data = BufWriter::new(create(directory.join(format!("{prefix}.data.bin"))).await?);
+        data.write_all(Header::new(FileKind::Data).as_bytes())
             .await?;
 
-        let mut file = BufWriter::new(file);
+        let mut lookup =
+            BufWriter::new(create(directory.join(format!("{prefix}.lookup.bin"))).await?);
+        lookup
+            .write_all(Header::new(FileKind::Lookup).as_bytes())
+            .await?;
+
+        Ok(Self {
+            data,
+            lookup,
+            position: header::SIZE as u64,
+        })
+    }
 
-        for item in offsets {
-            file.write_u64(item).await?;
+    pub async fn push(&mut self, item: &Entry) -> Result<(), io::Error> {
+        bitflags! {
+            struct EntryFlag: u32 {
+                const COMPRESSED = 0b1 << (u32::BITS - 1);
+            }
         }
 
-        file.flush().await?;
+        let size = match item {
+            Entry::Compressed(buffer) => EntryFlag::COMPRESSED.bits() | buffer.len() as u32,
+            Entry::Uncompressed(buffer) => buffer.len() as u32,
+        };
+
+        self.lookup.write_u64(self.position).await?;
+
+        self.data.write_u32(size).await?;
+        self.data.write_all(item.as_ref()).await?;
+
+        self.position += (size_of::<u32>() + item.as_ref().len()) as u64;
 
         Ok(())
     }
 
-    async fn write_full_table<'entry>(
-        &self,
-        prefix: &str,
-        table: impl IntoIterator<Item = &'entry Entry, IntoIter: ExactSizeIterator>,
-    ) -> Result<(), io::Error> {
-        let file = fs::OpenOptions::new()
-            .create_new(true)
-            .write(true)
-            .open(self.directory.join(format!("{prefix}.data.bin")))
-            .await?;
+    pub async fn finish(mut self) -> Result<(), io::Error> {
+        for file in [&mut self.data, &mut self.lookup] {
+            file.flush().await?;
+            file.get_mut().sync_all().await?;
+        }
 
-        let mut file = BufWriter::new(file);
+        Ok(())
+    }
+}
 
-        let table = table.into_iter();
+/// Reads the items of a table front to back, so that segments can be merged without loading
+/// them.
+pub struct TableCursor {
+    data: BufReader<File>,
+}
 
-        let mut offsets = Vec::with_capacity(table.len());
+impl TableCursor {
+    pub async fn next(&mut self) -> Result<Option<Entry>, DiskResolutionError> {
+        if self.data.fill_buf().await?.is_empty() {
+            return Ok(None);
+        }
 
-        for item in table {
-            let position = file.stream_position().await?;
-
-            match item {
-                Entry::Compressed(buffer) => {
-                    bitflags! {
-                        struct EntryFlag: u32 {
-                            const COMPRESSED = 0b1 << (u32::BITS - 1);
-                        }
-                    }
+        let length_and_flag = read_or_invalid(self.data.read_u32().await)? as usize;
+        let compressed = (length_and_flag & (0b1 << 31)) != 0;
+        let length = length_and_flag & !(0b1 << 31);
 
-                    let size = EntryFlag::COMPRESSED.bits() | buffer.len() as u32;
+        let mut buffer = vec![0u8; length];
+        read_or_invalid(self.data.read_exact(&mut buffer).await)?;
 
-                    file.write_u32(size).await?;
-                    file.write_all(buffer).await?;
-                }
+        Ok(Some(if compressed {
+            Entry::Compressed(buffer)
+        } else {
+            Entry::Uncompressed(String::try_from(buffer).map_err(|err| err.utf8_error())?)
+        }))
+    }
+}
 
-                Entry::Uncompressed(buffer) => {
-                    let buffer = buffer.as_bytes();
+/// Reads the pairs of a table, such as the entries, front to back.
+pub struct PairCursor {
+    pairs: BufReader<File>,
+}
 
-                    file.write_u32(buffer.len() as u32).await?;
-                    file.write_all(buffer).await?;
-                }
-            }
+impl PairCursor {
+    pub async fn next(&mut self) -> Result<Option<(u32, u32)>, DiskResolutionError> {
+        if self.pairs.fill_buf().await?.is_empty() {
+            return Ok(None);
+        }
+
+        let first = read_or_invalid(self.pairs.read_u32().await)?;
+        let second = read_or_invalid(self.pairs.read_u32().await)?;
+
+        Ok(Some((first, second)))
+    }
+}
+
+/// Reads past the end of a table as a truncated item rather than an I/O error.
+fn read_or_invalid<T>(result: Result<T, io::Error>) -> Result<T, DiskResolutionError> {
+    result.map_err(|err| match err.kind() {
+        ErrorKind::UnexpectedEof => DiskResolutionError::DataInvalidSize,
+        _ => err.into(),
+    })
+}
+
+impl DiskSegment {
+    async fn write_full_table<'entry>(
+        &self,
+        prefix: &str,
+        table: impl IntoIterator<Item = &'entry Entry>,
+    ) -> Result<(), io::Error> {
+        let mut writer = self.table_writer(prefix).await?;
 
-            offsets.push(position);
+        for item in table {
+            writer.push(item).await?;
         }
 
-        file.flush().await?;
+        writer.finish().await
+    }
 
-        self.write_lookup_table(prefix, offsets).await
+    /// Starts writing the keys or values table, which has to be finished before
+    /// [`Self::finish_write`].
+    pub async fn table_writer(&self, prefix: &str) -> Result<TableWriter, io::Error> {
+        TableWriter::create(&self.directory, prefix).await
     }
 
     async fn write_bloom_filter(&self, filter: &Bloom<str>) -> Result<(), io::Error> {
@@ -99,46 +335,288 @@ impl DiskSegment {
             .open(self.directory.join("bloom.bin"))
             .await?;
 
-        file.write_all(filter.as_slice()).await
+        file.write_all(Header::new(FileKind::Bloom).as_bytes())
+            .await?;
+        file.write_all(filter.as_slice()).await?;
+        file.sync_all().await
     }
 
-    async fn write_entries(
+    /// Writes a table of pairs, such as the entries.
+    async fn write_pairs(
         &self,
-        entries: impl IntoIterator<Item = (u32, u32)>,
+        name: &str,
+        kind: FileKind,
+        pairs: impl IntoIterator<Item = (u32, u32)>,
     ) -> Result<(), io::Error> {
         let file = fs::OpenOptions::new()
             .create_new(true)
             .write(true)
-            .open(self.directory.join("entries.bin"))
+            .open(self.directory.join(name))
             .await?;
 
         let mut file = BufWriter::new(file);
+        file.write_all(Header::new(kind).as_bytes()).await?;
 
-        for (key, value) in entries {
-            file.write_u32(key).await?;
-            file.write_u32(value).await?;
+        for (first, second) in pairs {
+            file.write_u32(first).await?;
+            file.write_u32(second).await?;
         }
 
         file.flush().await?;
+        file.get_mut().sync_all().await?;
 
         Ok(())
     }
 
-    pub async fn flush_memory_segment(&self, segment: &CachedSegment) -> Result<(), io::Error> {
-        self.write_full_table("keys", segment.keys.iter()).await?;
-        self.write_full_table("values", segment.values.iter())
+    /// Writes the segment files, then opens them for lookups.
+    pub async fn flush_memory_segment(&mut self, segment: &CachedSegment) -> Result<(), io::Error> {
+        self.write_full_table("keys", &segment.keys).await?;
+        self.write_full_table("values", &segment.values).await?;
+
+        self.finish_write(&segment.bloom, &segment.entries, &segment.reverse)
+            .await
+    }
+
+    /// Writes the files following the keys and values tables, then opens the segment for
+    /// lookups.
+    pub async fn finish_write(
+        &mut self,
+        bloom: &Bloom<str>,
+        entries: &[(u32, u32)],
+        reverse: &[(u32, u32)],
+    ) -> Result<(), io::Error> {
+        self.write_bloom_filter(bloom).await?;
+
+        self.write_pairs("entries.bin", FileKind::Entries, entries.iter().copied())
+            .await?;
+        self.write_pairs(
+            REVERSE_FILE_NAME,
+            FileKind::Reverse,
+            reverse.iter().copied(),
+        )
+        .await?;
+
+        self.write_checksums().await?;
+
+        // the files were just written, so failing to open them isn't a matter of their contents
+        let bloom = Bloom::from_bytes(bloom.as_slice().to_vec())
+            .map_err(|err| io::Error::new(ErrorKind::InvalidData, err))?;
+
+        self.load(bloom).await.map_err(|err| match err {
+            DiskResolutionError::IoError { source, .. } => source,
+            err => io::Error::new(ErrorKind::InvalidData, err),
+        })
+    }
+
+    async fn write_checksums(&self) -> Result<(), io::Error> {
+        let mut buffer = Vec::new();
+
+        for name in SEGMENT_FILES.into_iter().chain([REVERSE_FILE_NAME]) {
+            let checksum = Checksum::of_file(&self.directory.join(name)).await?;
+
+            buffer.extend_from_slice(&(name.len() as u16).to_be_bytes());
+            buffer.extend_from_slice(name.as_bytes());
+            buffer.extend_from_slice(&checksum.length.to_be_bytes());
+            buffer.extend_from_slice(&checksum.adler32.to_be_bytes());
+        }
+
+        buffer.extend_from_slice(&adler2::adler32_slice(&buffer).to_be_bytes());
+
+        let mut file = fs::OpenOptions::new()
+            .create_new(true)
+            .write(true)
+            .open(self.directory.join(CHECKSUMS_FILE_NAME))
+            .await?;
+
+        file.write_all(Header::new(FileKind::Checksums).as_bytes())
             .await?;
+        file.write_all(&buffer).await?;
+        file.sync_all().await
+    }
+
+    /// Reads the recorded checksums, or `None` for segments written without them.
+    async fn read_checksums(
+        &self,
+    ) -> Result<Option<FxHashMap<String, Checksum>>, DiskResolutionError> {
+        let buffer = match fs::read(self.directory.join(CHECKSUMS_FILE_NAME)).await {
+            Ok(buffer) => buffer,
+            Err(err) if err.kind() == ErrorKind::NotFound => return Ok(None),
+            Err(err) => return Err(err.into()),
+        };
+
+        let corrupted = || DiskResolutionError::Corrupted {
+            file: CHECKSUMS_FILE_NAME.to_string(),
+        };
+
+        let buffer = header::strip(&buffer, CHECKSUMS_FILE_NAME, FileKind::Checksums)?;
+
+        let Some((mut buffer, trailer)) = buffer.split_last_chunk::<4>() else {
+            return Err(corrupted());
+        };
+
+        if adler2::adler32_slice(buffer) != u32::from_be_bytes(*trailer) {
+            return Err(corrupted());
+        }
+
+        let mut checksums = FxHashMap::default();
+
+        while !buffer.is_empty() {
+            let (length, rest) = buffer.split_first_chunk::<2>().ok_or_else(corrupted)?;
+            let (name, rest) = rest
+                .split_at_checked(u16::from_be_bytes(*length) as usize)
+                .ok_or_else(corrupted)?;
+
+            let (length, rest) = rest.split_first_chunk::<8>().ok_or_else(corrupted)?;
+            let (adler32, rest) = rest.split_first_chunk::<4>().ok_or_else(corrupted)?;
+
+            checksums.insert(
+                String::from_utf8(name.to_vec()).map_err(|_| corrupted())?,
+                Checksum {
+                    length: u64::from_be_bytes(*length),
+                    adler32: u32::from_be_bytes(*adler32),
+                },
+            );
+
+            buffer = rest;
+        }
+
+        Ok(Some(checksums))
+    }
+
+    /// Reads a segment file in full, checking it against its recorded checksum.
+    async fn read_checked(&self, name: &str) -> Result<Vec<u8>, DiskResolutionError> {
+        let buffer = fs::read(self.directory.join(name)).await?;
+
+        if let Some(checksums) = self.read_checksums().await?
+            && checksums.get(name) != Some(&Checksum::of(&buffer))
+        {
+            return Err(DiskResolutionError::Corrupted {
+                file: name.to_string(),
+            });
+        }
+
+        Ok(buffer)
+    }
+
+    /// Prepares an empty segment in `directory` for [`Self::flush_memory_segment`] to write
+    /// into.
+    pub fn create(directory: PathBuf) -> Self {
+        Self {
+            directory,
+            resident: None,
+        }
+    }
 
-        self.write_bloom_filter(&segment.bloom).await?;
+    /// Opens a written segment, checking every file against its recorded checksum and loading
+    /// its bloom filter, so lookups never read from a damaged table.
+    pub async fn open(directory: PathBuf) -> Result<Self, DiskResolutionError> {
+        let mut segment = Self::create(directory);
+
+        match segment.read_checksums().await? {
+            Some(checksums) => segment.check_files(&checksums).await?,
+            None => tracing::warn!(
+                "segment {:?} has no checksums, reading it unverified",
+                segment.directory
+            ),
+        }
+
+        let buffer = fs::read(segment.directory.join("bloom.bin")).await?;
+        let buffer = header::strip(&buffer, "bloom.bin", FileKind::Bloom)?;
+
+        let bloom = Bloom::<str>::from_bytes(buffer.to_vec())
+            .map_err(|_| DiskResolutionError::BloomLoadError)?;
+
+        tracing::trace!("loaded bloom of size: {:?}", bloom.len());
+
+        segment.load(bloom).await?;
+
+        Ok(segment)
+    }
+
+    /// Opens the segment files for lookups, mapping them into memory where possible and
+    /// falling back to reading them otherwise.
+    async fn load(&mut self, bloom: Bloom<str>) -> Result<(), DiskResolutionError> {
+        let reverse = if fs::try_exists(self.directory.join(REVERSE_FILE_NAME)).await? {
+            None
+        } else {
+            tracing::debug!(
+                "segment {:?} has no reverse table, building it",
+                self.directory
+            );
+
+            Some(memory::reverse(&self.read_all_entries().await?))
+        };
+
+        let mut entries = File::open(self.directory.join("entries.bin")).await?;
+        let version = header::read_version(&mut entries, "entries.bin", FileKind::Entries).await?;
+
+        let reader = self.open_reader().await?;
+
+        self.resident = Some(Resident {
+            bloom,
+            reader,
+            reverse,
+            ordered: version >= header::ORDERED_VERSION,
+        });
+
+        Ok(())
+    }
+
+    async fn open_reader(&self) -> Result<Reader, DiskResolutionError> {
+        #[cfg(feature = "mmap")]
+        match MappedSegment::open(&self.directory).await {
+            Ok(mapped) => return Ok(Reader::Mapped(mapped)),
+            Err(DiskResolutionError::IoError { source, .. }) => {
+                tracing::warn!(
+                    "can't map segment {:?}, reading it instead: {source:?}",
+                    self.directory
+                );
+            }
+            Err(err) => return Err(err),
+        }
+
+        let files = FileReader::open(&self.directory).await?;
+
+        Ok(Reader::Files(Box::new(Mutex::new(files))))
+    }
+
+    /// Recomputes the checksum of every segment file, failing on the first mismatch.
+    pub async fn verify(&self) -> Result<(), DiskResolutionError> {
+        let Some(checksums) = self.read_checksums().await? else {
+            tracing::warn!(
+                "segment {:?} has no checksums, can't verify it",
+                self.directory
+            );
+
+            return Ok(());
+        };
 
-        self.write_entries(segment.entries.iter().cloned()).await?;
+        self.check_files(&checksums).await?;
+
+        tracing::trace!("verified segment {:?}", self.directory);
 
         Ok(())
     }
 
-    #[inline]
-    pub async fn open_or_create_segment(directory: PathBuf) -> Result<Self, io::Error> {
-        Ok(Self { directory })
+    async fn check_files(
+        &self,
+        checksums: &FxHashMap<String, Checksum>,
+    ) -> Result<(), DiskResolutionError> {
+        for name in recorded_files(checksums) {
+            let actual = match Checksum::of_file(&self.directory.join(name)).await {
+                Ok(checksum) => Some(checksum),
+                Err(err) if err.kind() == ErrorKind::NotFound => None,
+                Err(err) => return Err(err.into()),
+            };
+
+            if actual.is_none() || actual.as_ref() != checksums.get(name) {
+                return Err(DiskResolutionError::Corrupted {
+                    file: name.to_string(),
+                });
+            }
+        }
+
+        Ok(())
     }
 }
 
@@ -153,6 +631,12 @@ pub enum DiskResolutionError {
     #[snafu(display("can't load bloom"))]
     BloomLoadError,
 
+    #[snafu(display("segment file {file:?} is corrupted"))]
+    Corrupted { file: String },
+
+    #[snafu(display("segment file {file:?} has unsupported format version {version}"))]
+    UnsupportedVersion { file: String, version: u16 },
+
     #[snafu(transparent)]
     Utf8Error { source: std::str::Utf8Error },
 
@@ -172,8 +656,11 @@ fn convert(index: u32, factor: usize) -> u64 {
 }
 
 struct LinearMappedResolver {
+    pub name: &'static str,
     pub data: File,
     pub lookup: File,
+    /// Offset the lookup table starts at, past the header.
+    pub base: u64,
     pub length: u64,
 }
 
@@ -183,7 +670,11 @@ async fn read_offset(lookup: &mut File, offset: u64) -> Result<u64, DiskResoluti
     Ok(lookup.read_u64().await?)
 }
 
-async fn read_entry_within(data: &mut File, offset: u64) -> Result<String, DiskResolutionError> {
+async fn read_entry_within(
+    name: &str,
+    data: &mut File,
+    offset: u64,
+) -> Result<String, DiskResolutionError> {
     data.seek(SeekFrom::Start(offset)).await?;
 
     let length_and_flag = data.read_u32().await? as usize;
@@ -204,38 +695,60 @@ async fn read_entry_within(data: &mut File, offset: u64) -> Result<String, DiskR
         buffer.as_ref().len()
     );
 
-    Ok(buffer.into_uncompressed())
+    buffer
+        .try_into_uncompressed()
+        .ok_or_else(|| DiskResolutionError::Corrupted {
+            file: name.to_string(),
+        })
 }
 
 impl LinearMappedResolver {
-    pub async fn map_to_index(&mut self, key: &str) -> Result<Option<u32>, DiskResolutionError> {
-        if !self.length.is_multiple_of(size_of::<u64>() as u64) {
+    async fn open(
+        directory: &Path,
+        name: &'static str,
+        lookup_name: &'static str,
+    ) -> Result<Self, DiskResolutionError> {
+        let mut data = File::open(directory.join(name)).await?;
+        header::read(&mut data, name, FileKind::Data).await?;
+
+        let mut lookup = File::open(directory.join(lookup_name)).await?;
+        let base = header::read(&mut lookup, lookup_name, FileKind::Lookup).await?;
+        let length = lookup.metadata().await?.len() - base;
+
+        if !length.is_multiple_of(size_of::<u64>() as u64) {
             return Err(DiskResolutionError::LookupInvalidSize);
         }
 
-        let size = length(self.length, size_of::<u64>());
-        let mut current = size / 2;
+        Ok(Self {
+            name,
+            data,
+            lookup,
+            base,
+            length,
+        })
+    }
 
-        for step in 2..self.length.ilog2() + 1 {
-            let offset = read_offset(&mut self.lookup, convert(current, size_of::<u64>())).await?;
+    pub async fn map_to_index(&mut self, key: &str) -> Result<Option<u32>, DiskResolutionError> {
+        let (mut low, mut high) = (0, length(self.length, size_of::<u64>()));
 
-            let entry = read_entry_within(&mut self.data, offset).await?;
+        while low < high {
+            let middle = low + (high - low) / 2;
 
-            match key.cmp(&entry) {
-                Ordering::Less => {
-                    let value = (size / 2u32.pow(step)).max(1);
+            let offset = read_offset(
+                &mut self.lookup,
+                self.base + convert(middle, size_of::<u64>()),
+            )
+            .await?;
 
-                    current -= value;
-                }
-                Ordering::Greater => {
-                    let value = (size / 2u32.pow(step)).max(1);
+            let entry = read_entry_within(self.name, &mut self.data, offset).await?;
 
-                    current += value
-                }
+            match key.cmp(&entry) {
+                Ordering::Less => high = middle,
+                Ordering::Greater => low = middle + 1,
                 Ordering::Equal => {
-                    tracing::trace!("found item at {current:?}");
+                    tracing::trace!("found item at {middle:?}");
 
-                    return Ok(Some(current));
+                    return Ok(Some(middle));
                 }
             }
         }
@@ -243,14 +756,31 @@ impl LinearMappedResolver {
         Ok(None)
     }
 
-    pub async fn get_value_under(&mut self, index: u32) -> Result<String, DiskResolutionError> {
-        if !self.length.is_multiple_of(size_of::<u64>() as u64) {
-            return Err(DiskResolutionError::LookupInvalidSize);
+    /// Index of the first item not ordered before `key`.
+    pub async fn lower_bound(&mut self, key: &str) -> Result<u32, DiskResolutionError> {
+        let (mut low, mut high) = (0, length(self.length, size_of::<u64>()));
+
+        while low < high {
+            let middle = low + (high - low) / 2;
+
+            if *self.get_value_under(middle).await? < *key {
+                low = middle + 1;
+            } else {
+                high = middle;
+            }
         }
 
-        let offset = read_offset(&mut self.lookup, convert(index, size_of::<u64>())).await?;
+        Ok(low)
+    }
+
+    pub async fn get_value_under(&mut self, index: u32) -> Result<String, DiskResolutionError> {
+        let offset = read_offset(
+            &mut self.lookup,
+            self.base + convert(index, size_of::<u64>()),
+        )
+        .await?;
 
-        let entry = read_entry_within(&mut self.data, offset).await?;
+        let entry = read_entry_within(self.name, &mut self.data, offset).await?;
 
         Ok(entry)
     }
@@ -259,14 +789,39 @@ impl LinearMappedResolver {
 struct EntriesAndLinearMappedValueResolver {
     pub values: LinearMappedResolver,
     pub entries: File,
+    /// Offset the entries start at, past the header.
+    pub base: u64,
     pub length: u64,
 }
 
 impl EntriesAndLinearMappedValueResolver {
-    async fn read_sequential(&mut self, key: u32) -> Result<Vec<String>, DiskResolutionError> {
-        let mut items = Vec::new();
+    async fn open(directory: &Path) -> Result<Self, DiskResolutionError> {
+        let mut entries = File::open(directory.join("entries.bin")).await?;
+        let base = header::read(&mut entries, "entries.bin", FileKind::Entries).await?;
+        let length = entries.metadata().await?.len() - base;
 
-        loop {
+        if !length.is_multiple_of(size_of::<[u32; 2]>() as u64) {
+            return Err(DiskResolutionError::LookupInvalidSize);
+        }
+
+        Ok(Self {
+            values: LinearMappedResolver::open(directory, "values.data.bin", "values.lookup.bin")
+                .await?,
+            entries,
+            base,
+            length,
+        })
+    }
+
+    /// Reads up to `limit` entries of the key from the current position.
+    async fn read_sequential(
+        &mut self,
+        key: u32,
+        limit: usize,
+    ) -> Result<Found, DiskResolutionError> {
+        let mut found = Found::default();
+
+        for _ in 0..limit {
             let index = match self.entries.read_u32().await {
                 Err(err) => {
                     if err.kind() == ErrorKind::UnexpectedEof {
@@ -284,137 +839,562 @@ impl EntriesAndLinearMappedValueResolver {
                 break;
             }
 
-            let value_index = self.entries.read_u32().await?;
-
-            items.push(self.values.get_value_under(value_index).await?);
+            match Record::from_raw(self.entries.read_u32().await?) {
+                Record::Value(value) => {
+                    found.values.push(self.values.get_value_under(value).await?);
+                }
+                Record::Tombstone(value) => {
+                    found
+                        .tombstones
+                        .push(self.values.get_value_under(value).await?);
+                }
+                Record::KeyTombstone => found.deleted = true,
+            }
         }
 
-        Ok(items)
+        Ok(found)
     }
 
-    pub async fn resolve_entries_with_key(
-        mut self,
-        key: u32,
-    ) -> Result<Vec<String>, DiskResolutionError> {
-        if !self.length.is_multiple_of(size_of::<[u32; 2]>() as u64) {
-            return Err(DiskResolutionError::LookupInvalidSize);
-        }
-
-        let size = length(self.length, size_of::<[u32; 2]>());
-
-        self.entries
-            .seek(SeekFrom::Start(convert(size, size_of::<[u32; 2]>())))
-            .await?;
+    /// Index of the first entry `below` doesn't hold for.
+    async fn partition_point(
+        &mut self,
+        below: impl Fn((u32, u32)) -> bool,
+    ) -> Result<u32, DiskResolutionError> {
+        let (mut low, mut high) = (0, length(self.length, size_of::<[u32; 2]>()));
 
-        let mut offset = size / 2;
+        while low < high {
+            let middle = low + (high - low) / 2;
 
-        for step in 2..self.length.ilog2() + 1 {
             self.entries
-                .seek(SeekFrom::Start(convert(offset, size_of::<[u32; 2]>())))
+                .seek(SeekFrom::Start(
+                    self.base + convert(middle, size_of::<[u32; 2]>()),
+                ))
                 .await?;
 
-            let index = self.entries.read_u32().await?;
-            match key.cmp(&index) {
-                Ordering::Less => {
-                    offset -= (size / 2u32.pow(step)).max(1);
-                    continue;
-                }
-                Ordering::Greater => {
-                    offset += (size / 2u32.pow(step)).max(1);
-                    continue;
-                }
-                _ => (),
-            }
-
-            while offset > 0 {
-                self.entries
-                    .seek(SeekFrom::Start(convert(offset - 1, size_of::<[u32; 2]>())))
-                    .await?;
-
-                let index = self.entries.read_u32().await?;
+            let entry = (
+                self.entries.read_u32().await?,
+                self.entries.read_u32().await?,
+            );
 
-                if index != key {
-                    break;
-                } else {
-                    offset -= 1;
-                }
+            if below(entry) {
+                low = middle + 1;
+            } else {
+                high = middle;
             }
+        }
 
-            self.entries
-                .seek(SeekFrom::Start(convert(offset, size_of::<[u32; 2]>())))
-                .await?;
+        Ok(low)
+    }
 
-            return self.read_sequential(key).await;
-        }
+    /// Number of values under the key, counted off the entries.
+    pub async fn count(&mut self, key: u32) -> Result<u32, DiskResolutionError> {
+        // tombstones lead the run of a key, followed by its values
+        let values = self
+            .partition_point(|(index, record)| index < key || (index == key && record >= TOMBSTONE))
+            .await?;
+        let end = self.partition_point(|(index, _)| index <= key).await?;
 
-        Ok(vec![])
+        Ok(end - values)
     }
-}
 
-impl DiskSegment {
-    pub async fn find(&self, key: &str) -> Result<Vec<String>, DiskResolutionError> {
-        let contains = {
-            let mut bloom = File::open(self.directory.join("bloom.bin")).await?;
-            let mut buffer = Vec::with_capacity(4096);
-            bloom.read_to_end(&mut buffer).await?;
-            let bloom = Bloom::<str>::from_bytes(buffer)
-                .map_err(|_| DiskResolutionError::BloomLoadError)?;
+    /// Resolves the tombstones of the key and the values within `window`.
+    pub async fn resolve_entries_with_key(
+        &mut self,
+        key: u32,
+        window: Range<usize>,
+    ) -> Result<Found, DiskResolutionError> {
+        let size = length(self.length, size_of::<[u32; 2]>());
 
-            tracing::trace!("loaded bloom of size: {:?}", bloom.len());
+        // lower bound, so the run of the key is read from its very beginning
+        let start = self.partition_point(|(index, _)| index < key).await?;
 
-            bloom.check(key)
-        };
+        // tombstones lead the run of a key, followed by its values
+        let values = self
+            .partition_point(|(index, record)| index < key || (index == key && record >= TOMBSTONE))
+            .await?;
 
-        tracing::trace!("bloom existence: {contains:?}");
+        self.entries
+            .seek(SeekFrom::Start(
+                self.base + convert(start, size_of::<[u32; 2]>()),
+            ))
+            .await?;
 
-        if !contains {
-            return Ok(vec![]);
-        }
+        let mut found = self.read_sequential(key, (values - start) as usize).await?;
 
-        let resolved_key = {
-            let keys_lookup_file = File::open(self.directory.join("keys.lookup.bin")).await?;
+        let first = values
+            .saturating_add(u32::try_from(window.start).unwrap_or(u32::MAX))
+            .min(size);
 
-            LinearMappedResolver {
-                data: File::open(self.directory.join("keys.data.bin")).await?,
-                length: keys_lookup_file.metadata().await?.len(),
-                lookup: keys_lookup_file,
+        self.entries
+            .seek(SeekFrom::Start(
+                self.base + convert(first, size_of::<[u32; 2]>()),
+            ))
+            .await?;
+
+        found.values = self.read_sequential(key, window.len()).await?.values;
+
+        Ok(found)
+    }
+}
+
+/// Table of pairs ordered by both items, such as the reverse entries.
+struct PairResolver {
+    name: &'static str,
+    file: File,
+    /// Offset the pairs start at, past the header.
+    base: u64,
+    length: u32,
+}
+
+impl PairResolver {
+    /// Opens the table, unless the segment was written without it.
+    async fn open(
+        directory: &Path,
+        name: &'static str,
+        kind: FileKind,
+    ) -> Result<Option<Self>, DiskResolutionError> {
+        let mut file = match File::open(directory.join(name)).await {
+            Ok(file) => file,
+            Err(err) if err.kind() == ErrorKind::NotFound => return Ok(None),
+            Err(err) => return Err(err.into()),
+        };
+
+        let base = header::read(&mut file, name, kind).await?;
+        let size = file.metadata().await?.len() - base;
+
+        if !size.is_multiple_of(size_of::<[u32; 2]>() as u64) {
+            return Err(DiskResolutionError::LookupInvalidSize);
+        }
+
+        Ok(Some(Self {
+            name,
+            file,
+            base,
+            length: length(size, size_of::<[u32; 2]>()),
+        }))
+    }
+
+    async fn read(&mut self, index: u32) -> Result<(u32, u32), DiskResolutionError> {
+        self.file
+            .seek(SeekFrom::Start(
+                self.base + convert(index, size_of::<[u32; 2]>()),
+            ))
+            .await?;
+
+        Ok((self.file.read_u32().await?, self.file.read_u32().await?))
+    }
+
+    /// Index of the first pair not ordered before `pair`.
+    async fn lower_bound(&mut self, pair: (u32, u32)) -> Result<u32, DiskResolutionError> {
+        let (mut low, mut high) = (0, self.length);
+
+        while low < high {
+            let middle = low + (high - low) / 2;
+
+            if self.read(middle).await? < pair {
+                low = middle + 1;
+            } else {
+                high = middle;
             }
+        }
+
+        Ok(low)
+    }
+
+    /// Second items of the pairs starting with `first`.
+    async fn run(&mut self, first: u32) -> Result<Vec<u32>, DiskResolutionError> {
+        let mut run = Vec::new();
+
+        for index in self.lower_bound((first, 0)).await?..self.length {
+            let (item, second) = self.read(index).await?;
+
+            if item != first {
+                break;
+            }
+
+            run.push(second);
+        }
+
+        tracing::trace!("read run of {:?} pairs from {:?}", run.len(), self.name);
+
+        Ok(run)
+    }
+
+    async fn contains(&mut self, pair: (u32, u32)) -> Result<bool, DiskResolutionError> {
+        let index = self.lower_bound(pair).await?;
+
+        Ok(index < self.length && self.read(index).await? == pair)
+    }
+}
+
+/// Reader going through the segment files with a seek and a read per binary search probe.
+pub(super) struct FileReader {
+    keys: LinearMappedResolver,
+    entries: EntriesAndLinearMappedValueResolver,
+    reverse: Option<PairResolver>,
+}
+
+impl FileReader {
+    pub(super) async fn open(directory: &Path) -> Result<Self, DiskResolutionError> {
+        Ok(Self {
+            keys: LinearMappedResolver::open(directory, "keys.data.bin", "keys.lookup.bin").await?,
+            entries: EntriesAndLinearMappedValueResolver::open(directory).await?,
+            reverse: PairResolver::open(directory, REVERSE_FILE_NAME, FileKind::Reverse).await?,
+        })
+    }
+
+    fn reverse(&mut self) -> Result<&mut PairResolver, DiskResolutionError> {
+        self.reverse
+            .as_mut()
+            .ok_or_else(|| DiskResolutionError::Corrupted {
+                file: REVERSE_FILE_NAME.to_string(),
+            })
+    }
+
+    /// Looks the key up, only resolving the values within `window` of the ones it holds.
+    pub(super) async fn find_window(
+        &mut self,
+        key: &str,
+        window: Range<usize>,
+    ) -> Result<Found, DiskResolutionError> {
+        let resolved_key = self
+            .keys
             .map_to_index(key)
             .instrument(tracing::trace_span!("disk::map_to_index",))
-            .await?
-        };
+            .await?;
 
         tracing::trace!("resolved key index: {resolved_key:?}");
 
         let Some(key_index) = resolved_key else {
-            return Ok(vec![]);
+            return Ok(Found::default());
         };
 
-        let values = {
-            let entries_file = File::open(self.directory.join("entries.bin")).await?;
-
-            let values_lookup_file = File::open(self.directory.join("values.lookup.bin")).await?;
-            let values_lookup_length = values_lookup_file.metadata().await?.len();
-            EntriesAndLinearMappedValueResolver {
-                values: LinearMappedResolver {
-                    lookup: values_lookup_file,
-                    length: values_lookup_length,
-                    data: File::open(self.directory.join("values.data.bin")).await?,
-                },
-                length: entries_file.metadata().await?.len(),
-                entries: entries_file,
-            }
-            .resolve_entries_with_key(key_index)
+        self.entries
+            .resolve_entries_with_key(key_index, window)
             .instrument(tracing::trace_span!(
                 "disk::resolve_entries",
                 index = key_index,
             ))
-            .await?
+            .await
+    }
+
+    pub(super) async fn key_range(
+        &mut self,
+        start: &str,
+        end: Option<&str>,
+    ) -> Result<Range<u32>, DiskResolutionError> {
+        let start = self.keys.lower_bound(start).await?;
+        let end = match end {
+            Some(end) => self.keys.lower_bound(end).await?,
+            None => length(self.keys.length, size_of::<u64>()),
+        };
+
+        Ok(start..end.max(start))
+    }
+}
+
+impl DiskSegment {
+    #[cfg(test)]
+    async fn read_full_table(&self, prefix: &str) -> Result<Vec<Entry>, DiskResolutionError> {
+        let name = format!("{prefix}.data.bin");
+        let buffer = self.read_checked(&name).await?;
+        let mut buffer = header::strip(&buffer, &name, FileKind::Data)?;
+
+        let mut table = Vec::new();
+
+        while !buffer.is_empty() {
+            let Some((length_and_flag, rest)) = buffer.split_first_chunk::<4>() else {
+                return Err(DiskResolutionError::DataInvalidSize);
+            };
+
+            let length_and_flag = u32::from_be_bytes(*length_and_flag) as usize;
+            let compressed = (length_and_flag & (0b1 << 31)) != 0;
+            let length = length_and_flag & !(0b1 << 31);
+
+            let Some((item, rest)) = rest.split_at_checked(length) else {
+                return Err(DiskResolutionError::DataInvalidSize);
+            };
+
+            table.push(if compressed {
+                Entry::Compressed(item.to_vec())
+            } else {
+                Entry::Uncompressed(str::from_utf8(item)?.to_string())
+            });
+
+            buffer = rest;
+        }
+
+        tracing::trace!("loaded {prefix:?} table of {:?} entries", table.len());
+
+        Ok(table)
+    }
+
+    async fn read_all_entries(&self) -> Result<Vec<(u32, u32)>, DiskResolutionError> {
+        let buffer = self.read_checked("entries.bin").await?;
+        let buffer = header::strip(&buffer, "entries.bin", FileKind::Entries)?;
+
+        let (entries, rest) = buffer.as_chunks::<{ size_of::<[u32; 2]>() }>();
+
+        if !rest.is_empty() {
+            return Err(DiskResolutionError::LookupInvalidSize);
+        }
+
+        Ok(entries
+            .iter()
+            .map(|entry| {
+                let (key, value) = entry.split_at(size_of::<u32>());
+
+                (
+                    u32::from_be_bytes(key.try_into().unwrap()),
+                    u32::from_be_bytes(value.try_into().unwrap()),
+                )
+            })
+            .collect())
+    }
+
+    /// Opens the keys or values table for reading front to back.
+    pub async fn table_cursor(&self, prefix: &str) -> Result<TableCursor, DiskResolutionError> {
+        let name = format!("{prefix}.data.bin");
+
+        let mut data = File::open(self.directory.join(&name)).await?;
+        header::read(&mut data, &name, FileKind::Data).await?;
+
+        Ok(TableCursor {
+            data: BufReader::new(data),
+        })
+    }
+
+    /// Opens the entries for reading front to back.
+    pub async fn entries_cursor(&self) -> Result<PairCursor, DiskResolutionError> {
+        let mut pairs = File::open(self.directory.join("entries.bin")).await?;
+        header::read(&mut pairs, "entries.bin", FileKind::Entries).await?;
+
+        Ok(PairCursor {
+            pairs: BufReader::new(pairs),
+        })
+    }
+
+    /// Reads the keys, values and entries tables in full, for tests to look into a segment.
+    #[cfg(test)]
+    pub async fn load_tables(
+        &self,
+    ) -> Result<(Vec<Entry>, Vec<Entry>, Vec<(u32, u32)>), DiskResolutionError> {
+        Ok((
+            self.read_full_table("keys").await?,
+            self.read_full_table("values").await?,
+            self.read_all_entries().await?,
+        ))
+    }
+
+    /// Total size of the segment files, in bytes.
+    pub async fn size(&self) -> Result<u64, io::Error> {
+        let mut size = 0;
+        let mut iter = fs::read_dir(&self.directory).await?;
+
+        while let Some(entry) = iter.next_entry().await? {
+            size += entry.metadata().await?.len();
+        }
+
+        Ok(size)
+    }
+
+    /// Whether the values of every key are stored in order, so that they can be read a window
+    /// at a time while merging segments.
+    pub fn is_ordered(&self) -> bool {
+        self.resident
+            .as_ref()
+            .is_none_or(|resident| resident.ordered)
+    }
+
+    pub async fn find(&self, key: &str) -> Result<Found, DiskResolutionError> {
+        self.find_window(key, ALL_VALUES).await
+    }
+
+    /// Looks the key up, only resolving the values within `window` of the ones it holds.
+    pub async fn find_window(
+        &self,
+        key: &str,
+        window: Range<usize>,
+    ) -> Result<Found, DiskResolutionError> {
+        // nothing was written into the segment yet
+        let Some(resident) = &self.resident else {
+            return Ok(Found::default());
+        };
+
+        let contains = resident.bloom.check(key);
+
+        tracing::trace!("bloom existence: {contains:?}");
+
+        if !contains {
+            return Ok(Found::default());
+        }
+
+        let found = match &resident.reader {
+            #[cfg(feature = "mmap")]
+            Reader::Mapped(mapped) => mapped.find_window(key, window)?,
+            Reader::Files(files) => files.lock().await.find_window(key, window).await?,
+        };
+
+        tracing::trace!("resolved values: {:?}", found.values.len());
+
+        Ok(found)
+    }
+
+    /// Indices of the keys within `[start, end)`, or past `start` without an end.
+    pub async fn key_range(
+        &self,
+        start: &str,
+        end: Option<&str>,
+    ) -> Result<Range<u32>, DiskResolutionError> {
+        let Some(resident) = &self.resident else {
+            return Ok(0..0);
+        };
+
+        match &resident.reader {
+            #[cfg(feature = "mmap")]
+            Reader::Mapped(mapped) => mapped.key_range(start, end),
+            Reader::Files(files) => files.lock().await.key_range(start, end).await,
+        }
+    }
+
+    /// Key under an index of [`Self::key_range`].
+    pub async fn key(&self, index: u32) -> Result<String, DiskResolutionError> {
+        // nothing was written, so every index is out of range
+        let Some(resident) = &self.resident else {
+            return Err(DiskResolutionError::LookupInvalidSize);
+        };
+
+        resident.key(index).await
+    }
+
+    /// Everything recorded under the key of an index of [`Self::key_range`].
+    pub async fn resolve_key(&self, index: u32) -> Result<Found, DiskResolutionError> {
+        // nothing was written, so every index is out of range
+        let Some(resident) = &self.resident else {
+            return Err(DiskResolutionError::LookupInvalidSize);
+        };
+
+        match &resident.reader {
+            #[cfg(feature = "mmap")]
+            Reader::Mapped(mapped) => mapped.resolve(index, ALL_VALUES),
+            Reader::Files(files) => {
+                files
+                    .lock()
+                    .await
+                    .entries
+                    .resolve_entries_with_key(index, ALL_VALUES)
+                    .await
+            }
+        }
+    }
+
+    /// Keys holding `value` as values, and keys deleting it as tombstones.
+    pub async fn find_keys_for_value(&self, value: &str) -> Result<Found, DiskResolutionError> {
+        let Some(resident) = &self.resident else {
+            return Ok(Found::default());
+        };
+
+        let Some(value_index) = resident.value_index(value).await? else {
+            return Ok(Found::default());
+        };
+
+        tracing::trace!("resolved value index: {value_index:?}");
+
+        let mut found = Found::default();
+
+        for key in resident.reverse_run(value_index).await? {
+            found.values.push(resident.key(key).await?);
+        }
+
+        let tombstone = Record::Tombstone(value_index).into_raw();
+
+        for key in resident.reverse_run(tombstone).await? {
+            found.tombstones.push(resident.key(key).await?);
+        }
+
+        tracing::trace!("resolved keys: {:?}", found.values.len());
+
+        Ok(found)
+    }
+
+    /// Whether the segment holds the value under the key, deletes it, or records nothing
+    /// about it.
+    pub async fn holds(&self, key: &str, value: &str) -> Result<Option<bool>, DiskResolutionError> {
+        let Some(resident) = &self.resident else {
+            return Ok(None);
+        };
+
+        if !resident.bloom.check(key) {
+            return Ok(None);
+        }
+
+        let Some(key_index) = resident.key_index(key).await? else {
+            return Ok(None);
+        };
+
+        if let Some(value_index) = resident.value_index(value).await? {
+            // values are newer than the tombstones of their own segment
+            if resident.has_reverse((value_index, key_index)).await? {
+                return Ok(Some(true));
+            }
+
+            let tombstone = Record::Tombstone(value_index).into_raw();
+
+            if resident.has_reverse((tombstone, key_index)).await? {
+                return Ok(Some(false));
+            }
+        }
+
+        let deleted = resident.has_reverse((KEY_TOMBSTONE, key_index)).await?;
+
+        Ok(deleted.then_some(false))
+    }
+
+    /// Whether the segment records anything under the key.
+    pub async fn contains(&self, key: &str) -> Result<bool, DiskResolutionError> {
+        let Some(resident) = &self.resident else {
+            return Ok(false);
+        };
+
+        if !resident.bloom.check(key) {
+            return Ok(false);
+        }
+
+        Ok(resident.key_index(key).await?.is_some())
+    }
+
+    /// Number of values the segment holds under the key, counted off the entries.
+    pub async fn count(&self, key: &str) -> Result<usize, DiskResolutionError> {
+        let Some(resident) = &self.resident else {
+            return Ok(0);
         };
 
-        tracing::trace!("resolved values: {:?}", values.len());
+        if !resident.bloom.check(key) {
+            return Ok(0);
+        }
 
-        Ok(values)
+        let Some(key_index) = resident.key_index(key).await? else {
+            return Ok(0);
+        };
+
+        resident.count(key_index).await
+    }
+
+    /// Whether the segment deletes the whole of `key` from older segments.
+    pub async fn is_key_deleted(&self, key: &str) -> Result<bool, DiskResolutionError> {
+        let Some(resident) = &self.resident else {
+            return Ok(false);
+        };
+
+        if !resident.bloom.check(key) {
+            return Ok(false);
+        }
+
+        let Some(key_index) = resident.key_index(key).await? else {
+            return Ok(false);
+        };
+
+        resident.has_reverse((KEY_TOMBSTONE, key_index)).await
     }
 }
 
@@ -437,13 +1417,11 @@ mod tests {
         let mut map = FxHashMap::default();
         map.insert("key", vec!["value", "value2"]);
 
-        let mem_seg = CachedSegment::new(map);
-        let disk_seg = DiskSegment::open_or_create_segment(dir.clone())
-            .await
-            .unwrap();
+        let mem_seg = CachedSegment::new(&map);
+        let mut disk_seg = DiskSegment::create(dir.clone());
         disk_seg.flush_memory_segment(&mem_seg).await.unwrap();
 
-        let resolved = disk_seg.find("key").await.unwrap();
+        let resolved = disk_seg.find("key").await.unwrap().values;
         let set: HashSet<_> = resolved.iter().cloned().collect();
         assert_eq!(
             set,
@@ -462,14 +1440,12 @@ mod tests {
         map.insert("a", vec!["1"]);
         map.insert("b", vec!["2"]);
 
-        let mem_seg = CachedSegment::new(map);
-        let disk_seg = DiskSegment::open_or_create_segment(dir.clone())
-            .await
-            .unwrap();
+        let mem_seg = CachedSegment::new(&map);
+        let mut disk_seg = DiskSegment::create(dir.clone());
         disk_seg.flush_memory_segment(&mem_seg).await.unwrap();
 
-        assert_eq!(disk_seg.find("a").await.unwrap(), ["1"]);
-        assert_eq!(disk_seg.find("b").await.unwrap(), ["2"]);
+        assert_eq!(disk_seg.find("a").await.unwrap().values, ["1"]);
+        assert_eq!(disk_seg.find("b").await.unwrap().values, ["2"]);
     }
 
     #[tokio::test]
@@ -483,13 +1459,11 @@ mod tests {
         let mut map = FxHashMap::default();
         map.insert("a", vec!["1", "2"]);
 
-        let mem_seg = CachedSegment::new(map);
-        let disk_seg = DiskSegment::open_or_create_segment(dir.clone())
-            .await
-            .unwrap();
+        let mem_seg = CachedSegment::new(&map);
+        let mut disk_seg = DiskSegment::create(dir.clone());
         disk_seg.flush_memory_segment(&mem_seg).await.unwrap();
 
-        let resolved = disk_seg.find("a").await.unwrap();
+        let resolved = disk_seg.find("a").await.unwrap().values;
         assert_eq!(resolved, ["1", "2"]);
     }
 
@@ -504,14 +1478,12 @@ mod tests {
         map.insert("a", vec!["1"]);
         map.insert("b", vec!["1"]);
 
-        let mem_seg = CachedSegment::new(map);
-        let disk_seg = DiskSegment::open_or_create_segment(dir.clone())
-            .await
-            .unwrap();
+        let mem_seg = CachedSegment::new(&map);
+        let mut disk_seg = DiskSegment::create(dir.clone());
         disk_seg.flush_memory_segment(&mem_seg).await.unwrap();
 
-        assert_eq!(disk_seg.find("a").await.unwrap(), ["1"]);
-        assert_eq!(disk_seg.find("b").await.unwrap(), ["1"]);
+        assert_eq!(disk_seg.find("a").await.unwrap().values, ["1"]);
+        assert_eq!(disk_seg.find("b").await.unwrap().values, ["1"]);
     }
 
     #[tokio::test]
@@ -525,13 +1497,11 @@ mod tests {
         let mut map = FxHashMap::default();
         map.insert("a", vec!["1"]);
 
-        let mem_seg = CachedSegment::new(map);
-        let disk_seg = DiskSegment::open_or_create_segment(dir.clone())
-            .await
-            .unwrap();
+        let mem_seg = CachedSegment::new(&map);
+        let mut disk_seg = DiskSegment::create(dir.clone());
         disk_seg.flush_memory_segment(&mem_seg).await.unwrap();
 
-        assert_eq!(disk_seg.find("a").await.unwrap(), ["1"]);
+        assert_eq!(disk_seg.find("a").await.unwrap().values, ["1"]);
     }
 
     #[tokio::test]
@@ -545,13 +1515,305 @@ mod tests {
         map.insert("x", vec!["1"]);
         map.insert("y", vec!["2"]);
 
-        let mem_seg = CachedSegment::new(map);
-        let disk_seg = DiskSegment::open_or_create_segment(dir.clone())
+        let mem_seg = CachedSegment::new(&map);
+        let mut disk_seg = DiskSegment::create(dir.clone());
+
+        disk_seg.flush_memory_segment(&mem_seg).await.unwrap();
+
+        assert_eq!(disk_seg.find("z").await.unwrap().values.len(), 0);
+    }
+
+    #[tokio::test]
+    async fn find_absent_keys_around_existing_ones() {
+        let tmp = tempdir().unwrap();
+        let dir = tmp.path().join("seg");
+
+        fs::create_dir_all(&dir).await.unwrap();
+
+        let keys = (0..64).map(|i| format!("k{i:03}")).collect::<Vec<_>>();
+
+        let mut map = FxHashMap::default();
+        for key in &keys {
+            map.insert(key.as_str(), vec![key.as_str()]);
+        }
+
+        let mem_seg = CachedSegment::new(&map);
+        let mut disk_seg = DiskSegment::create(dir.clone());
+
+        disk_seg.flush_memory_segment(&mem_seg).await.unwrap();
+
+        for key in &keys {
+            assert_eq!(disk_seg.find(key).await.unwrap().values, [key.as_str()]);
+        }
+
+        for absent in ["a", "k", "k0000", "k0305", "z"] {
+            assert!(disk_seg.find(absent).await.unwrap().values.is_empty());
+        }
+    }
+
+    #[tokio::test]
+    async fn opened_segments_keep_their_bloom() {
+        let tmp = tempdir().unwrap();
+        let dir = tmp.path().join("seg");
+
+        fs::create_dir_all(&dir).await.unwrap();
+
+        let mut map = FxHashMap::default();
+        map.insert("a", vec!["1"]);
+
+        let mut disk_seg = DiskSegment::create(dir.clone());
+        disk_seg
+            .flush_memory_segment(&CachedSegment::new(&map))
             .await
             .unwrap();
 
+        let disk_seg = DiskSegment::open(dir.clone()).await.unwrap();
+
+        // lookups no longer touch the bloom file
+        fs::remove_file(dir.join("bloom.bin")).await.unwrap();
+
+        assert_eq!(disk_seg.find("a").await.unwrap().values, ["1"]);
+        assert!(disk_seg.find("b").await.unwrap().values.is_empty());
+    }
+
+    #[tokio::test]
+    async fn segments_without_reverse_table_build_it() {
+        let tmp = tempdir().unwrap();
+        let dir = tmp.path().join("seg");
+
+        fs::create_dir_all(&dir).await.unwrap();
+
+        let mut map = FxHashMap::default();
+        map.insert("a".to_string(), vec!["1".to_string(), "2".to_string()]);
+        map.insert("b".to_string(), vec!["2".to_string()]);
+
+        let mut deletion = FxHashMap::default();
+        deletion.insert("c".to_string(), Some(vec!["2".to_string()]));
+        deletion.insert("d".to_string(), None);
+
+        let merged = CachedSegment::merge(
+            [
+                &CachedSegment::new(&map),
+                &CachedSegment::deletion(&deletion),
+            ],
+            false,
+        );
+
+        DiskSegment::create(dir.clone())
+            .flush_memory_segment(&merged)
+            .await
+            .unwrap();
+
+        let expected = merged.find_keys_for_value("2");
+
+        let stored = DiskSegment::open(dir.clone()).await.unwrap();
+        assert_eq!(stored.find_keys_for_value("2").await.unwrap(), expected);
+
+        // as written before the reverse table was introduced
+        fs::remove_file(dir.join("reverse.bin")).await.unwrap();
+        fs::remove_file(dir.join("checksums.bin")).await.unwrap();
+
+        let built = DiskSegment::open(dir.clone()).await.unwrap();
+
+        assert_eq!(built.find_keys_for_value("2").await.unwrap(), expected);
+        assert_eq!(built.find_keys_for_value("1").await.unwrap().values, ["a"]);
+        assert!(built.is_key_deleted("d").await.unwrap());
+        assert!(!built.is_key_deleted("a").await.unwrap());
+        built.verify().await.unwrap();
+    }
+
+    async fn flip_last_byte(path: &Path) {
+        let mut buffer = fs::read(path).await.unwrap();
+        *buffer.last_mut().unwrap() ^= 0b1;
+        fs::write(path, buffer).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn flipped_bits_are_detected() {
+        let tmp = tempdir().unwrap();
+        let dir = tmp.path().join("seg");
+
+        fs::create_dir_all(&dir).await.unwrap();
+
+        let mut map = FxHashMap::default();
+        map.insert("a", vec!["1", "2"]);
+        map.insert("b", vec!["3"]);
+
+        let mem_seg = CachedSegment::new(&map);
+        let mut disk_seg = DiskSegment::create(dir.clone());
+
+        disk_seg.flush_memory_segment(&mem_seg).await.unwrap();
+
+        let disk_seg = DiskSegment::open(dir.clone()).await.unwrap();
+        disk_seg.verify().await.unwrap();
+
+        flip_last_byte(&dir.join("entries.bin")).await;
+
+        // an already open segment notices on a full verification or a checked read
+        assert!(matches!(
+            disk_seg.verify().await,
+            Err(DiskResolutionError::Corrupted { file }) if file == "entries.bin"
+        ));
+        assert!(matches!(
+            disk_seg.load_tables().await,
+            Err(DiskResolutionError::Corrupted { .. })
+        ));
+
+        flip_last_byte(&dir.join("entries.bin")).await;
+
+        for name in [
+            "keys.data.bin",
+            "values.data.bin",
+            "entries.bin",
+            "bloom.bin",
+        ] {
+            flip_last_byte(&dir.join(name)).await;
+
+            assert!(matches!(
+                DiskSegment::open(dir.clone()).await,
+                Err(DiskResolutionError::Corrupted { file }) if file == name
+            ));
+
+            flip_last_byte(&dir.join(name)).await;
+        }
+
+        let disk_seg = DiskSegment::open(dir.clone()).await.unwrap();
+        assert_eq!(disk_seg.find("a").await.unwrap().values, ["1", "2"]);
+    }
+
+    #[tokio::test]
+    async fn truncated_files_are_rejected_on_open() {
+        let tmp = tempdir().unwrap();
+        let dir = tmp.path().join("seg");
+
+        fs::create_dir_all(&dir).await.unwrap();
+
+        let mut map = FxHashMap::default();
+        map.insert("a", vec!["1"]);
+
+        let mem_seg = CachedSegment::new(&map);
+        let mut disk_seg = DiskSegment::create(dir.clone());
+
+        disk_seg.flush_memory_segment(&mem_seg).await.unwrap();
+
+        let buffer = fs::read(dir.join("keys.data.bin")).await.unwrap();
+        fs::write(dir.join("keys.data.bin"), &buffer[..buffer.len() - 1])
+            .await
+            .unwrap();
+
+        assert!(matches!(
+            DiskSegment::open(dir.clone()).await,
+            Err(DiskResolutionError::Corrupted { file }) if file == "keys.data.bin"
+        ));
+
+        // segments written without checksums are still readable
+        fs::write(dir.join("keys.data.bin"), buffer).await.unwrap();
+        fs::remove_file(dir.join(CHECKSUMS_FILE_NAME))
+            .await
+            .unwrap();
+
+        let disk_seg = DiskSegment::open(dir.clone()).await.unwrap();
+
+        disk_seg.verify().await.unwrap();
+        assert_eq!(disk_seg.find("a").await.unwrap().values, ["1"]);
+    }
+
+    #[tokio::test]
+    async fn headerless_segments_are_still_readable() {
+        let tmp = tempdir().unwrap();
+        let dir = tmp.path().join("seg");
+
+        fs::create_dir_all(&dir).await.unwrap();
+
+        let mut map = FxHashMap::default();
+        map.insert("a", vec!["1", "2"]);
+        map.insert("b", vec!["3"]);
+
+        let mem_seg = CachedSegment::new(&map);
+        let mut disk_seg = DiskSegment::create(dir.clone());
+
         disk_seg.flush_memory_segment(&mem_seg).await.unwrap();
 
-        assert_eq!(disk_seg.find("z").await.unwrap().len(), 0);
+        // rewrite the segment the way it was laid out before headers
+        fs::remove_file(dir.join(CHECKSUMS_FILE_NAME))
+            .await
+            .unwrap();
+
+        for name in ["keys.data.bin", "values.data.bin"] {
+            let prefix = name.trim_end_matches(".data.bin");
+
+            let table = disk_seg.read_full_table(prefix).await.unwrap();
+            fs::remove_file(dir.join(name)).await.unwrap();
+            fs::remove_file(dir.join(format!("{prefix}.lookup.bin")))
+                .await
+                .unwrap();
+
+            let mut data = Vec::new();
+            let mut lookup = Vec::new();
+
+            for entry in &table {
+                lookup.extend_from_slice(&(data.len() as u64).to_be_bytes());
+
+                let flag = match entry {
+                    Entry::Compressed(_) => 0b1 << 31,
+                    Entry::Uncompressed(_) => 0,
+                };
+
+                data.extend_from_slice(&(flag | entry.as_ref().len() as u32).to_be_bytes());
+                data.extend_from_slice(entry.as_ref());
+            }
+
+            fs::write(dir.join(name), data).await.unwrap();
+            fs::write(dir.join(format!("{prefix}.lookup.bin")), lookup)
+                .await
+                .unwrap();
+        }
+
+        for name in ["entries.bin", "bloom.bin"] {
+            let buffer = fs::read(dir.join(name)).await.unwrap();
+            fs::write(dir.join(name), &buffer[header::SIZE..])
+                .await
+                .unwrap();
+        }
+
+        let disk_seg = DiskSegment::open(dir.clone()).await.unwrap();
+
+        // merged as a whole rather than a window at a time, as the values may be in any order
+        assert!(!disk_seg.is_ordered());
+
+        assert_eq!(disk_seg.find("a").await.unwrap().values, ["1", "2"]);
+        assert_eq!(disk_seg.find("b").await.unwrap().values, ["3"]);
+        assert_eq!(disk_seg.load_tables().await.unwrap().2.len(), 3);
+    }
+
+    #[tokio::test]
+    async fn future_format_versions_are_refused() {
+        let tmp = tempdir().unwrap();
+        let dir = tmp.path().join("seg");
+
+        fs::create_dir_all(&dir).await.unwrap();
+
+        let mut map = FxHashMap::default();
+        map.insert("a", vec!["1"]);
+
+        let mem_seg = CachedSegment::new(&map);
+        let mut disk_seg = DiskSegment::create(dir.clone());
+
+        disk_seg.flush_memory_segment(&mem_seg).await.unwrap();
+        fs::remove_file(dir.join(CHECKSUMS_FILE_NAME))
+            .await
+            .unwrap();
+
+        // the version sits right after the magic
+        let mut buffer = fs::read(dir.join("keys.lookup.bin")).await.unwrap();
+        buffer[4..6].copy_from_slice(&(header::FORMAT_VERSION + 1).to_be_bytes());
+        fs::write(dir.join("keys.lookup.bin"), buffer)
+            .await
+            .unwrap();
+
+        assert!(matches!(
+            DiskSegment::open(dir.clone()).await,
+            Err(DiskResolutionError::UnsupportedVersion { file, .. }) if file == "keys.lookup.bin"
+        ));
     }
 }
@@ -0,0 +1,64 @@
+use std::{path::PathBuf, sync::Arc, time::Duration};
+use tokio::{io, sync::OwnedRwLockReadGuard};
+
+use super::{disk::DiskSegment, memory::CachedSegment};
+
+/// Thresholds after which the memory segments of a partition are merged and written to disk.
+#[derive(Debug, Clone)]
+pub struct FlushPolicy {
+    /// Number of queued memory segments.
+    pub max_segments: usize,
+
+    /// Approximate size of the queued memory segments, in bytes.
+    pub max_bytes: usize,
+
+    /// Age of the oldest queued memory segment.
+    pub max_age: Duration,
+
+    /// How often the thresholds are checked by the background task.
+    pub interval: Duration,
+}
+
+impl Default for FlushPolicy {
+    fn default() -> Self {
+        Self {
+            max_segments: 64,
+            max_bytes: 32 * 1024 * 1024,
+            max_age: Duration::from_secs(30),
+            interval: Duration::from_secs(1),
+        }
+    }
+}
+
+/// Snapshot of the memory segments being written into a single disk segment.
+///
+/// The job runs without access to the segment map, so inserts and lookups proceed while the
+/// segment is written; the memory segments stay visible until the flush is completed.
+pub struct FlushJob {
+    pub(super) id: usize,
+    pub(super) directory: PathBuf,
+    pub(super) segments: Vec<Arc<CachedSegment>>,
+
+    /// Whether there are no disk segments for the tombstones to shadow.
+    pub(super) purge: bool,
+
+    /// Keeps a drop of the partition waiting until the job is completed.
+    pub(super) _running: OwnedRwLockReadGuard<()>,
+}
+
+impl FlushJob {
+    pub async fn run(&self) -> Result<DiskSegment, io::Error> {
+        let merged = CachedSegment::merge(self.segments.iter().map(Arc::as_ref), self.purge);
+
+        tracing::debug!(
+            "merged {:?} memory segments into {:?} entries",
+            self.segments.len(),
+            merged.entries.len()
+        );
+
+        super::write_segment(&self.directory, self.id, async |segment| {
+            segment.flush_memory_segment(&merged).await
+        })
+        .await
+    }
+}
@@ -0,0 +1,168 @@
+use tokio::{
+    fs::File,
+    io::{self, AsyncReadExt, AsyncSeekExt, SeekFrom},
+};
+use zerocopy::{
+    FromBytes, Immutable, IntoBytes, KnownLayout, Unaligned, byteorder::big_endian::U16,
+};
+
+use super::disk::DiskResolutionError;
+
+const MAGIC: [u8; 4] = *b"CHSG";
+
+/// Version of the segment file layout written by this build.
+///
+/// Version 0 stands for the headerless files written before headers were introduced; they're
+/// told apart by the missing magic. Version 2 added tombstones to the entries, and version 3
+/// orders the values of every key by value.
+pub const FORMAT_VERSION: u16 = 3;
+
+/// First version ordering the values of every key, which older entries keep in insertion
+/// order instead.
+pub const ORDERED_VERSION: u16 = 3;
+
+const BIG_ENDIAN: u8 = 0;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum FileKind {
+    Data = 1,
+    Lookup = 2,
+    Entries = 3,
+    Bloom = 4,
+    Checksums = 5,
+    Reverse = 6,
+}
+
+/// Fixed header every segment file starts with.
+#[derive(Debug, FromBytes, IntoBytes, KnownLayout, Immutable, Unaligned)]
+#[repr(C)]
+pub struct Header {
+    magic: [u8; 4],
+    version: U16,
+    kind: u8,
+    endianness: u8,
+    reserved: [u8; 8],
+}
+
+pub const SIZE: usize = size_of::<Header>();
+
+impl Header {
+    pub fn new(kind: FileKind) -> Self {
+        Self {
+            magic: MAGIC,
+            version: FORMAT_VERSION.into(),
+            kind: kind as u8,
+            endianness: BIG_ENDIAN,
+            reserved: [0; 8],
+        }
+    }
+
+    /// Checks the header at the start of `buffer`, returning the version of the file.
+    pub fn parse(buffer: &[u8], name: &str, kind: FileKind) -> Result<u16, DiskResolutionError> {
+        let Ok((header, _)) = Self::ref_from_prefix(buffer) else {
+            return Ok(0);
+        };
+
+        if header.magic != MAGIC {
+            return Ok(0);
+        }
+
+        let version = header.version.get();
+
+        if version > FORMAT_VERSION {
+            return Err(DiskResolutionError::UnsupportedVersion {
+                file: name.to_string(),
+                version,
+            });
+        }
+
+        if header.kind != kind as u8 || header.endianness != BIG_ENDIAN {
+            return Err(DiskResolutionError::Corrupted {
+                file: name.to_string(),
+            });
+        }
+
+        Ok(version)
+    }
+}
+
+/// Size of the header of a file of the given version.
+pub fn offset(version: u16) -> usize {
+    match version {
+        0 => 0,
+        _ => SIZE,
+    }
+}
+
+/// Strips the header off a file read in full.
+pub fn strip<'buffer>(
+    buffer: &'buffer [u8],
+    name: &str,
+    kind: FileKind,
+) -> Result<&'buffer [u8], DiskResolutionError> {
+    let version = Header::parse(buffer, name, kind)?;
+
+    Ok(&buffer[offset(version)..])
+}
+
+/// Checks the header of an opened file, returning the version of the file.
+pub async fn read_version(
+    file: &mut File,
+    name: &str,
+    kind: FileKind,
+) -> Result<u16, DiskResolutionError> {
+    let mut buffer = [0u8; SIZE];
+
+    match file.read_exact(&mut buffer).await {
+        Ok(_) => Header::parse(&buffer, name, kind),
+        // shorter than a header, so a legacy file
+        Err(err) if err.kind() == io::ErrorKind::UnexpectedEof => Ok(0),
+        Err(err) => Err(err.into()),
+    }
+}
+
+/// Checks the header of an opened file, returning the offset its contents start at.
+pub async fn read(file: &mut File, name: &str, kind: FileKind) -> Result<u64, DiskResolutionError> {
+    let version = read_version(file, name, kind).await?;
+
+    let offset = offset(version) as u64;
+    file.seek(SeekFrom::Start(offset)).await?;
+
+    Ok(offset)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_dispatches_on_version() {
+        let header = Header::new(FileKind::Entries);
+
+        assert_eq!(
+            Header::parse(header.as_bytes(), "entries.bin", FileKind::Entries).unwrap(),
+            FORMAT_VERSION
+        );
+
+        // headerless legacy contents
+        assert_eq!(
+            Header::parse(&[0; 8], "entries.bin", FileKind::Entries).unwrap(),
+            0
+        );
+
+        assert!(matches!(
+            Header::parse(header.as_bytes(), "bloom.bin", FileKind::Bloom),
+            Err(DiskResolutionError::Corrupted { .. })
+        ));
+
+        let mut future = Header::new(FileKind::Entries);
+        future.version = (FORMAT_VERSION + 1).into();
+
+        assert!(matches!(
+            Header::parse(future.as_bytes(), "entries.bin", FileKind::Entries),
+            Err(DiskResolutionError::UnsupportedVersion { version, .. })
+                if version == FORMAT_VERSION + 1
+        ));
+    }
+}
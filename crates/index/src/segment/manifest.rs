@@ -0,0 +1,120 @@
+use serde::{Deserialize, Serialize};
+use std::{
+    path::Path,
+    time::{SystemTime, UNIX_EPOCH},
+};
+use tokio::{
+    fs::{self, File},
+    io::{self, AsyncWriteExt},
+};
+
+pub const FILE_NAME: &str = "manifest.json";
+
+const TEMPORARY_FILE_NAME: &str = "manifest.json.tmp";
+
+pub const FORMAT_VERSION: u32 = 1;
+
+/// Source of truth for which disk segments make up a partition.
+///
+/// A segment directory only becomes part of the partition once a manifest listing it is
+/// published, and stops being part of it as soon as one without it is; anything else found
+/// in the directory is a leftover of an interrupted write or compaction.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Manifest {
+    pub version: u32,
+
+    /// Incremented on every publish.
+    pub generation: u64,
+
+    /// Picked when the partition is created, telling it apart from partitions dropped from the
+    /// same directory before.
+    pub incarnation: u64,
+
+    /// Highest segment or sealed log index ever handed out.
+    pub counter: usize,
+
+    /// Sealed write-ahead logs up to this index are persisted in the listed segments.
+    pub checkpoint: usize,
+
+    /// Live segment directories, oldest first.
+    pub segments: Vec<String>,
+}
+
+/// Picks the incarnation of a newly created partition.
+pub fn incarnation() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |elapsed| elapsed.as_nanos() as u64)
+}
+
+impl Manifest {
+    pub async fn load(directory: &Path) -> Result<Option<Self>, io::Error> {
+        let buffer = match fs::read(directory.join(FILE_NAME)).await {
+            Ok(buffer) => buffer,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(err) => return Err(err),
+        };
+
+        let manifest = serde_json::from_slice::<Self>(&buffer)?;
+
+        tracing::trace!(
+            "loaded manifest generation {:?} with {:?} segments",
+            manifest.generation,
+            manifest.segments.len()
+        );
+
+        Ok(Some(manifest))
+    }
+
+    /// Atomically replaces the manifest in `directory` by writing a temporary file and
+    /// renaming it over the old one.
+    pub async fn publish(&self, directory: &Path) -> Result<(), io::Error> {
+        let temporary = directory.join(TEMPORARY_FILE_NAME);
+
+        let mut file = File::create(&temporary).await?;
+        file.write_all(&serde_json::to_vec(self)?).await?;
+        file.sync_all().await?;
+
+        fs::rename(&temporary, directory.join(FILE_NAME)).await?;
+        File::open(directory).await?.sync_all().await?;
+
+        tracing::debug!(
+            "published manifest generation {:?} with {:?} segments",
+            self.generation,
+            self.segments.len()
+        );
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::tempdir;
+
+    #[tokio::test]
+    async fn publish_and_load() {
+        let tmp = tempdir().unwrap();
+
+        assert_eq!(Manifest::load(tmp.path()).await.unwrap(), None);
+
+        let manifest = Manifest {
+            version: FORMAT_VERSION,
+            generation: 3,
+            incarnation: incarnation(),
+            counter: 7,
+            checkpoint: 5,
+            segments: vec!["seg-2".to_string(), "seg-6".to_string()],
+        };
+
+        manifest.publish(tmp.path()).await.unwrap();
+
+        assert_eq!(Manifest::load(tmp.path()).await.unwrap(), Some(manifest));
+        assert!(
+            !fs::try_exists(tmp.path().join(TEMPORARY_FILE_NAME))
+                .await
+                .unwrap()
+        );
+    }
+}
@@ -1,9 +1,9 @@
 use bloomfilter::Bloom;
 use fxhash::{FxHashMap, FxHashSet};
-use std::borrow::Cow;
+use std::{borrow::Cow, ops::Range, time::Instant};
 use zerocopy::IntoBytes;
 
-#[derive(PartialEq, Eq, PartialOrd, Ord, Debug, Hash)]
+#[derive(Clone, PartialEq, Eq, PartialOrd, Ord, Debug, Hash)]
 pub enum Entry {
     Compressed(Vec<u8>),
     Uncompressed(String),
@@ -40,146 +40,540 @@ impl Entry {
         }
     }
 
-    pub fn into_uncompressed(self) -> String {
+    /// Returns `None` for undecodable entries, such as ones read from a damaged file.
+    pub fn try_into_uncompressed(self) -> Option<String> {
         match self {
             Self::Compressed(buffer) => {
-                String::try_from(snappy::uncompress(buffer.as_bytes()).unwrap()).unwrap()
+                String::try_from(snappy::uncompress(buffer.as_bytes()).ok()?).ok()
             }
-            Self::Uncompressed(buffer) => buffer,
+            Self::Uncompressed(buffer) => Some(buffer),
         }
     }
 }
 
+/// Flag on the value index of an entry that deletes the pair from older segments.
+pub const TOMBSTONE: u32 = 0b1 << (u32::BITS - 1);
+
+/// Value index of an entry that deletes the whole key from older segments.
+pub const KEY_TOMBSTONE: u32 = u32::MAX;
+
+/// Decoded value index of an entry.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Record {
+    Value(u32),
+    Tombstone(u32),
+    KeyTombstone,
+}
+
+impl Record {
+    pub fn from_raw(raw: u32) -> Self {
+        match raw {
+            KEY_TOMBSTONE => Self::KeyTombstone,
+            raw if raw & TOMBSTONE != 0 => Self::Tombstone(raw & !TOMBSTONE),
+            raw => Self::Value(raw),
+        }
+    }
+
+    pub fn into_raw(self) -> u32 {
+        match self {
+            Self::Value(value) => value,
+            Self::Tombstone(value) => value | TOMBSTONE,
+            Self::KeyTombstone => KEY_TOMBSTONE,
+        }
+    }
+
+    /// Maps the value index, if there's one.
+    pub fn map(self, f: impl FnOnce(u32) -> u32) -> Self {
+        match self {
+            Self::Value(value) => Self::Value(f(value)),
+            Self::Tombstone(value) => Self::Tombstone(f(value)),
+            Self::KeyTombstone => Self::KeyTombstone,
+        }
+    }
+}
+
+/// Everything a segment records under a key.
+///
+/// Tombstones only shadow older segments, while the values of a segment are newer than its
+/// own tombstones: a key deleted and inserted again before a flush keeps the new values.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Found<T = String> {
+    pub values: Vec<T>,
+
+    /// Values deleted from older segments.
+    pub tombstones: Vec<T>,
+
+    /// Whether the whole key is deleted from older segments.
+    pub deleted: bool,
+}
+
+impl<T> Default for Found<T> {
+    fn default() -> Self {
+        Self {
+            values: Vec::new(),
+            tombstones: Vec::new(),
+            deleted: false,
+        }
+    }
+}
+
+impl<T> Found<T> {
+    pub fn is_empty(&self) -> bool {
+        self.values.is_empty() && self.tombstones.is_empty() && !self.deleted
+    }
+
+    /// Drops the tombstones, once there's nothing older left for them to shadow.
+    pub fn purge(&mut self) {
+        self.tombstones.clear();
+        self.deleted = false;
+    }
+}
+
+impl<T: Eq + std::hash::Hash> Found<T> {
+    pub fn push(&mut self, record: Record, resolve: impl FnOnce(u32) -> T) {
+        match record {
+            Record::Value(value) => self.values.push(resolve(value)),
+            Record::Tombstone(value) => self.tombstones.push(resolve(value)),
+            Record::KeyTombstone => self.deleted = true,
+        }
+    }
+
+    /// Applies the records of a newer segment on top of these.
+    pub fn apply(&mut self, newer: Self) {
+        if newer.deleted {
+            self.values.clear();
+            self.tombstones.clear();
+            self.deleted = true;
+        }
+
+        if !newer.tombstones.is_empty() {
+            let shadowed = newer.tombstones.iter().collect::<FxHashSet<_>>();
+            self.values.retain(|value| !shadowed.contains(value));
+        }
+
+        self.tombstones.extend(newer.tombstones);
+        self.values.extend(newer.values);
+    }
+}
+
 pub struct CachedSegment {
     pub keys: Vec<Entry>,
     pub values: Vec<Entry>,
     pub entries: Vec<(u32, u32)>,
+
+    /// Entries flipped into `(record, key)` and ordered by record, so that keys can be looked
+    /// up by value. Key tombstones, having no value, come last.
+    pub reverse: Vec<(u32, u32)>,
     pub bloom: Bloom<str>,
+    pub created: Instant,
+    pub size: usize,
 }
 
 impl CachedSegment {
-    fn to_keys_values_sets<K: AsRef<str> + Ord + Eq, B: AsRef<str>>(
-        entries: &FxHashMap<K, Vec<B>>,
-    ) -> (Vec<Entry>, Vec<Entry>) {
-        let mut values_mapping = FxHashSet::default();
+    fn build<K: AsRef<str>, B: AsRef<str>>(records: &[(K, Found<B>)]) -> Self {
+        let mut keys = records
+            .iter()
+            .filter(|(_, found)| !found.is_empty())
+            .map(|(key, _)| key.as_ref())
+            .collect::<Vec<_>>();
+        keys.sort_unstable();
+        keys.dedup();
 
-        let mut keys = Vec::new();
+        tracing::trace!("created keys mapping: {:?}", keys.len());
 
-        for (key, items) in entries {
-            keys.push(Entry::new(key.as_ref()));
+        let mut values = records
+            .iter()
+            .flat_map(|(_, found)| found.values.iter().chain(&found.tombstones))
+            .map(AsRef::as_ref)
+            .collect::<Vec<_>>();
+        values.sort_unstable();
+        values.dedup();
 
-            values_mapping.extend(items.iter().map(|item| item.as_ref()));
+        tracing::trace!("created values mapping: {:?}", values.len());
+
+        let key_indices = (keys.iter().copied().zip(0u32..)).collect::<FxHashMap<_, _>>();
+        let value_indices = (values.iter().copied().zip(0u32..)).collect::<FxHashMap<_, _>>();
+
+        let mut entries = Vec::new();
+
+        for (key, found) in records {
+            let Some(&key) = key_indices.get(key.as_ref()) else {
+                continue;
+            };
+
+            if found.deleted {
+                entries.push((key, KEY_TOMBSTONE));
+            }
+
+            entries.extend(found.tombstones.iter().map(|value| {
+                (
+                    key,
+                    Record::Tombstone(value_indices[value.as_ref()]).into_raw(),
+                )
+            }));
+
+            entries.extend(
+                found
+                    .values
+                    .iter()
+                    .map(|value| (key, value_indices[value.as_ref()])),
+            );
         }
 
-        keys.sort_unstable_by(|a, b| a.as_uncompressed().cmp(&b.as_uncompressed()));
+        entries.sort_unstable_by_key(|&(key, record)| run_order(key, record));
 
-        tracing::trace!("created keys mapping: {:?}", keys.len());
+        tracing::trace!("created entries: {:?}", entries.len());
 
-        let mut values = values_mapping
-            .into_iter()
-            .map(Entry::new)
-            .collect::<Vec<_>>();
-        values.sort_unstable_by(|a, b| a.as_uncompressed().cmp(&b.as_uncompressed()));
+        entries.dedup();
 
-        tracing::trace!("created values mapping: {:?}", values.len());
+        tracing::trace!("deduplicated entries: {:?}", entries.len());
 
-        (keys, values)
+        Self::from_parts(
+            keys.into_iter().map(Entry::new).collect(),
+            values.into_iter().map(Entry::new).collect(),
+            entries,
+        )
     }
 
-    pub fn new<K: AsRef<str> + Ord + Eq, B: AsRef<str>>(entries: FxHashMap<K, Vec<B>>) -> Self {
-        let (keys_linear, values_linear) = Self::to_keys_values_sets(&entries);
-
-        let mut bloom =
-            Bloom::new((entries.len().ilog2() * 2 + 1) as usize, entries.len()).unwrap();
+    pub fn new<K: AsRef<str> + Ord + Eq, B: AsRef<str>>(entries: &FxHashMap<K, Vec<B>>) -> Self {
+        let records = entries
+            .iter()
+            .map(|(key, values)| {
+                (
+                    key.as_ref(),
+                    Found {
+                        values: values.iter().map(AsRef::as_ref).collect(),
+                        ..Found::default()
+                    },
+                )
+            })
+            .collect::<Vec<_>>();
 
-        tracing::trace!("created new bloom of size: {:?}", bloom.len());
+        Self::build(&records)
+    }
 
-        let mut entries_linear = entries
-            .into_iter()
-            .flat_map(|(key, values)| {
-                values
-                    .into_iter()
-                    .map(|value| {
-                        bloom.set(key.as_ref());
-
-                        let key = keys_linear
-                            .binary_search_by(|entry| {
-                                entry.as_uncompressed().as_ref().cmp(key.as_ref())
-                            })
-                            .unwrap();
-                        let value = values_linear
-                            .binary_search_by(|entry| {
-                                entry.as_uncompressed().as_ref().cmp(value.as_ref())
-                            })
-                            .unwrap();
-
-                        (key as u32, value as u32)
-                    })
-                    .collect::<Vec<_>>()
+    /// Builds a segment of tombstones, deleting the given values of every key, or the whole
+    /// key for `None`.
+    pub fn deletion<K: AsRef<str>, B: AsRef<str>>(deletion: &FxHashMap<K, Option<Vec<B>>>) -> Self {
+        let records = deletion
+            .iter()
+            .map(|(key, values)| {
+                (
+                    key.as_ref(),
+                    Found {
+                        tombstones: values
+                            .iter()
+                            .flatten()
+                            .map(AsRef::as_ref)
+                            .collect::<Vec<_>>(),
+                        deleted: values.is_none(),
+                        ..Found::default()
+                    },
+                )
             })
             .collect::<Vec<_>>();
 
-        entries_linear.sort_unstable_by_key(|&(key, ..)| key);
+        Self::build(&records)
+    }
 
-        tracing::trace!("created entries: {:?}", entries_linear.len());
+    /// Builds a segment out of already sorted and deduplicated tables.
+    pub fn from_parts(keys: Vec<Entry>, values: Vec<Entry>, entries: Vec<(u32, u32)>) -> Self {
+        let mut bloom = bloom(keys.len());
 
-        entries_linear.dedup();
+        for key in &keys {
+            bloom.set(key.as_uncompressed().as_ref());
+        }
 
-        tracing::trace!("deduplicated entries: {:?}", entries_linear.len());
+        tracing::trace!("created new bloom of size: {:?}", bloom.len());
+
+        let reverse = reverse(&entries);
+
+        let size = keys
+            .iter()
+            .chain(&values)
+            .map(|entry| entry.as_ref().len())
+            .sum::<usize>()
+            + (entries.len() + reverse.len()) * size_of::<(u32, u32)>()
+            + bloom.as_slice().len();
 
         Self {
-            keys: keys_linear,
-            values: values_linear,
-            entries: entries_linear,
+            keys,
+            values,
+            entries,
+            reverse,
             bloom,
+            created: Instant::now(),
+            size,
+        }
+    }
+
+    /// Combines several segments, oldest first, into one, deduplicating keys, values and
+    /// entries. Tombstones shadow the older segments right away, and are dropped altogether
+    /// with `purge`.
+    pub fn merge<'segment>(
+        segments: impl IntoIterator<Item = &'segment CachedSegment>,
+        purge: bool,
+    ) -> Self {
+        let mut merged = FxHashMap::<String, Found>::default();
+
+        for segment in segments {
+            for (key, found) in segment.iter() {
+                merged.entry(key).or_default().apply(found);
+            }
+        }
+
+        if purge {
+            merged.values_mut().for_each(Found::purge);
+        }
+
+        tracing::trace!("merged segments into {:?} keys", merged.len());
+
+        Self::build(&merged.into_iter().collect::<Vec<_>>())
+    }
+
+    fn resolve(&self, entries: &[(u32, u32)]) -> Found {
+        let mut found = Found::default();
+
+        for &(_, value) in entries {
+            found.push(Record::from_raw(value), |value| {
+                self.values[value as usize].as_uncompressed().into_owned()
+            });
         }
+
+        found
+    }
+
+    /// Decodes every key along with its records.
+    pub fn iter(&self) -> impl Iterator<Item = (String, Found)> + '_ {
+        self.entries
+            .chunk_by(|(a, ..), (b, ..)| a == b)
+            .map(|entries| {
+                (
+                    self.keys[entries[0].0 as usize]
+                        .as_uncompressed()
+                        .into_owned(),
+                    self.resolve(entries),
+                )
+            })
     }
 
-    pub fn find(&self, key: &str) -> Vec<String> {
+    pub fn find(&self, key: &str) -> Found {
+        self.find_window(key, ALL_VALUES)
+    }
+
+    /// Looks the key up, only resolving the values within `window` of the ones it holds.
+    pub fn find_window(&self, key: &str, window: Range<usize>) -> Found {
         let Ok(key_index) = self
             .keys
             .binary_search_by(|entry| entry.as_uncompressed().as_ref().cmp(key))
         else {
-            return Vec::new();
+            return Found::default();
         };
 
         tracing::trace!("found key index: {:?}", key_index);
 
-        let Ok(mut index) = self
-            .entries
-            .binary_search_by(|&(index, ..)| index.cmp(&(key_index as u32)))
+        let found = self.resolve_window(key_index as u32, window);
+
+        tracing::trace!("loaded values: {:?}", found.values.len());
+
+        found
+    }
+
+    /// Keys holding `value` as values, and keys deleting it as tombstones.
+    pub fn find_keys_for_value(&self, value: &str) -> Found {
+        let Ok(value_index) = self
+            .values
+            .binary_search_by(|entry| entry.as_uncompressed().as_ref().cmp(value))
         else {
-            return Vec::new();
+            return Found::default();
         };
 
-        tracing::trace!("poked (found approximately) entries at index: {:?}", index);
-
-        let mut items = Vec::new();
+        let keys = |record| {
+            reverse_run(&self.reverse, record)
+                .iter()
+                .map(|&(_, key)| self.key(key))
+                .collect()
+        };
 
-        while index as isize > 0 && self.entries[index - 1].0 == key_index as u32 {
-            index -= 1;
+        Found {
+            values: keys(value_index as u32),
+            tombstones: keys(Record::Tombstone(value_index as u32).into_raw()),
+            deleted: false,
         }
+    }
 
-        tracing::trace!("found the start at index: {:?}", index);
+    /// Whether the segment holds the value under the key, deletes it, or records nothing
+    /// about it.
+    pub fn holds(&self, key: &str, value: &str) -> Option<bool> {
+        let key_index = self
+            .keys
+            .binary_search_by(|entry| entry.as_uncompressed().as_ref().cmp(key))
+            .ok()? as u32;
+
+        let recorded = |record: Record| {
+            self.reverse
+                .binary_search(&(record.into_raw(), key_index))
+                .is_ok()
+        };
 
-        for index in index..self.entries.len() {
-            let (item_key_index, value_index) = self.entries[index];
+        if let Ok(value_index) = self
+            .values
+            .binary_search_by(|entry| entry.as_uncompressed().as_ref().cmp(value))
+        {
+            let value_index = value_index as u32;
 
-            if key_index as u32 != item_key_index {
-                break;
+            // values are newer than the tombstones of their own segment
+            if recorded(Record::Value(value_index)) {
+                return Some(true);
             }
 
-            items.push(
-                self.values[value_index as usize]
-                    .as_uncompressed()
-                    .to_string(),
-            );
+            if recorded(Record::Tombstone(value_index)) {
+                return Some(false);
+            }
         }
 
-        tracing::trace!("loaded values: {:?}", items.len());
+        recorded(Record::KeyTombstone).then_some(false)
+    }
+
+    /// Whether the segment records anything under the key.
+    pub fn contains(&self, key: &str) -> bool {
+        self.bloom.check(key) && self.key_index(key).is_some()
+    }
+
+    /// Number of values the segment holds under the key, counted off the entries.
+    pub fn count(&self, key: &str) -> usize {
+        let Some(key_index) = self.key_index(key) else {
+            return 0;
+        };
+
+        let start = self
+            .entries
+            .partition_point(|&(index, ..)| index < key_index);
+        let end = self
+            .entries
+            .partition_point(|&(index, ..)| index <= key_index);
+
+        let (_, values) = window(&self.entries[start..end], ALL_VALUES, |&(_, record)| {
+            record >= TOMBSTONE
+        });
+
+        values.len()
+    }
+
+    fn key_index(&self, key: &str) -> Option<u32> {
+        self.keys
+            .binary_search_by(|entry| entry.as_uncompressed().as_ref().cmp(key))
+            .ok()
+            .map(|index| index as u32)
+    }
+
+    /// Whether the segment deletes the whole of `key` from older segments.
+    pub fn is_key_deleted(&self, key: &str) -> bool {
+        let Ok(key_index) = self
+            .keys
+            .binary_search_by(|entry| entry.as_uncompressed().as_ref().cmp(key))
+        else {
+            return false;
+        };
 
-        items
+        self.reverse
+            .binary_search(&(KEY_TOMBSTONE, key_index as u32))
+            .is_ok()
     }
+
+    /// Indices of the keys within `[start, end)`, or past `start` without an end.
+    pub fn key_range(&self, start: &str, end: Option<&str>) -> Range<u32> {
+        let bound = |bound: &str| {
+            self.keys
+                .partition_point(|entry| entry.as_uncompressed().as_ref() < bound)
+                as u32
+        };
+
+        let start = bound(start);
+
+        start..end.map_or(self.keys.len() as u32, bound).max(start)
+    }
+
+    pub fn key(&self, key_index: u32) -> String {
+        self.keys[key_index as usize].as_uncompressed().into_owned()
+    }
+
+    pub fn resolve_key(&self, key_index: u32) -> Found {
+        self.resolve_window(key_index, ALL_VALUES)
+    }
+
+    fn resolve_window(&self, key_index: u32, window: Range<usize>) -> Found {
+        let start = self
+            .entries
+            .partition_point(|&(index, ..)| index < key_index);
+        let end = self
+            .entries
+            .partition_point(|&(index, ..)| index <= key_index);
+
+        tracing::trace!("found entries at: {:?}", start..end);
+
+        let (tombstones, values) =
+            self::window(&self.entries[start..end], window, |&(_, record)| {
+                record >= TOMBSTONE
+            });
+
+        let mut found = self.resolve(tombstones);
+        found.values = self.resolve(values).values;
+
+        found
+    }
+}
+
+/// Order of the entries of a segment: by key, with the tombstones of a key ahead of its values,
+/// and both in the order of the values table, which is the order of the values themselves.
+pub fn run_order(key: u32, record: u32) -> (u32, bool, u32) {
+    (key, record < TOMBSTONE, record)
+}
+
+/// Window covering every value of a key.
+pub const ALL_VALUES: Range<usize> = 0..usize::MAX;
+
+/// Splits the run of a key into its tombstones and the values within `window`.
+///
+/// Every segment writes the tombstones of a key ahead of its values, so the values make up the
+/// tail of the run.
+pub fn window<T>(
+    run: &[T],
+    window: Range<usize>,
+    is_tombstone: impl Fn(&T) -> bool,
+) -> (&[T], &[T]) {
+    let (tombstones, values) = run.split_at(run.partition_point(is_tombstone));
+
+    let start = window.start.min(values.len());
+    let end = window.end.clamp(start, values.len());
+
+    (tombstones, &values[start..end])
+}
+
+/// Empty bloom filter sized for the keys of a segment.
+pub fn bloom(keys: usize) -> Bloom<str> {
+    let items = keys.max(1);
+
+    Bloom::new((items.ilog2() * 2 + 1) as usize, items).unwrap()
+}
+
+/// Flips entries into the reverse table.
+pub fn reverse(entries: &[(u32, u32)]) -> Vec<(u32, u32)> {
+    let mut reverse = entries
+        .iter()
+        .map(|&(key, record)| (record, key))
+        .collect::<Vec<_>>();
+    reverse.sort_unstable();
+
+    reverse
+}
+
+/// Pairs of the reverse table under `record`.
+pub fn reverse_run(reverse: &[(u32, u32)], record: u32) -> &[(u32, u32)] {
+    let start = reverse.partition_point(|&(item, _)| item < record);
+    let end = reverse.partition_point(|&(item, _)| item <= record);
+
+    &reverse[start..end]
 }
 
 #[cfg(test)]
@@ -214,7 +608,7 @@ mod tests {
         let mut map = FxHashMap::default();
         map.insert("key", vec!["value", "value", "value2"]);
 
-        let seg = CachedSegment::new(map);
+        let seg = CachedSegment::new(&map);
 
         // Should contain unique key
         assert_eq!(seg.keys.len(), 1);
@@ -228,7 +622,7 @@ mod tests {
         assert_eq!(unique_values.len(), 2);
 
         // resolve should return a valid value for "key"
-        let resolved = seg.find("key");
+        let resolved = seg.find("key").values;
         assert_eq!(resolved, ["value", "value2"]);
     }
 
@@ -238,14 +632,14 @@ mod tests {
         map.insert("a", vec!["1"]);
         map.insert("b", vec!["2"]);
 
-        let seg = CachedSegment::new(map);
+        let seg = CachedSegment::new(&map);
 
         assert_eq!(seg.keys.len(), 2);
         assert_eq!(seg.values.len(), 2);
         assert_eq!(seg.entries.len(), 2);
 
-        assert_eq!(seg.find("a"), ["1"]);
-        assert_eq!(seg.find("b"), ["2"]);
+        assert_eq!(seg.find("a").values, ["1"]);
+        assert_eq!(seg.find("b").values, ["2"]);
     }
 
     #[test]
@@ -254,7 +648,7 @@ mod tests {
         let mut map = FxHashMap::default();
         map.insert("a", vec!["1", "2"]);
 
-        let seg = CachedSegment::new(map);
+        let seg = CachedSegment::new(&map);
 
         // Deduplicated keys (1 unique key)
         assert_eq!(seg.keys.len(), 1);
@@ -263,7 +657,7 @@ mod tests {
         assert_eq!(seg.entries.len(), 2);
 
         // Resolve returns both values
-        let resolved = seg.find("a");
+        let resolved = seg.find("a").values;
         assert_eq!(resolved, ["1", "2"]);
     }
 
@@ -273,15 +667,15 @@ mod tests {
         map.insert("a", vec!["1"]);
         map.insert("b", vec!["1"]);
 
-        let seg = CachedSegment::new(map);
+        let seg = CachedSegment::new(&map);
 
         // 2 keys, 1 deduplicated value
         assert_eq!(seg.keys.len(), 2);
         assert_eq!(seg.values.len(), 1);
         assert_eq!(seg.entries.len(), 2);
 
-        assert_eq!(seg.find("a"), ["1"]);
-        assert_eq!(seg.find("b"), ["1"]);
+        assert_eq!(seg.find("a").values, ["1"]);
+        assert_eq!(seg.find("b").values, ["1"]);
     }
 
     #[test]
@@ -290,13 +684,141 @@ mod tests {
         let mut map = FxHashMap::default();
         map.insert("a", vec!["1", "1"]);
 
-        let seg = CachedSegment::new(map);
+        let seg = CachedSegment::new(&map);
 
         // 1 unique key, 1 unique value
         assert_eq!(seg.keys.len(), 1);
         assert_eq!(seg.values.len(), 1);
         assert_eq!(seg.entries.len(), 1);
 
-        assert_eq!(seg.find("a"), ["1"]);
+        assert_eq!(seg.find("a").values, ["1"]);
+    }
+
+    #[test]
+    fn merge_combines_segments() {
+        let mut first = FxHashMap::default();
+        first.insert("a", vec!["1", "2"]);
+        first.insert("b", vec!["3"]);
+
+        let mut second = FxHashMap::default();
+        second.insert("a", vec!["2", "4"]);
+        second.insert("c", vec!["5"]);
+
+        let merged = CachedSegment::merge(
+            [&CachedSegment::new(&first), &CachedSegment::new(&second)],
+            false,
+        );
+
+        assert_eq!(merged.keys.len(), 3);
+        assert_eq!(merged.values.len(), 5);
+
+        assert_eq!(merged.find("a").values, ["1", "2", "4"]);
+        assert_eq!(merged.find("b").values, ["3"]);
+        assert_eq!(merged.find("c").values, ["5"]);
+    }
+
+    #[test]
+    fn merge_applies_newer_tombstones() {
+        let mut inserted = FxHashMap::default();
+        inserted.insert("a", vec!["1", "2"]);
+        inserted.insert("b", vec!["3"]);
+
+        let mut deletion = FxHashMap::default();
+        deletion.insert("a", Some(vec!["1"]));
+        deletion.insert("b", None);
+
+        let mut reinserted = FxHashMap::default();
+        reinserted.insert("b", vec!["4"]);
+
+        let deleted = CachedSegment::deletion(&deletion);
+
+        assert_eq!(
+            deleted.find("b"),
+            Found {
+                deleted: true,
+                ..Found::default()
+            }
+        );
+
+        let segments = [
+            CachedSegment::new(&inserted),
+            deleted,
+            CachedSegment::new(&reinserted),
+        ];
+
+        let merged = CachedSegment::merge(&segments, false);
+
+        assert_eq!(merged.find("a").values, ["2"]);
+        assert_eq!(merged.find("a").tombstones, ["1"]);
+        assert_eq!(merged.find("b").values, ["4"]);
+        assert!(merged.find("b").deleted);
+
+        let purged = CachedSegment::merge(&segments, true);
+
+        assert_eq!(
+            purged.find("a"),
+            Found {
+                values: vec!["2".to_string()],
+                ..Found::default()
+            }
+        );
+        assert_eq!(purged.values.len(), 2);
+    }
+
+    #[test]
+    fn find_keys_for_value_reads_the_reverse_table() {
+        let mut map = FxHashMap::default();
+        map.insert("a".to_string(), vec!["1".to_string(), "2".to_string()]);
+        map.insert("b".to_string(), vec!["2".to_string()]);
+
+        let mut deletion = FxHashMap::default();
+        deletion.insert("c".to_string(), Some(vec!["2".to_string()]));
+        deletion.insert("d".to_string(), None);
+
+        let segment = CachedSegment::merge(
+            [
+                &CachedSegment::new(&map),
+                &CachedSegment::deletion(&deletion),
+            ],
+            false,
+        );
+
+        let found = segment.find_keys_for_value("2");
+
+        assert_eq!(found.values, ["a", "b"]);
+        assert_eq!(found.tombstones, ["c"]);
+        assert_eq!(segment.find_keys_for_value("1").values, ["a"]);
+        assert_eq!(segment.find_keys_for_value("3"), Found::default());
+
+        assert!(segment.is_key_deleted("d"));
+        assert!(!segment.is_key_deleted("a"));
+        assert!(!segment.is_key_deleted("c"));
+        assert!(!segment.is_key_deleted("e"));
+    }
+
+    #[test]
+    fn count_skips_tombstones() {
+        let mut map = FxHashMap::default();
+        map.insert("a".to_string(), vec!["1".to_string(), "2".to_string()]);
+
+        let mut deletion = FxHashMap::default();
+        deletion.insert("a".to_string(), Some(vec!["0".to_string()]));
+        deletion.insert("b".to_string(), None);
+
+        let segment = CachedSegment::merge(
+            [
+                &CachedSegment::deletion(&deletion),
+                &CachedSegment::new(&map),
+            ],
+            false,
+        );
+
+        assert_eq!(segment.count("a"), 2);
+        assert_eq!(segment.count("b"), 0);
+        assert_eq!(segment.count("c"), 0);
+
+        assert!(segment.contains("a"));
+        assert!(segment.contains("b"));
+        assert!(!segment.contains("c"));
     }
 }
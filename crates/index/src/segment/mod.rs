@@ -1,25 +1,64 @@
-use fxhash::FxHashMap;
+use fxhash::{FxHashMap, FxHashSet};
+use serde::{Deserialize, Serialize};
 use snafu::Snafu;
-use std::{collections::VecDeque, path::PathBuf};
+use std::{
+    collections::VecDeque,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
 use tokio::{
-    fs::{self, read_dir},
+    fs::{self, File, read_dir},
     io,
+    sync::RwLock,
 };
 
 use crate::segment::memory::CachedSegment;
 
+mod compaction;
 mod disk;
+mod flush;
+mod header;
+mod manifest;
+#[cfg(feature = "mmap")]
+mod mapped;
 mod memory;
+mod scan;
+mod wal;
 
+pub use compaction::{CompactionJob, CompactionPolicy};
 pub use disk::DiskResolutionError;
+pub use flush::{FlushJob, FlushPolicy};
+pub use scan::Direction;
 
 pub struct TieredSegmentMap {
     pub(super) directory: PathBuf,
     counter: usize,
+    generation: u64,
+    incarnation: u64,
+    checkpoint: usize,
+
+    memory: VecDeque<Arc<memory::CachedSegment>>,
+
+    disk: VecDeque<Arc<disk::DiskSegment>>,
+
+    wal: Option<wal::WriteAheadLog>,
+    flushing: bool,
+    compacting: bool,
+    dropped: bool,
 
-    memory: VecDeque<memory::CachedSegment>,
+    /// Held for reading by every flush and compaction job, so that a drop can wait for the
+    /// ones still writing into the directory.
+    jobs: Arc<RwLock<()>>,
+}
 
-    disk: VecDeque<disk::DiskSegment>,
+/// Where a page of the values of a key ended.
+///
+/// Values come in order, so the next page picks up past the last one returned, however the
+/// segments were flushed or compacted in between.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Position {
+    /// Last value returned.
+    pub value: String,
 }
 
 #[derive(Debug, Snafu)]
@@ -27,11 +66,149 @@ pub enum SegmentMapError {
     #[snafu(transparent)]
     IoError { source: io::Error },
 
-    #[snafu(display("unknown file found in segments directory"))]
-    UnknownFile,
-
     #[snafu(display("file has invalid index"))]
     InvalidIndex,
+
+    #[snafu(display("manifest format version {version} is not supported"))]
+    UnsupportedVersion { version: u32 },
+
+    #[snafu(transparent)]
+    ResolutionError { source: DiskResolutionError },
+}
+
+/// Disk segment that failed verification.
+#[derive(Debug)]
+pub struct DamagedSegment {
+    pub directory: PathBuf,
+    pub error: DiskResolutionError,
+}
+
+/// Segments making up a segment map, as of when the stats were taken.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct SegmentStats {
+    /// Manifest generation of the disk segments.
+    pub generation: u64,
+
+    /// Memory segments waiting to be flushed.
+    pub memory_segments: usize,
+
+    /// Approximate size of the memory segments, in bytes.
+    pub memory_bytes: usize,
+
+    /// Disk segments, oldest first.
+    pub disk_segments: Vec<DiskSegmentStats>,
+
+    pub flushing: bool,
+    pub compacting: bool,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct DiskSegmentStats {
+    pub name: String,
+
+    /// Size of the segment files, in bytes.
+    pub size: u64,
+}
+
+fn parse_index(name: &str) -> Result<usize, SegmentMapError> {
+    name.split('-')
+        .find(|part| !part.is_empty() && part.chars().all(|char| char.is_ascii_digit()))
+        .ok_or(SegmentMapError::InvalidIndex)?
+        .parse::<usize>()
+        .map_err(|_| SegmentMapError::InvalidIndex)
+}
+
+fn segment_name(segment: &disk::DiskSegment) -> &str {
+    segment
+        .directory
+        .file_name()
+        .and_then(|name| name.to_str())
+        .expect("disk segments are always named in utf-8")
+}
+
+/// Writes the segment into `seg-{id}`, which stays invisible until a manifest listing it is
+/// published.
+async fn write_segment<E: From<io::Error>>(
+    directory: &Path,
+    id: usize,
+    write: impl AsyncFnOnce(&mut disk::DiskSegment) -> Result<(), E>,
+) -> Result<disk::DiskSegment, E> {
+    let path = directory.join(format!("seg-{id}"));
+
+    tracing::debug!("issued segment write into: {path:?}");
+
+    // not `create_dir_all`, so that a job finishing after its partition got dropped can't
+    // bring the directory back
+    fs::create_dir(&path).await?;
+
+    let mut disk_segment = disk::DiskSegment::create(path);
+
+    if let Err(err) = write(&mut disk_segment).await {
+        fs::remove_dir_all(&disk_segment.directory).await?;
+
+        return Err(err);
+    }
+
+    File::open(directory).await?.sync_all().await?;
+
+    tracing::trace!("persisted segment to disk: {:?}", disk_segment.directory);
+
+    Ok(disk_segment)
+}
+
+/// Contents of a segment map directory that aren't pointed to by the manifest.
+#[derive(Default)]
+struct Listing {
+    /// Segment directories, both `seg-{index}` and the older `{index}-segment`.
+    segments: Vec<(usize, String)>,
+    sealed: Vec<usize>,
+    maximum_index: usize,
+}
+
+impl Listing {
+    async fn read(directory: &Path) -> Result<Self, io::Error> {
+        let mut listing = Self::default();
+        let mut iter = read_dir(directory).await?;
+
+        while let Some(entry) = iter.next_entry().await? {
+            let name = entry.file_name();
+            let Some(name) = name.to_str() else {
+                tracing::warn!("ignoring unknown entry {name:?} in {directory:?}");
+
+                continue;
+            };
+
+            tracing::trace!("entry {name:?} in the segment map found");
+
+            if name == wal::FILE_NAME || name.starts_with(manifest::FILE_NAME) {
+                continue;
+            }
+
+            if let Some(id) = wal::sealed_id(name) {
+                listing.maximum_index = listing.maximum_index.max(id);
+                listing.sealed.push(id);
+
+                continue;
+            }
+
+            let path_index = match parse_index(name) {
+                Ok(index) if name.starts_with("seg-") || name.ends_with("-segment") => index,
+                _ => {
+                    tracing::warn!("ignoring unknown entry {name:?} in {directory:?}");
+
+                    continue;
+                }
+            };
+            listing.maximum_index = listing.maximum_index.max(path_index);
+
+            listing.segments.push((path_index, name.to_string()));
+        }
+
+        listing.segments.sort_unstable();
+        listing.sealed.sort_unstable();
+
+        Ok(listing)
+    }
 }
 
 impl TieredSegmentMap {
@@ -42,71 +219,186 @@ impl TieredSegmentMap {
             return Ok(Self {
                 directory,
                 counter: 0,
+                generation: 0,
+                incarnation: manifest::incarnation(),
+                checkpoint: 0,
                 disk: VecDeque::new(),
                 memory: VecDeque::new(),
+                wal: None,
+                flushing: false,
+                compacting: false,
+                dropped: false,
+                jobs: Arc::default(),
             });
         }
 
-        let mut iter = read_dir(&directory).await?;
-        let mut maximum_index = 0usize;
-        let mut disk_segments = VecDeque::new();
-
         tracing::trace!("opening {directory:?} as segment map");
 
-        while let Some(entry) = iter.next_entry().await? {
-            let name = entry.file_name();
-            let Some(name) = name.to_str() else {
-                return Err(SegmentMapError::UnknownFile);
-            };
-
-            tracing::trace!("entry {name:?} in the segment map found");
+        let listing = Listing::read(&directory).await?;
 
-            if !name.starts_with("seg-") {
-                return Err(SegmentMapError::UnknownFile);
+        let manifest = match manifest::Manifest::load(&directory).await? {
+            Some(manifest) if manifest.version > manifest::FORMAT_VERSION => {
+                return Err(SegmentMapError::UnsupportedVersion {
+                    version: manifest.version,
+                });
+            }
+            Some(manifest) => manifest,
+            None => {
+                tracing::debug!("no manifest in {directory:?}, adopting listed segments");
+
+                manifest::Manifest {
+                    version: manifest::FORMAT_VERSION,
+                    generation: 0,
+                    incarnation: manifest::incarnation(),
+                    counter: listing.maximum_index,
+                    checkpoint: 0,
+                    segments: listing
+                        .segments
+                        .iter()
+                        .map(|(_, name)| name.clone())
+                        .collect(),
+                }
             }
+        };
 
-            let path_index = name
-                .split('-')
-                .nth(1)
-                .expect("no index found in the segment name")
-                .parse::<usize>()
-                .map_err(|_| SegmentMapError::InvalidIndex)?;
+        for (_, name) in &listing.segments {
+            if !manifest.segments.contains(name) {
+                tracing::debug!("removing segment {name:?} missing from the manifest");
 
-            if maximum_index < path_index {
-                maximum_index = path_index + 1;
+                fs::remove_dir_all(directory.join(name)).await?;
             }
+        }
+
+        let mut disk_segments = VecDeque::new();
+
+        for name in &manifest.segments {
+            tracing::debug!("segment {name:?} found");
 
-            tracing::debug!("segment {path_index:?} found");
+            disk_segments.push_back(Arc::new(
+                disk::DiskSegment::open(directory.join(name)).await?,
+            ));
+        }
+
+        // sealed logs up to the checkpoint only lack the final cleanup
+        let mut pending = Vec::new();
 
-            disk_segments.push_back(disk::DiskSegment::open_or_create_segment(entry.path()).await?);
+        for id in listing.sealed {
+            if id <= manifest.checkpoint {
+                wal::remove_sealed(&directory, id).await?;
+            } else {
+                pending.push(id);
+            }
         }
 
+        let (wal, batches) = wal::WriteAheadLog::open(&directory, &pending).await?;
+
+        let memory = batches
+            .into_iter()
+            .map(|batch| {
+                Arc::new(match batch {
+                    wal::Batch::Insert(values) => CachedSegment::new(&values),
+                    wal::Batch::Delete(deletion) => CachedSegment::deletion(&deletion),
+                })
+            })
+            .collect::<VecDeque<_>>();
+
         tracing::trace!(
-            "created segment map with {:?} segments",
-            disk_segments.len()
+            "created segment map with {:?} segments and {:?} replayed memory segments",
+            disk_segments.len(),
+            memory.len(),
         );
 
-        Ok(Self {
+        let mut map = Self {
             directory,
-            counter: maximum_index,
-            memory: VecDeque::new(),
+            counter: manifest.counter.max(listing.maximum_index),
+            generation: manifest.generation,
+            incarnation: manifest.incarnation,
+            checkpoint: manifest.checkpoint,
+            memory,
             disk: disk_segments,
-        })
+            wal: Some(wal),
+            flushing: false,
+            compacting: false,
+            dropped: false,
+            jobs: Arc::default(),
+        };
+
+        if map.generation == 0 {
+            map.publish().await?;
+        }
+
+        Ok(map)
+    }
+
+    /// Publishes the current list of disk segments, making any change to it durable.
+    async fn publish(&mut self) -> Result<(), io::Error> {
+        manifest::Manifest {
+            version: manifest::FORMAT_VERSION,
+            generation: self.generation + 1,
+            incarnation: self.incarnation,
+            counter: self.counter,
+            checkpoint: self.checkpoint,
+            segments: self
+                .disk
+                .iter()
+                .map(|segment| segment_name(segment).to_string())
+                .collect(),
+        }
+        .publish(&self.directory)
+        .await?;
+
+        self.generation += 1;
+
+        Ok(())
+    }
+
+    async fn wal(&mut self) -> Result<&mut wal::WriteAheadLog, io::Error> {
+        if self.wal.is_none() {
+            fs::create_dir_all(&self.directory).await?;
+
+            let (wal, batches) = wal::WriteAheadLog::open(&self.directory, &[]).await?;
+            debug_assert!(batches.is_empty());
+
+            self.wal = Some(wal);
+
+            // the incarnation of a new partition is recorded before anything is written to it
+            if self.generation == 0 {
+                self.publish().await?;
+            }
+        }
+
+        Ok(self.wal.as_mut().unwrap())
+    }
+
+    fn next_index(&mut self) -> usize {
+        self.counter += 1;
+
+        self.counter
     }
 
     pub async fn insert<K: AsRef<str> + Ord + Eq, B: AsRef<str>>(
         &mut self,
         values: FxHashMap<K, Vec<B>>,
     ) -> Result<(), io::Error> {
-        let memory_segment = memory::CachedSegment::new(values);
+        let memory_segment = memory::CachedSegment::new(&values);
+
+        // disk segments have to stay older than every memory segment, so that the newer ones
+        // can be told apart when looking up tombstones
+        if memory_segment.values.len() > 4096 && self.memory.is_empty() && !self.flushing {
+            fs::create_dir_all(&self.directory).await?;
 
-        if memory_segment.values.len() > 4096 {
-            let disk_segment = self.write_segment(&memory_segment).await?;
-            self.disk.push_back(disk_segment);
+            let id = self.next_index();
+            let disk_segment = write_segment(&self.directory, id, async |segment| {
+                segment.flush_memory_segment(&memory_segment).await
+            })
+            .await?;
+            self.disk.push_back(Arc::new(disk_segment));
+            self.publish().await?;
 
             tracing::debug!("wrote disk segment");
         } else {
-            self.memory.push_back(memory_segment);
+            self.wal().await?.append(&values).await?;
+            self.memory.push_back(Arc::new(memory_segment));
 
             tracing::debug!("wrote memory segment");
         }
@@ -114,186 +406,1681 @@ impl TieredSegmentMap {
         Ok(())
     }
 
-    async fn write_segment(
+    /// Deletes the given values of every key, or the whole key for `None`, from the segments
+    /// written so far.
+    pub async fn delete<K: AsRef<str>, B: AsRef<str>>(
         &mut self,
-        memory_segment: &CachedSegment,
-    ) -> Result<disk::DiskSegment, io::Error> {
-        let path = {
-            self.counter += 1;
+        deletion: FxHashMap<K, Option<Vec<B>>>,
+    ) -> Result<(), io::Error> {
+        let memory_segment = memory::CachedSegment::deletion(&deletion);
+
+        self.wal().await?.append_deletion(&deletion).await?;
+        self.memory.push_back(Arc::new(memory_segment));
+
+        tracing::debug!("wrote tombstone segment");
+
+        Ok(())
+    }
 
-            self.directory.join(format!("{}-segment", self.counter))
+    pub fn should_flush(&self, policy: &FlushPolicy) -> bool {
+        let Some(oldest) = self.memory.front() else {
+            return false;
         };
 
-        tracing::debug!("issued segment write into: {path:?}");
+        self.memory.len() >= policy.max_segments
+            || self
+                .memory
+                .iter()
+                .map(|segment| segment.size)
+                .sum::<usize>()
+                >= policy.max_bytes
+            || oldest.created.elapsed() >= policy.max_age
+    }
+
+    /// Snapshots the memory segments for a flush, unless there are none or a flush or a
+    /// compaction is already in progress. Must be followed by [`Self::complete_flush`].
+    pub async fn prepare_flush(&mut self) -> Result<Option<FlushJob>, io::Error> {
+        if self.dropped || self.flushing || self.compacting || self.memory.is_empty() {
+            return Ok(None);
+        }
+
+        let Ok(running) = self.jobs.clone().try_read_owned() else {
+            return Ok(None);
+        };
 
-        fs::create_dir_all(&path).await?;
+        let id = self.next_index();
+        self.wal().await?.seal(id).await?;
 
-        let disk_segment = disk::DiskSegment::open_or_create_segment(path).await?;
-        disk_segment.flush_memory_segment(memory_segment).await?;
+        self.flushing = true;
 
-        tracing::trace!("persisted memory segment to disk: {:?}", disk_segment.directory);
+        tracing::debug!("preparing flush of {:?} memory segments", self.memory.len());
 
-        Ok(disk_segment)
+        Ok(Some(FlushJob {
+            id,
+            directory: self.directory.clone(),
+            segments: self.memory.iter().cloned().collect(),
+            purge: self.disk.is_empty(),
+            _running: running,
+        }))
     }
 
-    pub async fn find(
-        &self,
-        key: &str,
-        mut limit: Option<usize>,
-    ) -> Result<Vec<String>, disk::DiskResolutionError> {
-        let at_most = limit;
+    pub async fn complete_flush(
+        &mut self,
+        job: FlushJob,
+        result: Result<disk::DiskSegment, io::Error>,
+    ) -> Result<(), io::Error> {
+        self.flushing = false;
 
-        if let Some(0) = limit {
-            return Ok(vec![]);
+        if self.dropped {
+            return Ok(());
         }
 
-        let mut entries = vec![];
-
-        {
-            let mut memory = self.memory.iter();
-
-            while limit.map(|x| x > 0).unwrap_or(true) {
-                let Some(segment) = memory.next() else {
-                    break;
-                };
+        let disk_segment = result?;
 
-                tracing::trace!(segment = ?(segment as *const CachedSegment).addr(), "trying memory segment");
+        // larger inserts stay in memory while a flush is running, so nothing on disk is newer
+        self.disk.push_back(Arc::new(disk_segment));
 
-                let new = segment.find(key);
+        for segment in &job.segments {
+            let front = self.memory.pop_front();
+            debug_assert!(front.is_some_and(|front| Arc::ptr_eq(&front, segment)));
+        }
 
-                if let Some(value) = limit {
-                    limit = Some(value.saturating_sub(new.len()));
-                }
+        // every sealed log predates the job, so they are all covered by the new segment
+        self.checkpoint = job.id;
+        self.publish().await?;
 
-                entries.extend(new);
+        for id in Listing::read(&self.directory).await?.sealed {
+            if id <= job.id {
+                wal::remove_sealed(&self.directory, id).await?;
             }
         }
 
-        let mut disk = self.disk.iter();
+        tracing::debug!("flushed {:?} memory segments", job.segments.len());
 
-        while limit.map(|x| x > 0).unwrap_or(true) {
-            let Some(segment) = disk.next() else {
-                break;
-            };
+        Ok(())
+    }
 
-            tracing::trace!(segment = ?segment.directory, "trying disk segment");
+    /// Persists every memory segment as a disk segment, waiting for the write to finish.
+    pub async fn flush(&mut self) -> Result<(), io::Error> {
+        let Some(job) = self.prepare_flush().await? else {
+            return Ok(());
+        };
 
-            let new = segment.find(key).await?;
+        let result = job.run().await;
 
-            if let Some(value) = limit {
-                limit = Some(value.saturating_sub(new.len()));
-            }
+        self.complete_flush(job, result).await
+    }
 
-            entries.extend(new);
+    /// Snapshots a run of disk segments chosen by the policy for a compaction, unless a flush
+    /// or a compaction is already in progress. Must be followed by
+    /// [`Self::complete_compaction`].
+    pub async fn prepare_compaction(
+        &mut self,
+        policy: &CompactionPolicy,
+    ) -> Result<Option<CompactionJob>, io::Error> {
+        if self.dropped || self.flushing || self.compacting {
+            return Ok(None);
         }
 
-        if let Some(at_most) = at_most
-            && entries.len() > at_most
-        {
-            entries.drain(at_most..);
+        let mut sizes = Vec::with_capacity(self.disk.len());
+        for segment in &self.disk {
+            sizes.push(segment.size().await?);
         }
 
-        Ok(entries)
+        let Some(range) = policy.select(&sizes) else {
+            return Ok(None);
+        };
+
+        Ok(self.compaction_job(range))
     }
-}
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use tempfile::tempdir;
-    use tokio::fs;
+    /// Snapshots every disk segment for a compaction, as long as there are at least two.
+    pub fn prepare_full_compaction(&mut self) -> Option<CompactionJob> {
+        if self.dropped || self.flushing || self.compacting || self.disk.len() < 2 {
+            return None;
+        }
 
-    #[tokio::test]
-    async fn insert_and_find_in_memory_segment() {
-        let tmp = tempdir().unwrap();
-        let mut map = TieredSegmentMap {
-            directory: tmp.path().to_path_buf(),
-            counter: 0,
-            memory: VecDeque::new(),
-            disk: VecDeque::new(),
-        };
+        self.compaction_job(0..self.disk.len())
+    }
 
-        let mut entries = FxHashMap::default();
-        entries.insert("k1", vec!["v1", "v2"]);
+    fn compaction_job(&mut self, range: std::ops::Range<usize>) -> Option<CompactionJob> {
+        let running = self.jobs.clone().try_read_owned().ok()?;
 
-        map.insert(entries).await.unwrap();
+        self.compacting = true;
 
-        let found = map.find("k1", Some(10)).await.unwrap();
-        assert_eq!(found, ["v1", "v2"]);
+        tracing::debug!("preparing compaction of disk segments {range:?}");
+
+        Some(CompactionJob {
+            id: self.next_index(),
+            directory: self.directory.clone(),
+            purge: range.start == 0,
+            inputs: self.disk.range(range).cloned().collect(),
+            _running: running,
+        })
     }
 
-    #[tokio::test]
-    async fn insert_large_segment_goes_to_disk() {
-        let tmp = tempdir().unwrap();
-        fs::create_dir_all(tmp.path()).await.unwrap();
+    /// Swaps the compacted segment in place of its inputs and deletes them.
+    pub async fn complete_compaction(
+        &mut self,
+        job: CompactionJob,
+        result: Result<disk::DiskSegment, DiskResolutionError>,
+    ) -> Result<(), DiskResolutionError> {
+        self.compacting = false;
 
-        let mut map = TieredSegmentMap {
-            directory: tmp.path().to_path_buf(),
-            counter: 0,
-            memory: VecDeque::new(),
-            disk: VecDeque::new(),
-        };
+        if self.dropped {
+            return Ok(());
+        }
 
-        // simulate 4097 unique values -> should flush to disk
-        let values: Vec<String> = (0..4097).map(|i| format!("val{i}")).collect();
-        let refs: Vec<&str> = values.iter().map(|s| s.as_str()).collect();
+        let disk_segment = result?;
+
+        let position = self
+            .disk
+            .iter()
+            .position(|segment| Arc::ptr_eq(segment, &job.inputs[0]))
+            .expect("compaction inputs are only removed by the compaction itself");
+
+        let removed = self
+            .disk
+            .drain(position..position + job.inputs.len())
+            .collect::<Vec<_>>();
+        debug_assert!(
+            removed
+                .iter()
+                .zip(&job.inputs)
+                .all(|(removed, input)| Arc::ptr_eq(removed, input))
+        );
 
-        let mut entries = FxHashMap::default();
-        entries.insert("bigkey", refs.clone());
+        self.disk.insert(position, Arc::new(disk_segment));
+        self.publish().await?;
 
-        map.insert(entries).await.unwrap();
+        for input in &job.inputs {
+            tracing::trace!("removing compacted segment: {:?}", input.directory);
 
-        assert_eq!(map.find("bigkey", None).await.unwrap(), refs);
+            fs::remove_dir_all(&input.directory).await?;
+        }
 
-        // ensure no in-memory segments remain
-        assert!(map.memory.is_empty());
+        tracing::debug!("compacted {:?} disk segments", job.inputs.len());
 
-        // directory should contain flushed segment files
-        let mut entries = vec![];
-        let mut read_dir = fs::read_dir(tmp.path()).await.unwrap();
+        Ok(())
+    }
 
-        while let Some(next) = read_dir.next_entry().await.unwrap() {
-            entries.push(next);
+    /// Forgets every segment ahead of the removal of the directory. Background jobs still
+    /// running are discarded once they complete.
+    ///
+    /// The returned future resolves once they did, and doesn't borrow the map, which the jobs
+    /// need to lock to complete.
+    pub fn mark_dropped(&mut self) -> impl Future<Output = ()> + use<> {
+        self.dropped = true;
+        self.wal = None;
+        self.memory.clear();
+        self.disk.clear();
+
+        let jobs = self.jobs.clone();
+
+        async move {
+            drop(jobs.write().await);
         }
+    }
 
-        assert!(!entries.is_empty());
+    pub fn is_dropped(&self) -> bool {
+        self.dropped
     }
 
-    #[tokio::test]
-    async fn find_limits_results() {
-        let tmp = tempdir().unwrap();
-        let mut map = TieredSegmentMap {
-            directory: tmp.path().to_path_buf(),
-            counter: 0,
-            memory: VecDeque::new(),
-            disk: VecDeque::new(),
-        };
+    /// Tells the partition apart from the ones dropped from its directory before it.
+    pub fn incarnation(&self) -> u64 {
+        self.incarnation
+    }
 
-        let mut entries = FxHashMap::default();
-        entries.insert("key", vec!["v1", "v2", "v3"]);
+    /// Verifies the checksums of every disk segment, reporting the damaged ones.
+    ///
+    /// The returned future doesn't borrow the map, so the scan doesn't hold up inserts and
+    /// lookups.
+    pub fn verify(&self) -> impl Future<Output = Vec<DamagedSegment>> + use<> {
+        let segments = self.disk.iter().cloned().collect::<Vec<_>>();
 
-        map.insert(entries).await.unwrap();
+        async move {
+            let mut damaged = Vec::new();
 
-        let found = map.find("key", Some(2)).await.unwrap();
-        assert_eq!(found.len(), 2);
+            for segment in segments {
+                if let Err(error) = segment.verify().await {
+                    tracing::warn!("segment {:?} is damaged: {error}", segment.directory);
+
+                    damaged.push(DamagedSegment {
+                        directory: segment.directory.clone(),
+                        error,
+                    });
+                }
+            }
+
+            damaged
+        }
     }
 
-    #[tokio::test]
-    async fn find_nonexistent_returns_empty() {
-        let tmp = tempdir().unwrap();
-        let mut map = TieredSegmentMap {
-            directory: tmp.path().to_path_buf(),
-            counter: 0,
-            memory: VecDeque::new(),
-            disk: VecDeque::new(),
-        };
+    pub async fn stats(&self) -> Result<SegmentStats, io::Error> {
+        let mut disk_segments = Vec::with_capacity(self.disk.len());
 
-        let mut entries = FxHashMap::default();
-        entries.insert("exists", vec!["yes"]);
+        for segment in &self.disk {
+            disk_segments.push(DiskSegmentStats {
+                name: segment_name(segment).to_string(),
+                size: segment.size().await?,
+            });
+        }
 
-        map.insert(entries).await.unwrap();
+        Ok(SegmentStats {
+            generation: self.generation,
+            memory_segments: self.memory.len(),
+            memory_bytes: self.memory.iter().map(|segment| segment.size).sum(),
+            disk_segments,
+            flushing: self.flushing,
+            compacting: self.compacting,
+        })
+    }
 
-        let found = map.find("nope", Some(10)).await.unwrap();
-        assert!(found.is_empty());
+    /// Looks the key up, returning its values in order. Values deleted by segments newer than
+    /// the ones holding them are left out.
+    pub async fn find(
+        &self,
+        key: &str,
+        limit: Option<usize>,
+    ) -> Result<Vec<String>, DiskResolutionError> {
+        Ok(self.find_page(key, None, limit).await?.0)
+    }
+
+    /// Number of values [`Self::find`] would return for the key.
+    ///
+    /// Counted off the entries of the newest segment recording the key; values of older
+    /// segments are only resolved when a newer one may hold or delete them too.
+    pub async fn count(&self, key: &str) -> Result<usize, DiskResolutionError> {
+        self.count_up_to(key, None).await
+    }
+
+    /// Whether [`Self::find`] would return any value for the key.
+    pub async fn contains(&self, key: &str) -> Result<bool, DiskResolutionError> {
+        Ok(self.count_up_to(key, Some(1)).await? > 0)
+    }
+
+    async fn count_up_to(
+        &self,
+        key: &str,
+        limit: Option<usize>,
+    ) -> Result<usize, DiskResolutionError> {
+        let segments = self.segments();
+
+        // newer segments recording the key
+        let mut recording = Vec::new();
+        let mut count = 0;
+
+        for segment in &segments {
+            if limit.is_some_and(|limit| count >= limit) {
+                break;
+            }
+
+            if !segment.contains(key).await? {
+                continue;
+            }
+
+            if recording.is_empty() {
+                count += segment.count(key).await?;
+            } else {
+                let left = limit.map_or(usize::MAX, |limit| limit - count);
+                count += unrecorded(segment, &recording, key, left).await?;
+            }
+
+            if segment.is_key_deleted(key).await? {
+                break;
+            }
+
+            recording.push(segment);
+        }
+
+        Ok(limit.map_or(count, |limit| count.min(limit)))
+    }
+
+    /// Whether [`Self::find`] would return the value for the key, as told by the newest segment
+    /// recording anything about the pair.
+    pub async fn holds(&self, key: &str, value: &str) -> Result<bool, DiskResolutionError> {
+        for segment in self.segments() {
+            if let Some(held) = segment.holds(key, value).await? {
+                return Ok(held);
+            }
+        }
+
+        Ok(false)
+    }
+
+    /// Looks the key up like [`Self::find`], continuing after a previous page, and returns
+    /// where this page ended unless nothing is left.
+    ///
+    /// Every segment is read from the first value past the previous page on, so a page costs
+    /// about as much as the first one no matter how far in it is.
+    pub async fn find_page(
+        &self,
+        key: &str,
+        after: Option<Position>,
+        limit: Option<usize>,
+    ) -> Result<(Vec<String>, Option<Position>), DiskResolutionError> {
+        let mut entries = Vec::new();
+
+        if let Some(0) = limit {
+            return Ok((entries, after));
+        }
+
+        let after = after.map(|position| position.value);
+
+        let mut values = scan::Values::new(self.segments(), key, after.as_deref()).await?;
+
+        while let Some(value) = values.next().await? {
+            entries.push(value);
+
+            if limit == Some(entries.len()) {
+                let position = Position {
+                    value: entries[entries.len() - 1].clone(),
+                };
+
+                return Ok((entries, Some(position)));
+            }
+        }
+
+        tracing::trace!("found values: {:?}", entries.len());
+
+        Ok((entries, None))
+    }
+
+    /// Looks up every key starting with `prefix`, returning the keys left with values in key
+    /// order.
+    ///
+    /// Keys are merged off every segment one at a time, so only the ones up to the last value
+    /// within the limit are read.
+    pub async fn find_prefix(
+        &self,
+        prefix: &str,
+        limit: Option<usize>,
+    ) -> Result<Vec<(String, Vec<String>)>, DiskResolutionError> {
+        if let Some(0) = limit {
+            return Ok(Vec::new());
+        }
+
+        take_values(&mut self.prefix_scan(prefix).await?, limit).await
+    }
+
+    /// Scans the keys starting with `prefix`, which make up `[prefix, successor(prefix))`.
+    async fn prefix_scan(&self, prefix: &str) -> Result<scan::Scan<'_>, DiskResolutionError> {
+        let end = successor(prefix);
+
+        scan::Scan::new(self.segments(), prefix, end.as_deref(), Direction::Forward).await
+    }
+
+    /// Every segment, newest first.
+    fn segments(&self) -> Vec<scan::Segment<'_>> {
+        let mut segments = Vec::new();
+
+        segments.extend(
+            self.memory
+                .iter()
+                .rev()
+                .map(|segment| scan::Segment::Memory(segment)),
+        );
+        segments.extend(
+            self.disk
+                .iter()
+                .rev()
+                .map(|segment| scan::Segment::Disk(segment)),
+        );
+
+        segments
+    }
+
+    /// Looks up the keys `value` is indexed under, from the newest segment to the oldest, so
+    /// that keys deleting the value or deleted as a whole by newer segments are left out.
+    pub async fn find_keys_for_value(
+        &self,
+        value: &str,
+        limit: Option<usize>,
+    ) -> Result<Vec<String>, DiskResolutionError> {
+        let mut keys = Vec::new();
+
+        if let Some(0) = limit {
+            return Ok(keys);
+        }
+
+        let segments = self.segments();
+
+        // keys a newer segment either holds the value under or deletes it from
+        let mut decided = FxHashSet::default();
+
+        for (position, segment) in segments.iter().enumerate() {
+            let found = segment.find_keys_for_value(value).await?;
+
+            for key in found.values {
+                if !decided.insert(key.clone()) {
+                    continue;
+                }
+
+                let mut deleted = false;
+
+                for newer in &segments[..position] {
+                    if newer.is_key_deleted(&key).await? {
+                        deleted = true;
+                        break;
+                    }
+                }
+
+                if !deleted {
+                    keys.push(key);
+                }
+
+                if limit.is_some_and(|limit| keys.len() >= limit) {
+                    return Ok(keys);
+                }
+            }
+
+            decided.extend(found.tombstones);
+        }
+
+        tracing::trace!("found keys: {:?}", keys.len());
+
+        Ok(keys)
+    }
+
+    /// Scans the keys within `[start, end)`, or past `start` without an end, in the given
+    /// direction, returning up to `limit` keys left with values.
+    pub async fn scan(
+        &self,
+        start: &str,
+        end: Option<&str>,
+        direction: Direction,
+        limit: Option<usize>,
+    ) -> Result<Vec<(String, Vec<String>)>, DiskResolutionError> {
+        let segments = self.segments();
+
+        let mut scan = scan::Scan::new(segments, start, end, direction).await?;
+        let mut result = Vec::new();
+
+        while limit.is_none_or(|limit| result.len() < limit)
+            && let Some(item) = scan.next().await?
+        {
+            result.push(item);
+        }
+
+        tracing::trace!("scanned keys: {:?}", result.len());
+
+        Ok(result)
+    }
+}
+
+/// Whether any of the segments holds or deletes the value under the key.
+async fn recorded(
+    segments: &[&scan::Segment<'_>],
+    key: &str,
+    value: &str,
+) -> Result<bool, DiskResolutionError> {
+    for segment in segments {
+        if segment.holds(key, value).await?.is_some() {
+            return Ok(true);
+        }
+    }
+
+    Ok(false)
+}
+
+/// Number of values of the key held by the segment and recorded by none of the newer ones,
+/// read a window at a time and up to `limit`.
+async fn unrecorded(
+    segment: &scan::Segment<'_>,
+    recording: &[&scan::Segment<'_>],
+    key: &str,
+    limit: usize,
+) -> Result<usize, DiskResolutionError> {
+    let (mut count, mut offset, mut window) = (0, 0, scan::WINDOWS.start);
+
+    loop {
+        let found = segment.find_window(key, offset..offset + window).await?;
+
+        for value in &found.values {
+            if !recorded(recording, key, value).await? {
+                count += 1;
+
+                if count >= limit {
+                    return Ok(count);
+                }
+            }
+        }
+
+        if found.values.len() < window {
+            return Ok(count);
+        }
+
+        offset += window;
+        window = (window * 2).min(scan::WINDOWS.end);
+    }
+}
+
+/// Smallest string ordered after every string starting with `prefix`, unless there's none.
+///
+/// Strings order by their UTF-8 bytes, which is the order of their chars, so bumping the last
+/// char that can be bumped gives the end of the range.
+fn successor(prefix: &str) -> Option<String> {
+    let mut chars = prefix.chars().collect::<Vec<_>>();
+
+    while let Some(last) = chars.pop() {
+        // skips over the surrogates, which aren't chars
+        if let Some(next) = (last as u32 + 1..=char::MAX as u32).find_map(char::from_u32) {
+            chars.push(next);
+
+            return Some(chars.into_iter().collect());
+        }
+    }
+
+    None
+}
+
+/// Takes keys off a scan until `limit` values are taken, cutting the last key short.
+async fn take_values(
+    scan: &mut scan::Scan<'_>,
+    limit: Option<usize>,
+) -> Result<Vec<(String, Vec<String>)>, DiskResolutionError> {
+    let mut found = Vec::new();
+    let mut left = limit.unwrap_or(usize::MAX);
+
+    while left > 0
+        && let Some((key, mut values)) = scan.next().await?
+    {
+        values.truncate(left);
+        left -= values.len();
+
+        found.push((key, values));
+    }
+
+    Ok(found)
+}
+
+/// Adds the values of a segment that newer segments neither deleted nor held already,
+/// returning whether older segments can't contribute anything anymore.
+///
+/// Values are skipped once taken, so that a value indexed again in a later batch is only
+/// returned once.
+fn collect(
+    found: memory::Found,
+    entries: &mut Vec<String>,
+    skipped: &mut FxHashSet<String>,
+) -> bool {
+    entries.extend(
+        found
+            .values
+            .into_iter()
+            .filter(|value| skipped.insert(value.clone())),
+    );
+
+    skipped.extend(found.tombstones);
+
+    found.deleted
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures_util::FutureExt;
+    use tempfile::tempdir;
+    use tokio::fs;
+
+    async fn compact(map: &mut TieredSegmentMap, policy: Option<&CompactionPolicy>) -> bool {
+        let job = match policy {
+            Some(policy) => map.prepare_compaction(policy).await.unwrap(),
+            None => map.prepare_full_compaction(),
+        };
+
+        let Some(job) = job else {
+            return false;
+        };
+
+        let result = job.run().await;
+        map.complete_compaction(job, result).await.unwrap();
+
+        true
+    }
+
+    async fn insert_and_flush(map: &mut TieredSegmentMap, key: &str, values: &[&str]) {
+        let mut entries = FxHashMap::default();
+        entries.insert(key, values.to_vec());
+
+        map.insert(entries).await.unwrap();
+        map.flush().await.unwrap();
+    }
+
+    fn sorted<T: Clone + Ord>(values: &[T]) -> Vec<T> {
+        let mut values = values.to_vec();
+        values.sort();
+        values
+    }
+
+    #[tokio::test]
+    async fn insert_and_find_in_memory_segment() {
+        let tmp = tempdir().unwrap();
+        let mut map = TieredSegmentMap::new(tmp.path().to_path_buf())
+            .await
+            .unwrap();
+
+        let mut entries = FxHashMap::default();
+        entries.insert("k1", vec!["v1", "v2"]);
+
+        map.insert(entries).await.unwrap();
+
+        let found = map.find("k1", Some(10)).await.unwrap();
+        assert_eq!(found, ["v1", "v2"]);
+    }
+
+    #[tokio::test]
+    async fn insert_large_segment_goes_to_disk() {
+        let tmp = tempdir().unwrap();
+        fs::create_dir_all(tmp.path()).await.unwrap();
+
+        let mut map = TieredSegmentMap::new(tmp.path().to_path_buf())
+            .await
+            .unwrap();
+
+        // simulate 4097 unique values -> should flush to disk
+        let values: Vec<String> = (0..4097).map(|i| format!("val{i}")).collect();
+        let refs: Vec<&str> = values.iter().map(|s| s.as_str()).collect();
+
+        let mut entries = FxHashMap::default();
+        entries.insert("bigkey", refs.clone());
+
+        map.insert(entries).await.unwrap();
+
+        assert_eq!(map.find("bigkey", None).await.unwrap(), sorted(&refs));
+
+        // ensure no in-memory segments remain
+        assert!(map.memory.is_empty());
+
+        // directory should contain flushed segment files
+        let mut entries = vec![];
+        let mut read_dir = fs::read_dir(tmp.path()).await.unwrap();
+
+        while let Some(next) = read_dir.next_entry().await.unwrap() {
+            entries.push(next);
+        }
+
+        assert!(!entries.is_empty());
+    }
+
+    #[tokio::test]
+    async fn find_limits_results() {
+        let tmp = tempdir().unwrap();
+        let mut map = TieredSegmentMap::new(tmp.path().to_path_buf())
+            .await
+            .unwrap();
+
+        let mut entries = FxHashMap::default();
+        entries.insert("key", vec!["v1", "v2", "v3"]);
+
+        map.insert(entries).await.unwrap();
+
+        let found = map.find("key", Some(2)).await.unwrap();
+        assert_eq!(found.len(), 2);
+    }
+
+    #[tokio::test]
+    async fn find_nonexistent_returns_empty() {
+        let tmp = tempdir().unwrap();
+        let mut map = TieredSegmentMap::new(tmp.path().to_path_buf())
+            .await
+            .unwrap();
+
+        let mut entries = FxHashMap::default();
+        entries.insert("exists", vec!["yes"]);
+
+        map.insert(entries).await.unwrap();
+
+        let found = map.find("nope", Some(10)).await.unwrap();
+        assert!(found.is_empty());
+    }
+
+    #[tokio::test]
+    async fn memory_segments_are_replayed_after_reopen() {
+        let tmp = tempdir().unwrap();
+        let directory = tmp.path().join("partition");
+
+        {
+            let mut map = TieredSegmentMap::new(directory.clone()).await.unwrap();
+
+            let mut entries = FxHashMap::default();
+            entries.insert("k1", vec!["v1", "v2"]);
+            map.insert(entries).await.unwrap();
+
+            let mut entries = FxHashMap::default();
+            entries.insert("k2", vec!["v3"]);
+            map.insert(entries).await.unwrap();
+        }
+
+        let map = TieredSegmentMap::new(directory).await.unwrap();
+
+        assert_eq!(map.memory.len(), 2);
+        assert_eq!(map.find("k1", None).await.unwrap(), ["v1", "v2"]);
+        assert_eq!(map.find("k2", None).await.unwrap(), ["v3"]);
+    }
+
+    #[tokio::test]
+    async fn flush_persists_memory_segments_and_truncates_wal() {
+        let tmp = tempdir().unwrap();
+        let directory = tmp.path().join("partition");
+
+        {
+            let mut map = TieredSegmentMap::new(directory.clone()).await.unwrap();
+
+            let mut entries = FxHashMap::default();
+            entries.insert("k1", vec!["v1", "v2"]);
+            map.insert(entries).await.unwrap();
+
+            map.flush().await.unwrap();
+
+            assert!(map.memory.is_empty());
+            assert_eq!(map.disk.len(), 1);
+        }
+
+        let wal = fs::metadata(directory.join(wal::FILE_NAME)).await.unwrap();
+        assert_eq!(wal.len(), 0);
+
+        let map = TieredSegmentMap::new(directory).await.unwrap();
+
+        assert!(map.memory.is_empty());
+        assert_eq!(map.find("k1", None).await.unwrap(), ["v1", "v2"]);
+    }
+
+    #[tokio::test]
+    async fn flush_merges_memory_segments_into_one_disk_segment() {
+        let tmp = tempdir().unwrap();
+        let mut map = TieredSegmentMap::new(tmp.path().to_path_buf())
+            .await
+            .unwrap();
+
+        let mut entries = FxHashMap::default();
+        entries.insert("k1", vec!["v1"]);
+        map.insert(entries).await.unwrap();
+
+        let mut entries = FxHashMap::default();
+        entries.insert("k1", vec!["v2"]);
+        entries.insert("k2", vec!["v3"]);
+        map.insert(entries).await.unwrap();
+
+        map.flush().await.unwrap();
+
+        assert!(map.memory.is_empty());
+        assert_eq!(map.disk.len(), 1);
+
+        assert_eq!(map.find("k1", None).await.unwrap(), ["v1", "v2"]);
+        assert_eq!(map.find("k2", None).await.unwrap(), ["v3"]);
+
+        let mut read_dir = fs::read_dir(tmp.path()).await.unwrap();
+        while let Some(entry) = read_dir.next_entry().await.unwrap() {
+            let name = entry.file_name();
+            assert!(wal::sealed_id(name.to_str().unwrap()).is_none());
+        }
+    }
+
+    #[tokio::test]
+    async fn inserts_during_flush_stay_in_memory() {
+        let tmp = tempdir().unwrap();
+        let directory = tmp.path().join("partition");
+        let mut map = TieredSegmentMap::new(directory.clone()).await.unwrap();
+
+        let mut entries = FxHashMap::default();
+        entries.insert("k1", vec!["v1"]);
+        map.insert(entries).await.unwrap();
+
+        let job = map.prepare_flush().await.unwrap().unwrap();
+        assert!(map.prepare_flush().await.unwrap().is_none());
+
+        let mut entries = FxHashMap::default();
+        entries.insert("k2", vec!["v2"]);
+        map.insert(entries).await.unwrap();
+
+        let result = job.run().await;
+        assert_eq!(map.find("k1", None).await.unwrap(), ["v1"]);
+
+        map.complete_flush(job, result).await.unwrap();
+
+        assert_eq!(map.memory.len(), 1);
+        assert_eq!(map.disk.len(), 1);
+        drop(map);
+
+        // only the insert made after the flush started is left in the log
+        let map = TieredSegmentMap::new(directory).await.unwrap();
+
+        assert_eq!(map.memory.len(), 1);
+        assert_eq!(map.find("k1", None).await.unwrap(), ["v1"]);
+        assert_eq!(map.find("k2", None).await.unwrap(), ["v2"]);
+    }
+
+    #[tokio::test]
+    async fn drops_wait_for_running_jobs() {
+        let tmp = tempdir().unwrap();
+        let mut map = TieredSegmentMap::new(tmp.path().join("partition"))
+            .await
+            .unwrap();
+
+        let mut entries = FxHashMap::default();
+        entries.insert("k1", vec!["v1"]);
+        map.insert(entries).await.unwrap();
+
+        let job = map.prepare_flush().await.unwrap().unwrap();
+
+        let mut dropped = std::pin::pin!(map.mark_dropped());
+        assert!((&mut dropped).now_or_never().is_none());
+
+        // nothing new gets started in the meantime
+        insert_and_flush(&mut map, "k2", &["v2"]).await;
+        assert!(map.prepare_full_compaction().is_none());
+        assert!(map.disk.is_empty());
+
+        let result = job.run().await;
+        map.complete_flush(job, result).await.unwrap();
+
+        assert!(dropped.now_or_never().is_some());
+        assert!(map.disk.is_empty());
+    }
+
+    #[tokio::test]
+    async fn should_flush_follows_policy() {
+        let tmp = tempdir().unwrap();
+        let mut map = TieredSegmentMap::new(tmp.path().to_path_buf())
+            .await
+            .unwrap();
+
+        let policy = FlushPolicy {
+            max_segments: 2,
+            max_bytes: usize::MAX,
+            max_age: std::time::Duration::MAX,
+            ..FlushPolicy::default()
+        };
+
+        assert!(!map.should_flush(&policy));
+
+        let mut entries = FxHashMap::default();
+        entries.insert("k1", vec!["v1"]);
+        map.insert(entries).await.unwrap();
+
+        assert!(!map.should_flush(&policy));
+        assert!(map.should_flush(&FlushPolicy {
+            max_bytes: 1,
+            ..policy.clone()
+        }));
+        assert!(map.should_flush(&FlushPolicy {
+            max_age: std::time::Duration::ZERO,
+            ..policy.clone()
+        }));
+
+        let mut entries = FxHashMap::default();
+        entries.insert("k2", vec!["v2"]);
+        map.insert(entries).await.unwrap();
+
+        assert!(map.should_flush(&policy));
+    }
+
+    #[tokio::test]
+    async fn full_compaction_merges_disk_segments() {
+        let tmp = tempdir().unwrap();
+        let directory = tmp.path().join("partition");
+        let mut map = TieredSegmentMap::new(directory.clone()).await.unwrap();
+
+        assert!(!compact(&mut map, None).await);
+
+        insert_and_flush(&mut map, "k1", &["v1", "v2"]).await;
+        insert_and_flush(&mut map, "k2", &["v3"]).await;
+        insert_and_flush(&mut map, "k1", &["v2", "v4"]).await;
+
+        assert_eq!(map.disk.len(), 3);
+
+        let inputs = map
+            .disk
+            .iter()
+            .map(|segment| segment.directory.clone())
+            .collect::<Vec<_>>();
+
+        assert!(compact(&mut map, None).await);
+
+        assert_eq!(map.disk.len(), 1);
+        assert_eq!(map.find("k1", None).await.unwrap(), ["v1", "v2", "v4"]);
+        assert_eq!(map.find("k2", None).await.unwrap(), ["v3"]);
+        assert!(map.find("k3", None).await.unwrap().is_empty());
+
+        for input in inputs {
+            assert!(!fs::try_exists(input).await.unwrap());
+        }
+
+        drop(map);
+
+        let map = TieredSegmentMap::new(directory).await.unwrap();
+
+        assert_eq!(map.disk.len(), 1);
+        assert_eq!(map.find("k1", None).await.unwrap(), ["v1", "v2", "v4"]);
+    }
+
+    #[tokio::test]
+    async fn policy_compaction_merges_similar_segments_only() {
+        let tmp = tempdir().unwrap();
+        let mut map = TieredSegmentMap::new(tmp.path().to_path_buf())
+            .await
+            .unwrap();
+
+        let values: Vec<String> = (0..4097).map(|i| format!("val{i}")).collect();
+        let refs: Vec<&str> = values.iter().map(|s| s.as_str()).collect();
+
+        let mut entries = FxHashMap::default();
+        entries.insert("big", refs.clone());
+        map.insert(entries).await.unwrap();
+
+        for index in 0..3 {
+            insert_and_flush(&mut map, &format!("k{index}"), &["v"]).await;
+        }
+
+        let policy = CompactionPolicy {
+            min_segments: 3,
+            ..CompactionPolicy::default()
+        };
+
+        assert!(compact(&mut map, Some(&policy)).await);
+        assert_eq!(map.disk.len(), 2);
+        assert!(!compact(&mut map, Some(&policy)).await);
+
+        assert_eq!(map.find("big", None).await.unwrap(), sorted(&refs));
+        for index in 0..3 {
+            assert_eq!(map.find(&format!("k{index}"), None).await.unwrap(), ["v"]);
+        }
+    }
+
+    #[tokio::test]
+    async fn segments_missing_from_manifest_are_removed() {
+        let tmp = tempdir().unwrap();
+        let directory = tmp.path().join("partition");
+
+        {
+            let mut map = TieredSegmentMap::new(directory.clone()).await.unwrap();
+            insert_and_flush(&mut map, "k1", &["v1"]).await;
+        }
+
+        // a segment write interrupted before publishing and an unrelated file
+        fs::create_dir_all(directory.join("seg-42")).await.unwrap();
+        fs::write(directory.join("seg-42").join("keys.data.bin"), b"garbage")
+            .await
+            .unwrap();
+        fs::write(directory.join("notes.txt"), b"hello")
+            .await
+            .unwrap();
+
+        let mut map = TieredSegmentMap::new(directory.clone()).await.unwrap();
+
+        assert_eq!(map.disk.len(), 1);
+        assert!(!fs::try_exists(directory.join("seg-42")).await.unwrap());
+        assert_eq!(map.find("k1", None).await.unwrap(), ["v1"]);
+
+        // indices are never reused, even those of removed segments
+        insert_and_flush(&mut map, "k2", &["v2"]).await;
+        assert!(parse_index(segment_name(&map.disk[1])).unwrap() > 42);
+    }
+
+    #[tokio::test]
+    async fn sealed_logs_behind_checkpoint_are_not_replayed() {
+        let tmp = tempdir().unwrap();
+        let directory = tmp.path().join("partition");
+
+        {
+            let mut map = TieredSegmentMap::new(directory.clone()).await.unwrap();
+
+            let mut entries = FxHashMap::default();
+            entries.insert("k1", vec!["v1"]);
+            map.insert(entries).await.unwrap();
+
+            let job = map.prepare_flush().await.unwrap().unwrap();
+            let result = job.run().await;
+
+            let sealed = directory.join(format!("wal-{}.bin", job.id));
+            let backup = fs::read(&sealed).await.unwrap();
+
+            map.complete_flush(job, result).await.unwrap();
+
+            // as if the process died right after publishing the manifest
+            fs::write(&sealed, backup).await.unwrap();
+        }
+
+        let map = TieredSegmentMap::new(directory).await.unwrap();
+
+        assert!(map.memory.is_empty());
+        assert_eq!(map.find("k1", None).await.unwrap(), ["v1"]);
+    }
+
+    #[tokio::test]
+    async fn segments_without_manifest_are_adopted() {
+        let tmp = tempdir().unwrap();
+        let directory = tmp.path().join("partition");
+
+        {
+            let mut map = TieredSegmentMap::new(directory.clone()).await.unwrap();
+            insert_and_flush(&mut map, "k1", &["v1"]).await;
+            insert_and_flush(&mut map, "k2", &["v2"]).await;
+        }
+
+        fs::remove_file(directory.join(manifest::FILE_NAME))
+            .await
+            .unwrap();
+
+        let map = TieredSegmentMap::new(directory.clone()).await.unwrap();
+
+        assert_eq!(map.disk.len(), 2);
+        assert_eq!(map.find("k1", None).await.unwrap(), ["v1"]);
+        assert_eq!(map.find("k2", None).await.unwrap(), ["v2"]);
+
+        let manifest = manifest::Manifest::load(&directory).await.unwrap().unwrap();
+        assert_eq!(manifest.segments.len(), 2);
+    }
+
+    #[tokio::test]
+    async fn newer_manifest_versions_are_rejected() {
+        let tmp = tempdir().unwrap();
+
+        manifest::Manifest {
+            version: manifest::FORMAT_VERSION + 1,
+            generation: 1,
+            incarnation: 1,
+            counter: 0,
+            checkpoint: 0,
+            segments: vec![],
+        }
+        .publish(tmp.path())
+        .await
+        .unwrap();
+
+        assert!(matches!(
+            TieredSegmentMap::new(tmp.path().to_path_buf()).await,
+            Err(SegmentMapError::UnsupportedVersion { .. })
+        ));
+    }
+
+    #[tokio::test]
+    async fn verify_reports_damaged_segments() {
+        let tmp = tempdir().unwrap();
+        let mut map = TieredSegmentMap::new(tmp.path().to_path_buf())
+            .await
+            .unwrap();
+
+        insert_and_flush(&mut map, "k1", &["v1"]).await;
+        insert_and_flush(&mut map, "k2", &["v2"]).await;
+
+        assert!(map.verify().await.is_empty());
+
+        let damaged = map.disk[1].directory.join("values.data.bin");
+        let mut buffer = fs::read(&damaged).await.unwrap();
+        *buffer.last_mut().unwrap() ^= 0b1;
+        fs::write(&damaged, buffer).await.unwrap();
+
+        let report = map.verify().await;
+
+        assert_eq!(report.len(), 1);
+        assert_eq!(report[0].directory, map.disk[1].directory);
+        assert!(matches!(
+            report[0].error,
+            DiskResolutionError::Corrupted { .. }
+        ));
+    }
+
+    fn deletion<'a>(
+        entries: &[(&'a str, Option<&[&'a str]>)],
+    ) -> FxHashMap<&'a str, Option<Vec<&'a str>>> {
+        entries
+            .iter()
+            .map(|&(key, values)| (key, values.map(<[_]>::to_vec)))
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn deletes_shadow_older_segments() {
+        let tmp = tempdir().unwrap();
+        let directory = tmp.path().join("partition");
+
+        {
+            let mut map = TieredSegmentMap::new(directory.clone()).await.unwrap();
+
+            insert_and_flush(&mut map, "k1", &["v1", "v2", "v3"]).await;
+            insert_and_flush(&mut map, "k2", &["v4"]).await;
+
+            map.delete(deletion(&[("k1", Some(&["v2"])), ("k2", None)]))
+                .await
+                .unwrap();
+
+            assert_eq!(map.find("k1", None).await.unwrap(), ["v1", "v3"]);
+            assert!(map.find("k2", None).await.unwrap().is_empty());
+
+            // inserts newer than a tombstone are visible again
+            let mut entries = FxHashMap::default();
+            entries.insert("k2", vec!["v5"]);
+            map.insert(entries).await.unwrap();
+
+            assert_eq!(map.find("k2", None).await.unwrap(), ["v5"]);
+        }
+
+        // tombstones are replayed from the log, and survive a flush
+        let mut map = TieredSegmentMap::new(directory.clone()).await.unwrap();
+
+        assert_eq!(map.find("k1", None).await.unwrap(), ["v1", "v3"]);
+        assert_eq!(map.find("k2", None).await.unwrap(), ["v5"]);
+
+        map.flush().await.unwrap();
+
+        assert_eq!(map.disk.len(), 3);
+        assert_eq!(map.find("k1", None).await.unwrap(), ["v1", "v3"]);
+        assert_eq!(map.find("k2", None).await.unwrap(), ["v5"]);
+        assert_eq!(map.find("k1", Some(1)).await.unwrap(), ["v1"]);
+    }
+
+    #[tokio::test]
+    async fn find_prefix_merges_every_segment() {
+        let tmp = tempdir().unwrap();
+        let mut map = TieredSegmentMap::new(tmp.path().to_path_buf())
+            .await
+            .unwrap();
+
+        insert_and_flush(&mut map, "user:1:email", &["e1", "e2"]).await;
+        insert_and_flush(&mut map, "user:2:email", &["e3"]).await;
+        insert_and_flush(&mut map, "user:1:name", &["n1"]).await;
+
+        map.delete(deletion(&[
+            ("user:1:email", Some(&["e1"])),
+            ("user:1:name", None),
+        ]))
+        .await
+        .unwrap();
+
+        let mut entries = FxHashMap::default();
+        entries.insert("user:1:age", vec!["a1"]);
+        entries.insert("user:1:email", vec!["e4"]);
+        map.insert(entries).await.unwrap();
+
+        let expected = [
+            ("user:1:age".to_string(), vec!["a1".to_string()]),
+            (
+                "user:1:email".to_string(),
+                vec!["e2".to_string(), "e4".to_string()],
+            ),
+        ];
+
+        assert_eq!(map.find_prefix("user:1:", None).await.unwrap(), expected);
+        assert_eq!(
+            map.find_prefix("user:1:", Some(2)).await.unwrap(),
+            [
+                ("user:1:age".to_string(), vec!["a1".to_string()]),
+                ("user:1:email".to_string(), vec!["e2".to_string()]),
+            ]
+        );
+        assert_eq!(map.find_prefix("user:", None).await.unwrap().len(), 3);
+        assert!(
+            map.find_prefix("user:1:", Some(0))
+                .await
+                .unwrap()
+                .is_empty()
+        );
+
+        map.flush().await.unwrap();
+
+        assert_eq!(map.find_prefix("user:1:", None).await.unwrap(), expected);
+    }
+
+    #[tokio::test]
+    async fn limited_prefix_lookups_stop_at_the_limit() {
+        let tmp = tempdir().unwrap();
+        let mut map = TieredSegmentMap::new(tmp.path().to_path_buf())
+            .await
+            .unwrap();
+
+        let keys = (0..100).map(|i| format!("k{i:03}")).collect::<Vec<_>>();
+
+        for (batch, value) in ["a", "b", "c"].into_iter().enumerate() {
+            let entries = keys
+                .iter()
+                .map(|key| (key.as_str(), vec![value]))
+                .collect::<FxHashMap<_, _>>();
+
+            map.insert(entries).await.unwrap();
+
+            if batch < 2 {
+                map.flush().await.unwrap();
+            }
+        }
+
+        assert_eq!(
+            map.find_prefix("", Some(1)).await.unwrap(),
+            [("k000".to_string(), vec!["a".to_string()])]
+        );
+        assert_eq!(map.find_prefix("k0", Some(4)).await.unwrap().len(), 2);
+
+        // only the first key of each of the segments is resolved
+        let mut scan = map.prefix_scan("").await.unwrap();
+        take_values(&mut scan, Some(1)).await.unwrap();
+
+        assert_eq!(scan.resolved, 3);
+    }
+
+    #[test]
+    fn successors_bound_prefixes() {
+        assert_eq!(successor("user:1:").as_deref(), Some("user:1;"));
+        assert_eq!(successor("a\u{10FFFF}").as_deref(), Some("b"));
+        assert_eq!(successor("\u{D7FF}").as_deref(), Some("\u{E000}"));
+        assert_eq!(successor("\u{10FFFF}"), None);
+        assert_eq!(successor(""), None);
+    }
+
+    #[tokio::test]
+    async fn scans_merge_every_segment_in_both_directions() {
+        let tmp = tempdir().unwrap();
+        let mut map = TieredSegmentMap::new(tmp.path().to_path_buf())
+            .await
+            .unwrap();
+
+        insert_and_flush(&mut map, "2026-10-16T10", &["a"]).await;
+        insert_and_flush(&mut map, "2026-10-16T12", &["b", "c"]).await;
+        insert_and_flush(&mut map, "2026-10-16T14", &["d"]).await;
+
+        map.delete(deletion(&[
+            ("2026-10-16T10", None),
+            ("2026-10-16T12", Some(&["b"])),
+        ]))
+        .await
+        .unwrap();
+
+        let mut entries = FxHashMap::default();
+        entries.insert("2026-10-16T11", vec!["e"]);
+        entries.insert("2026-10-16T14", vec!["f"]);
+        entries.insert("2026-10-17T00", vec!["g"]);
+        map.insert(entries).await.unwrap();
+
+        let keys = |scanned: Vec<(String, Vec<String>)>| {
+            scanned
+                .into_iter()
+                .map(|(key, values)| format!("{key}={}", values.join(",")))
+                .collect::<Vec<_>>()
+        };
+
+        let forward = map
+            .scan("2026-10-16", Some("2026-10-17"), Direction::Forward, None)
+            .await
+            .unwrap();
+
+        assert_eq!(
+            keys(forward),
+            ["2026-10-16T11=e", "2026-10-16T12=c", "2026-10-16T14=d,f"]
+        );
+
+        let reverse = map
+            .scan("2026-10-16T11", None, Direction::Reverse, Some(2))
+            .await
+            .unwrap();
+
+        assert_eq!(keys(reverse), ["2026-10-17T00=g", "2026-10-16T14=d,f"]);
+
+        // the end is exclusive
+        let bounded = map
+            .scan(
+                "2026-10-16T12",
+                Some("2026-10-16T14"),
+                Direction::Forward,
+                None,
+            )
+            .await
+            .unwrap();
+
+        assert_eq!(keys(bounded), ["2026-10-16T12=c"]);
+        assert!(
+            map.scan("b", Some("a"), Direction::Forward, None)
+                .await
+                .unwrap()
+                .is_empty()
+        );
+
+        map.flush().await.unwrap();
+
+        let all = map.scan("", None, Direction::Forward, None).await.unwrap();
+
+        assert_eq!(
+            keys(all),
+            [
+                "2026-10-16T11=e",
+                "2026-10-16T12=c",
+                "2026-10-16T14=d,f",
+                "2026-10-17T00=g"
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn find_pages_continue_where_the_previous_one_ended() {
+        let tmp = tempdir().unwrap();
+        let mut map = TieredSegmentMap::new(tmp.path().to_path_buf())
+            .await
+            .unwrap();
+
+        insert_and_flush(&mut map, "k", &["a", "b", "c"]).await;
+        insert_and_flush(&mut map, "k", &["d", "e"]).await;
+
+        map.delete(deletion(&[("k", Some(&["b"]))])).await.unwrap();
+
+        let mut entries = FxHashMap::default();
+        entries.insert("k", vec!["f"]);
+        map.insert(entries).await.unwrap();
+
+        let all = map.find("k", None).await.unwrap();
+        assert_eq!(all, ["a", "c", "d", "e", "f"]);
+
+        let mut pages = Vec::new();
+        let mut after = None;
+
+        loop {
+            let (page, next) = map.find_page("k", after, Some(2)).await.unwrap();
+            pages.extend(page);
+
+            if next.is_none() {
+                break;
+            }
+            after = next;
+        }
+
+        assert_eq!(pages, all);
+
+        // the segments change in between, and the second page still starts past the first
+        let (first, after) = map.find_page("k", None, Some(3)).await.unwrap();
+        map.flush().await.unwrap();
+        let (second, after) = map.find_page("k", after, Some(3)).await.unwrap();
+
+        assert_eq!([first, second].concat(), all);
+        assert!(after.is_none());
+    }
+
+    #[tokio::test]
+    async fn values_are_returned_in_value_order() {
+        let tmp = tempdir().unwrap();
+        let mut map = TieredSegmentMap::new(tmp.path().to_path_buf())
+            .await
+            .unwrap();
+
+        let mut entries = FxHashMap::default();
+        entries.insert("k", vec!["val10", "val9", "val1"]);
+        map.insert(entries).await.unwrap();
+
+        let expected = ["val1", "val10", "val9"];
+        assert_eq!(map.find("k", None).await.unwrap(), expected);
+
+        map.flush().await.unwrap();
+        assert_eq!(map.find("k", None).await.unwrap(), expected);
+
+        insert_and_flush(&mut map, "k", &["val0", "val2"]).await;
+        assert_eq!(
+            map.find("k", None).await.unwrap(),
+            ["val0", "val1", "val10", "val2", "val9"]
+        );
+    }
+
+    #[tokio::test]
+    async fn values_keep_their_order_through_compaction() {
+        let tmp = tempdir().unwrap();
+        let mut map = TieredSegmentMap::new(tmp.path().to_path_buf())
+            .await
+            .unwrap();
+
+        insert_and_flush(&mut map, "k", &["v4", "v2", "v0"]).await;
+        insert_and_flush(&mut map, "k", &["v1", "v5"]).await;
+        insert_and_flush(&mut map, "k", &["v3", "v2"]).await;
+
+        map.delete(deletion(&[("k", Some(&["v0"]))])).await.unwrap();
+        map.flush().await.unwrap();
+
+        let before = map.find("k", None).await.unwrap();
+        assert_eq!(before, ["v1", "v2", "v3", "v4", "v5"]);
+
+        let (first, after) = map.find_page("k", None, Some(2)).await.unwrap();
+
+        assert!(compact(&mut map, None).await);
+        assert_eq!(map.disk.len(), 1);
+
+        assert_eq!(map.find("k", None).await.unwrap(), before);
+
+        // a page started before the compaction carries on where it ended
+        let (second, after) = map.find_page("k", after, Some(2)).await.unwrap();
+        let (third, after) = map.find_page("k", after, Some(2)).await.unwrap();
+
+        assert_eq!([first, second, third].concat(), before);
+        assert!(after.is_none());
+    }
+
+    #[tokio::test]
+    async fn values_indexed_again_are_returned_once() {
+        let tmp = tempdir().unwrap();
+        let mut map = TieredSegmentMap::new(tmp.path().to_path_buf())
+            .await
+            .unwrap();
+
+        insert_and_flush(&mut map, "k", &["a", "b"]).await;
+        insert_and_flush(&mut map, "k", &["b", "c"]).await;
+
+        let mut entries = FxHashMap::default();
+        entries.insert("k", vec!["c", "a", "d"]);
+        map.insert(entries).await.unwrap();
+
+        assert_eq!(map.find("k", None).await.unwrap(), ["a", "b", "c", "d"]);
+
+        // the limit counts distinct values
+        assert_eq!(map.find("k", Some(4)).await.unwrap(), ["a", "b", "c", "d"]);
+
+        let (first, after) = map.find_page("k", None, Some(3)).await.unwrap();
+        let (second, after) = map.find_page("k", after, Some(3)).await.unwrap();
+
+        assert_eq!(first, ["a", "b", "c"]);
+        assert_eq!(second, ["d"]);
+        assert!(after.is_none());
+
+        assert_eq!(
+            map.find_prefix("k", None).await.unwrap(),
+            [(
+                "k".to_string(),
+                vec!["a", "b", "c", "d"]
+                    .into_iter()
+                    .map(String::from)
+                    .collect()
+            )]
+        );
+        assert_eq!(
+            map.scan("", None, Direction::Forward, None).await.unwrap(),
+            map.find_prefix("", None).await.unwrap()
+        );
+
+        assert!(map.holds("k", "b").await.unwrap());
+        assert!(!map.holds("k", "e").await.unwrap());
+        assert!(!map.holds("j", "a").await.unwrap());
+
+        map.delete(deletion(&[("k", Some(&["b"]))])).await.unwrap();
+        assert!(!map.holds("k", "b").await.unwrap());
+
+        map.delete(deletion(&[("k", None)])).await.unwrap();
+        assert!(!map.holds("k", "a").await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn counts_match_found_values() {
+        let tmp = tempdir().unwrap();
+        let mut map = TieredSegmentMap::new(tmp.path().to_path_buf())
+            .await
+            .unwrap();
+
+        insert_and_flush(&mut map, "k1", &["a", "b", "c"]).await;
+        insert_and_flush(&mut map, "k2", &["a"]).await;
+        insert_and_flush(&mut map, "k1", &["c", "d"]).await;
+
+        map.delete(deletion(&[("k1", Some(&["a"])), ("k2", None)]))
+            .await
+            .unwrap();
+
+        let mut entries = FxHashMap::default();
+        entries.insert("k3", vec!["e"]);
+        map.insert(entries).await.unwrap();
+
+        let expected = [("k1", 3), ("k2", 0), ("k3", 1), ("k4", 0)];
+
+        for flushed in [false, true] {
+            if flushed {
+                map.flush().await.unwrap();
+            }
+
+            for (key, count) in expected {
+                assert_eq!(
+                    map.find(key, None).await.unwrap().len(),
+                    count,
+                    "{key}, flushed: {flushed}"
+                );
+                assert_eq!(
+                    map.count(key).await.unwrap(),
+                    count,
+                    "{key}, flushed: {flushed}"
+                );
+                assert_eq!(
+                    map.contains(key).await.unwrap(),
+                    count > 0,
+                    "{key}, flushed: {flushed}"
+                );
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn counts_read_older_segments_in_windows() {
+        let tmp = tempdir().unwrap();
+        let mut map = TieredSegmentMap::new(tmp.path().to_path_buf())
+            .await
+            .unwrap();
+
+        let values = (0..100).map(|i| format!("v{i:03}")).collect::<Vec<_>>();
+        let values = values.iter().map(String::as_str).collect::<Vec<_>>();
+
+        insert_and_flush(&mut map, "k", &values).await;
+        insert_and_flush(&mut map, "k", &values[40..60]).await;
+
+        // the newest segment only deletes, so every count is read off the older ones
+        map.delete(deletion(&[("k", Some(&values[..30]))]))
+            .await
+            .unwrap();
+
+        assert_eq!(map.count("k").await.unwrap(), 70);
+        assert_eq!(map.find("k", None).await.unwrap().len(), 70);
+        assert!(map.contains("k").await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn finds_keys_for_values_across_segments() {
+        let tmp = tempdir().unwrap();
+        let mut map = TieredSegmentMap::new(tmp.path().to_path_buf())
+            .await
+            .unwrap();
+
+        insert_and_flush(&mut map, "k1", &["v"]).await;
+        insert_and_flush(&mut map, "k2", &["v"]).await;
+        insert_and_flush(&mut map, "k3", &["v"]).await;
+        insert_and_flush(&mut map, "k4", &["v", "w"]).await;
+
+        map.delete(deletion(&[("k1", Some(&["v"])), ("k2", None)]))
+            .await
+            .unwrap();
+
+        let mut entries = FxHashMap::default();
+        entries.insert("k2", vec!["w"]);
+        entries.insert("k5", vec!["v"]);
+        map.insert(entries).await.unwrap();
+
+        let sorted = |mut keys: Vec<String>| {
+            keys.sort();
+            keys
+        };
+
+        for flushed in [false, true] {
+            if flushed {
+                map.flush().await.unwrap();
+            }
+
+            assert_eq!(
+                sorted(map.find_keys_for_value("v", None).await.unwrap()),
+                ["k3", "k4", "k5"],
+                "flushed: {flushed}"
+            );
+            assert_eq!(
+                sorted(map.find_keys_for_value("w", None).await.unwrap()),
+                ["k2", "k4"],
+                "flushed: {flushed}"
+            );
+            assert_eq!(
+                map.find_keys_for_value("v", Some(2)).await.unwrap().len(),
+                2
+            );
+            assert!(
+                map.find_keys_for_value("missing", None)
+                    .await
+                    .unwrap()
+                    .is_empty()
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn compaction_purges_tombstones_with_oldest_segment() {
+        let tmp = tempdir().unwrap();
+        let mut map = TieredSegmentMap::new(tmp.path().to_path_buf())
+            .await
+            .unwrap();
+
+        insert_and_flush(&mut map, "k1", &["v1", "v2"]).await;
+        insert_and_flush(&mut map, "k2", &["v3"]).await;
+
+        map.delete(deletion(&[("k1", Some(&["v1"])), ("k2", None)]))
+            .await
+            .unwrap();
+        map.flush().await.unwrap();
+
+        // without the oldest segment, tombstones have to be kept
+        let job = map.compaction_job(1..3).unwrap();
+        let result = job.run().await;
+        map.complete_compaction(job, result).await.unwrap();
+
+        let (_, _, entries) = map.disk[1].load_tables().await.unwrap();
+        assert!(
+            entries
+                .iter()
+                .any(|&(_, value)| value == memory::KEY_TOMBSTONE)
+        );
+
+        assert_eq!(map.find("k1", None).await.unwrap(), ["v2"]);
+        assert!(map.find("k2", None).await.unwrap().is_empty());
+
+        assert!(compact(&mut map, None).await);
+
+        let (keys, values, entries) = map.disk[0].load_tables().await.unwrap();
+        assert_eq!(keys.len(), 1);
+        assert_eq!(values.len(), 1);
+        assert_eq!(entries, [(0, 0)]);
+
+        assert_eq!(map.find("k1", None).await.unwrap(), ["v2"]);
+        assert!(map.find("k2", None).await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn large_inserts_stay_behind_memory_segments() {
+        let tmp = tempdir().unwrap();
+        let mut map = TieredSegmentMap::new(tmp.path().to_path_buf())
+            .await
+            .unwrap();
+
+        map.delete(deletion(&[("big", None)])).await.unwrap();
+
+        let values = (0..4097).map(|i| format!("val{i}")).collect::<Vec<_>>();
+
+        let mut entries = FxHashMap::default();
+        entries.insert("big", values.clone());
+        map.insert(entries).await.unwrap();
+
+        // queued behind the tombstone rather than written to disk ahead of it
+        assert!(map.disk.is_empty());
+        assert_eq!(map.find("big", None).await.unwrap(), sorted(&values));
     }
 }
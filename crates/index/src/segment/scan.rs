@@ -0,0 +1,396 @@
+use fxhash::FxHashSet;
+use std::{
+    cmp::{Ordering, Reverse},
+    collections::{BinaryHeap, VecDeque},
+    ops::Range,
+};
+
+use super::{
+    disk::{DiskResolutionError, DiskSegment},
+    memory::{CachedSegment, Found},
+};
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Direction {
+    #[default]
+    Forward,
+    Reverse,
+}
+
+pub(super) enum Segment<'segment> {
+    Memory(&'segment CachedSegment),
+    Disk(&'segment DiskSegment),
+}
+
+impl Segment<'_> {
+    async fn key_range(
+        &self,
+        start: &str,
+        end: Option<&str>,
+    ) -> Result<Range<u32>, DiskResolutionError> {
+        match self {
+            Self::Memory(segment) => Ok(segment.key_range(start, end)),
+            Self::Disk(segment) => segment.key_range(start, end).await,
+        }
+    }
+
+    async fn key(&self, index: u32) -> Result<String, DiskResolutionError> {
+        match self {
+            Self::Memory(segment) => Ok(segment.key(index)),
+            Self::Disk(segment) => segment.key(index).await,
+        }
+    }
+
+    async fn resolve_key(&self, index: u32) -> Result<Found, DiskResolutionError> {
+        match self {
+            Self::Memory(segment) => Ok(segment.resolve_key(index)),
+            Self::Disk(segment) => segment.resolve_key(index).await,
+        }
+    }
+
+    pub(super) async fn find_window(
+        &self,
+        key: &str,
+        window: Range<usize>,
+    ) -> Result<Found, DiskResolutionError> {
+        match self {
+            Self::Memory(segment) => Ok(segment.find_window(key, window)),
+            Self::Disk(segment) => segment.find_window(key, window).await,
+        }
+    }
+
+    pub(super) async fn find_keys_for_value(
+        &self,
+        value: &str,
+    ) -> Result<Found, DiskResolutionError> {
+        match self {
+            Self::Memory(segment) => Ok(segment.find_keys_for_value(value)),
+            Self::Disk(segment) => segment.find_keys_for_value(value).await,
+        }
+    }
+
+    pub(super) async fn holds(
+        &self,
+        key: &str,
+        value: &str,
+    ) -> Result<Option<bool>, DiskResolutionError> {
+        match self {
+            Self::Memory(segment) => Ok(segment.holds(key, value)),
+            Self::Disk(segment) => segment.holds(key, value).await,
+        }
+    }
+
+    pub(super) async fn contains(&self, key: &str) -> Result<bool, DiskResolutionError> {
+        match self {
+            Self::Memory(segment) => Ok(segment.contains(key)),
+            Self::Disk(segment) => segment.contains(key).await,
+        }
+    }
+
+    pub(super) async fn count(&self, key: &str) -> Result<usize, DiskResolutionError> {
+        match self {
+            Self::Memory(segment) => Ok(segment.count(key)),
+            Self::Disk(segment) => segment.count(key).await,
+        }
+    }
+
+    pub(super) async fn find(&self, key: &str) -> Result<Found, DiskResolutionError> {
+        match self {
+            Self::Memory(segment) => Ok(segment.find(key)),
+            Self::Disk(segment) => segment.find(key).await,
+        }
+    }
+
+    pub(super) async fn is_key_deleted(&self, key: &str) -> Result<bool, DiskResolutionError> {
+        match self {
+            Self::Memory(segment) => Ok(segment.is_key_deleted(key)),
+            Self::Disk(segment) => segment.is_key_deleted(key).await,
+        }
+    }
+
+    fn is_ordered(&self) -> bool {
+        match self {
+            Self::Memory(_) => true,
+            Self::Disk(segment) => segment.is_ordered(),
+        }
+    }
+
+    async fn values_after(&self, key: &str, after: &str) -> Result<usize, DiskResolutionError> {
+        let (mut low, mut high) = (0, self.count(key).await?);
+
+        while low < high {
+            let middle = low + (high - low) / 2;
+
+            let found = self.find_window(key, middle..middle + 1).await?;
+
+            match found.values.first() {
+                Some(value) if value.as_str() <= after => low = middle + 1,
+                _ => high = middle,
+            }
+        }
+
+        Ok(low)
+    }
+}
+
+// ordered so that the heap yields keys in the scan direction, and the newest run first among
+// equal keys
+#[derive(PartialEq, Eq)]
+struct Head {
+    key: String,
+    index: u32,
+    run: usize,
+    direction: Direction,
+}
+
+impl Ord for Head {
+    fn cmp(&self, other: &Self) -> Ordering {
+        let keys = match self.direction {
+            Direction::Forward => other.key.cmp(&self.key),
+            Direction::Reverse => self.key.cmp(&other.key),
+        };
+
+        keys.then_with(|| other.run.cmp(&self.run))
+    }
+}
+
+impl PartialOrd for Head {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+/// Merges the keys within a range of every segment, reading each key once it's reached.
+pub(super) struct Scan<'segment> {
+    runs: Vec<(Segment<'segment>, Range<u32>)>,
+    heap: BinaryHeap<Head>,
+    direction: Direction,
+
+    #[cfg(test)]
+    pub(super) resolved: usize,
+}
+
+impl<'segment> Scan<'segment> {
+    pub async fn new(
+        segments: Vec<Segment<'segment>>,
+        start: &str,
+        end: Option<&str>,
+        direction: Direction,
+    ) -> Result<Self, DiskResolutionError> {
+        let mut scan = Self {
+            runs: Vec::new(),
+            heap: BinaryHeap::new(),
+            direction,
+            #[cfg(test)]
+            resolved: 0,
+        };
+
+        for segment in segments {
+            let range = segment.key_range(start, end).await?;
+            scan.runs.push((segment, range));
+        }
+
+        for run in 0..scan.runs.len() {
+            scan.advance(run).await?;
+        }
+
+        Ok(scan)
+    }
+
+    async fn advance(&mut self, run: usize) -> Result<(), DiskResolutionError> {
+        let (segment, range) = &mut self.runs[run];
+
+        let index = match self.direction {
+            Direction::Forward => range.next(),
+            Direction::Reverse => range.next_back(),
+        };
+
+        if let Some(index) = index {
+            self.heap.push(Head {
+                key: segment.key(index).await?,
+                index,
+                run,
+                direction: self.direction,
+            });
+        }
+
+        Ok(())
+    }
+
+    pub async fn next(&mut self) -> Result<Option<(String, Vec<String>)>, DiskResolutionError> {
+        while let Some(head) = self.heap.pop() {
+            let mut entries = Vec::new();
+            let mut skipped = FxHashSet::default();
+            let mut shadowed = false;
+
+            let mut current = (head.run, head.index);
+
+            loop {
+                let (run, index) = current;
+
+                if !shadowed {
+                    let found = self.runs[run].0.resolve_key(index).await?;
+
+                    #[cfg(test)]
+                    {
+                        self.resolved += 1;
+                    }
+
+                    shadowed = super::collect(found, &mut entries, &mut skipped);
+                }
+
+                self.advance(run).await?;
+
+                // equal keys come out of the heap newest first
+                match self.heap.peek() {
+                    Some(next) if next.key == head.key => {
+                        let next = self.heap.pop().expect("the heap has a head");
+                        current = (next.run, next.index);
+                    }
+                    _ => break,
+                }
+            }
+
+            if !entries.is_empty() {
+                entries.sort_unstable();
+
+                return Ok(Some((head.key, entries)));
+            }
+        }
+
+        Ok(None)
+    }
+}
+
+pub(super) const WINDOWS: Range<usize> = 16..4096;
+
+struct ValueRun<'segment> {
+    segment: Segment<'segment>,
+
+    buffer: VecDeque<String>,
+
+    offset: usize,
+    window: usize,
+
+    exhausted: bool,
+
+    tombstones: FxHashSet<String>,
+}
+
+impl ValueRun<'_> {
+    async fn next(&mut self, key: &str) -> Result<Option<String>, DiskResolutionError> {
+        if self.buffer.is_empty() && !self.exhausted {
+            let found = self
+                .segment
+                .find_window(key, self.offset..self.offset + self.window)
+                .await?;
+
+            self.exhausted = found.values.len() < self.window;
+            self.offset += found.values.len();
+            self.window = (self.window * 2).min(WINDOWS.end);
+
+            self.buffer.extend(found.values);
+        }
+
+        Ok(self.buffer.pop_front())
+    }
+}
+
+/// Merges the values of a key in value order, so that they come out the same way no matter
+/// how the segments are flushed and compacted.
+pub(super) struct Values<'segment> {
+    key: String,
+
+    runs: Vec<ValueRun<'segment>>,
+
+    // the newest run comes first among equal values
+    heap: BinaryHeap<Reverse<(String, usize)>>,
+}
+
+impl<'segment> Values<'segment> {
+    pub async fn new(
+        segments: Vec<Segment<'segment>>,
+        key: &str,
+        after: Option<&str>,
+    ) -> Result<Self, DiskResolutionError> {
+        let mut values = Self {
+            key: key.to_string(),
+            runs: Vec::new(),
+            heap: BinaryHeap::new(),
+        };
+
+        for segment in segments {
+            if !segment.contains(key).await? {
+                continue;
+            }
+
+            let found = segment.find_window(key, 0..0).await?;
+
+            let mut run = ValueRun {
+                segment,
+                buffer: VecDeque::new(),
+                offset: 0,
+                window: WINDOWS.start,
+                exhausted: false,
+                tombstones: found.tombstones.into_iter().collect(),
+            };
+
+            if !run.segment.is_ordered() {
+                let mut held = run.segment.find(key).await?.values;
+                held.sort_unstable();
+                held.retain(|value| after.is_none_or(|after| value.as_str() > after));
+
+                run.buffer = held.into();
+                run.exhausted = true;
+            } else if let Some(after) = after {
+                run.offset = run.segment.values_after(key, after).await?;
+            }
+
+            values.runs.push(run);
+
+            if found.deleted {
+                break;
+            }
+        }
+
+        for run in 0..values.runs.len() {
+            values.advance(run).await?;
+        }
+
+        Ok(values)
+    }
+
+    async fn advance(&mut self, run: usize) -> Result<(), DiskResolutionError> {
+        if let Some(value) = self.runs[run].next(&self.key).await? {
+            self.heap.push(Reverse((value, run)));
+        }
+
+        Ok(())
+    }
+
+    pub async fn next(&mut self) -> Result<Option<String>, DiskResolutionError> {
+        while let Some(Reverse((value, run))) = self.heap.pop() {
+            self.advance(run).await?;
+
+            // older segments holding the value as well
+            while let Some(Reverse((next, _))) = self.heap.peek()
+                && *next == value
+            {
+                let Reverse((_, older)) = self.heap.pop().expect("the heap has a head");
+                self.advance(older).await?;
+            }
+
+            // the newest segment holding the value decides, unless a newer one deletes it
+            if self.runs[..run]
+                .iter()
+                .any(|newer| newer.tombstones.contains(&value))
+            {
+                continue;
+            }
+
+            return Ok(Some(value));
+        }
+
+        Ok(None)
+    }
+}
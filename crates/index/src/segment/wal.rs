@@ -0,0 +1,437 @@
+use fxhash::FxHashMap;
+use std::path::{Path, PathBuf};
+use tokio::{
+    fs::{self, File},
+    io::{self, AsyncWriteExt},
+};
+
+pub const FILE_NAME: &str = "wal.bin";
+
+const SEALED_PREFIX: &str = "wal-";
+const SEALED_SUFFIX: &str = ".bin";
+
+const RECORD_HEADER_SIZE: usize = size_of::<[u32; 2]>();
+
+/// Flag on the length of a record holding a deletion.
+const DELETION: u32 = 0b1 << (u32::BITS - 1);
+
+/// Value count standing for the deletion of a whole key.
+const WHOLE_KEY: u32 = u32::MAX;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Batch {
+    Insert(FxHashMap<String, Vec<String>>),
+
+    /// Values to delete under every key, or the whole key for `None`.
+    Delete(FxHashMap<String, Option<Vec<String>>>),
+}
+
+/// Append-only log of the batches that live only in memory segments.
+///
+/// Once a flush of the memory segments starts, the active log is sealed under the id of the
+/// disk segment being written (`wal-{id}.bin`) and appends continue in a fresh one, so the
+/// sealed log can be removed as soon as that segment is persisted.
+///
+/// Every record is laid out as `[length: u32][adler32: u32][payload]`, where the payload is
+/// the batch itself: `[keys: u32]` followed by `[key length: u32][key][values: u32]` and
+/// `[value length: u32][value]` for every value of that key. Deletions set the highest bit
+/// of the length, and use `u32::MAX` values for a deletion of the whole key.
+pub struct WriteAheadLog {
+    path: PathBuf,
+    file: File,
+}
+
+fn encode<'item, K: AsRef<str>, B: AsRef<str> + 'item>(
+    values: impl ExactSizeIterator<Item = (K, Option<&'item [B]>)>,
+) -> Vec<u8> {
+    let mut payload = Vec::new();
+
+    payload.extend_from_slice(&(values.len() as u32).to_be_bytes());
+
+    for (key, items) in values {
+        let key = key.as_ref().as_bytes();
+
+        payload.extend_from_slice(&(key.len() as u32).to_be_bytes());
+        payload.extend_from_slice(key);
+
+        let Some(items) = items else {
+            payload.extend_from_slice(&WHOLE_KEY.to_be_bytes());
+            continue;
+        };
+
+        payload.extend_from_slice(&(items.len() as u32).to_be_bytes());
+
+        for item in items {
+            let item = item.as_ref().as_bytes();
+
+            payload.extend_from_slice(&(item.len() as u32).to_be_bytes());
+            payload.extend_from_slice(item);
+        }
+    }
+
+    payload
+}
+
+struct Decoder<'buffer> {
+    buffer: &'buffer [u8],
+}
+
+impl<'buffer> Decoder<'buffer> {
+    fn take(&mut self, length: usize) -> Option<&'buffer [u8]> {
+        if self.buffer.len() < length {
+            return None;
+        }
+
+        let (head, tail) = self.buffer.split_at(length);
+        self.buffer = tail;
+
+        Some(head)
+    }
+
+    fn u32(&mut self) -> Option<u32> {
+        Some(u32::from_be_bytes(
+            self.take(size_of::<u32>())?.try_into().ok()?,
+        ))
+    }
+
+    fn string(&mut self) -> Option<String> {
+        let length = self.u32()? as usize;
+
+        String::from_utf8(self.take(length)?.to_vec()).ok()
+    }
+
+    fn batch(&mut self, deletion: bool) -> Option<Batch> {
+        let mut batch = FxHashMap::<String, Option<Vec<String>>>::default();
+
+        for _ in 0..self.u32()? {
+            let key = self.string()?;
+
+            let items = match self.u32()? {
+                WHOLE_KEY if deletion => None,
+                count => {
+                    let mut items = Vec::new();
+                    for _ in 0..count {
+                        items.push(self.string()?);
+                    }
+
+                    Some(items)
+                }
+            };
+
+            match (batch.entry(key).or_insert(Some(Vec::new())), items) {
+                (Some(existing), Some(items)) => existing.extend(items),
+                (existing, _) => *existing = None,
+            }
+        }
+
+        if !self.buffer.is_empty() {
+            return None;
+        }
+
+        Some(if deletion {
+            Batch::Delete(batch)
+        } else {
+            Batch::Insert(
+                batch
+                    .into_iter()
+                    .map(|(key, items)| (key, items.unwrap_or_default()))
+                    .collect(),
+            )
+        })
+    }
+}
+
+/// Splits the log into batches, returning them along with the length of the valid prefix.
+fn decode(buffer: &[u8]) -> (Vec<Batch>, usize) {
+    let mut batches = Vec::new();
+    let mut offset = 0;
+
+    while buffer.len() - offset >= RECORD_HEADER_SIZE {
+        let mut header = Decoder {
+            buffer: &buffer[offset..offset + RECORD_HEADER_SIZE],
+        };
+
+        let (Some(length), Some(checksum)) = (header.u32(), header.u32()) else {
+            break;
+        };
+
+        let deletion = length & DELETION != 0;
+        let length = length & !DELETION;
+
+        let start = offset + RECORD_HEADER_SIZE;
+        let Some(payload) = buffer.get(start..start + length as usize) else {
+            tracing::warn!("write-ahead log record at {offset:?} is truncated");
+            break;
+        };
+
+        if adler2::adler32_slice(payload) != checksum {
+            tracing::warn!("write-ahead log record at {offset:?} has invalid checksum");
+            break;
+        }
+
+        let Some(batch) = (Decoder { buffer: payload }).batch(deletion) else {
+            tracing::warn!("write-ahead log record at {offset:?} is malformed");
+            break;
+        };
+
+        batches.push(batch);
+        offset = start + length as usize;
+    }
+
+    (batches, offset)
+}
+
+/// Parses the id of a sealed log out of its file name.
+pub fn sealed_id(name: &str) -> Option<usize> {
+    name.strip_prefix(SEALED_PREFIX)?
+        .strip_suffix(SEALED_SUFFIX)?
+        .parse()
+        .ok()
+}
+
+fn sealed_path(directory: &Path, id: usize) -> PathBuf {
+    directory.join(format!("{SEALED_PREFIX}{id}{SEALED_SUFFIX}"))
+}
+
+async fn read(path: &Path) -> Result<(Vec<Batch>, usize, usize), io::Error> {
+    let buffer = match fs::read(path).await {
+        Ok(buffer) => buffer,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => Vec::new(),
+        Err(err) => return Err(err),
+    };
+
+    let (batches, valid) = decode(&buffer);
+
+    tracing::debug!("replayed {:?} batches from {path:?}", batches.len());
+
+    Ok((batches, valid, buffer.len()))
+}
+
+/// Removes a sealed log whose batches are persisted in a disk segment.
+pub async fn remove_sealed(directory: &Path, id: usize) -> Result<(), io::Error> {
+    match fs::remove_file(sealed_path(directory, id)).await {
+        Err(err) if err.kind() != io::ErrorKind::NotFound => Err(err),
+        _ => Ok(()),
+    }
+}
+
+impl WriteAheadLog {
+    /// Opens (or creates) the log in `directory`, returning the batches of the `sealed` logs
+    /// (in the given order) followed by the batches of the active one.
+    ///
+    /// A torn or damaged tail, as left by a crash in the middle of an append, is cut off.
+    pub async fn open(directory: &Path, sealed: &[usize]) -> Result<(Self, Vec<Batch>), io::Error> {
+        let mut batches = Vec::new();
+
+        for &id in sealed {
+            batches.extend(read(&sealed_path(directory, id)).await?.0);
+        }
+
+        let path = directory.join(FILE_NAME);
+
+        let (active, valid, length) = read(&path).await?;
+        batches.extend(active);
+
+        let file = fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&path)
+            .await?;
+
+        if valid < length {
+            tracing::warn!("discarding {:?} trailing bytes of {path:?}", length - valid);
+
+            file.set_len(valid as u64).await?;
+            file.sync_data().await?;
+        }
+
+        Ok((Self { path, file }, batches))
+    }
+
+    pub async fn append<K: AsRef<str>, B: AsRef<str>>(
+        &mut self,
+        values: &FxHashMap<K, Vec<B>>,
+    ) -> Result<(), io::Error> {
+        let payload = encode(
+            values
+                .iter()
+                .map(|(key, items)| (key, Some(items.as_slice()))),
+        );
+
+        self.write(payload, 0).await
+    }
+
+    pub async fn append_deletion<K: AsRef<str>, B: AsRef<str>>(
+        &mut self,
+        deletion: &FxHashMap<K, Option<Vec<B>>>,
+    ) -> Result<(), io::Error> {
+        let payload = encode(deletion.iter().map(|(key, items)| (key, items.as_deref())));
+
+        self.write(payload, DELETION).await
+    }
+
+    async fn write(&mut self, payload: Vec<u8>, flags: u32) -> Result<(), io::Error> {
+        let mut record = Vec::with_capacity(RECORD_HEADER_SIZE + payload.len());
+        record.extend_from_slice(&(flags | payload.len() as u32).to_be_bytes());
+        record.extend_from_slice(&adler2::adler32_slice(&payload).to_be_bytes());
+        record.extend_from_slice(&payload);
+
+        self.file.write_all(&record).await?;
+        self.file.sync_data().await?;
+
+        tracing::trace!("appended {:?} bytes to {:?}", record.len(), self.path);
+
+        Ok(())
+    }
+
+    /// Moves the active log aside as `wal-{id}.bin` and starts a fresh one.
+    pub async fn seal(&mut self, id: usize) -> Result<(), io::Error> {
+        let directory = self
+            .path
+            .parent()
+            .expect("log is always within a directory");
+
+        fs::rename(&self.path, sealed_path(directory, id)).await?;
+
+        self.file = fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)
+            .await?;
+
+        File::open(directory).await?.sync_all().await?;
+
+        tracing::trace!("sealed {:?} as {id:?}", self.path);
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::tempdir;
+
+    fn batch(entries: &[(&str, &[&str])]) -> Batch {
+        Batch::Insert(
+            entries
+                .iter()
+                .map(|(key, values)| {
+                    (
+                        key.to_string(),
+                        values.iter().map(|value| value.to_string()).collect(),
+                    )
+                })
+                .collect(),
+        )
+    }
+
+    async fn append(wal: &mut WriteAheadLog, batch: &Batch) {
+        match batch {
+            Batch::Insert(values) => wal.append(values).await.unwrap(),
+            Batch::Delete(deletion) => wal.append_deletion(deletion).await.unwrap(),
+        }
+    }
+
+    #[tokio::test]
+    async fn append_and_replay() {
+        let tmp = tempdir().unwrap();
+
+        let first = batch(&[("a", &["1", "2"]), ("b", &["3"])]);
+        let second = batch(&[("c", &["4"])]);
+
+        {
+            let (mut wal, batches) = WriteAheadLog::open(tmp.path(), &[]).await.unwrap();
+            assert!(batches.is_empty());
+
+            append(&mut wal, &first).await;
+            append(&mut wal, &second).await;
+        }
+
+        let (_, batches) = WriteAheadLog::open(tmp.path(), &[]).await.unwrap();
+        assert_eq!(batches, [first, second]);
+    }
+
+    #[tokio::test]
+    async fn torn_tail_is_discarded() {
+        let tmp = tempdir().unwrap();
+
+        let first = batch(&[("a", &["1"])]);
+
+        {
+            let (mut wal, _) = WriteAheadLog::open(tmp.path(), &[]).await.unwrap();
+            append(&mut wal, &first).await;
+            append(&mut wal, &batch(&[("b", &["2"])])).await;
+        }
+
+        let path = tmp.path().join(FILE_NAME);
+        let length = fs::metadata(&path).await.unwrap().len();
+        fs::OpenOptions::new()
+            .write(true)
+            .open(&path)
+            .await
+            .unwrap()
+            .set_len(length - 1)
+            .await
+            .unwrap();
+
+        let (mut wal, batches) = WriteAheadLog::open(tmp.path(), &[]).await.unwrap();
+        assert_eq!(batches, std::slice::from_ref(&first));
+
+        // appends continue right after the last valid record
+        let third = batch(&[("c", &["3"])]);
+        append(&mut wal, &third).await;
+
+        let (_, batches) = WriteAheadLog::open(tmp.path(), &[]).await.unwrap();
+        assert_eq!(batches, [first, third]);
+    }
+
+    #[tokio::test]
+    async fn sealed_logs_are_replayed_before_active() {
+        let tmp = tempdir().unwrap();
+
+        let first = batch(&[("a", &["1"])]);
+        let second = batch(&[("b", &["2"])]);
+
+        {
+            let (mut wal, _) = WriteAheadLog::open(tmp.path(), &[]).await.unwrap();
+            append(&mut wal, &first).await;
+            wal.seal(3).await.unwrap();
+            append(&mut wal, &second).await;
+        }
+
+        assert_eq!(sealed_id("wal-3.bin"), Some(3));
+
+        let (_, batches) = WriteAheadLog::open(tmp.path(), &[]).await.unwrap();
+        assert_eq!(batches, std::slice::from_ref(&second));
+
+        let (_, batches) = WriteAheadLog::open(tmp.path(), &[3]).await.unwrap();
+        assert_eq!(batches, [first, second.clone()]);
+
+        remove_sealed(tmp.path(), 3).await.unwrap();
+
+        let (_, batches) = WriteAheadLog::open(tmp.path(), &[3]).await.unwrap();
+        assert_eq!(batches, [second]);
+    }
+
+    #[tokio::test]
+    async fn deletions_are_replayed() {
+        let tmp = tempdir().unwrap();
+
+        let insert = batch(&[("a", &["1", "2"]), ("b", &["3"])]);
+
+        let mut deletion = FxHashMap::default();
+        deletion.insert("a".to_string(), Some(vec!["1".to_string()]));
+        deletion.insert("b".to_string(), None);
+        let deletion = Batch::Delete(deletion);
+
+        {
+            let (mut wal, _) = WriteAheadLog::open(tmp.path(), &[]).await.unwrap();
+            append(&mut wal, &insert).await;
+            append(&mut wal, &deletion).await;
+        }
+
+        let (_, batches) = WriteAheadLog::open(tmp.path(), &[]).await.unwrap();
+        assert_eq!(batches, [insert, deletion]);
+    }
+}